@@ -0,0 +1,196 @@
+//! Helpers shared by the benchmarks, which run clients connected in memory
+//! and measure what they send each other.
+
+// Each benchmark compiles this separately and uses only some of it.
+#![allow(dead_code)]
+
+use c3edit::client::{
+    connect_in_memory, Change, ClientBuilder, ClientConfig, ClientHandle, ClientMessage,
+};
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::{sleep, timeout},
+};
+
+/// How long to wait for any one response.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A client running in the background.
+pub struct BenchClient {
+    pub handle: ClientHandle,
+    events: UnboundedReceiver<ClientMessage>,
+}
+
+/// Totals reported by `GetStats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    pub bytes_sent: u64,
+    pub messages_sent: u64,
+}
+
+impl BenchClient {
+    pub async fn spawn(config: ClientConfig) -> BenchClient {
+        let config = ClientConfig {
+            handle_signals: false,
+            ..config
+        };
+        let builder = ClientBuilder::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .config(config);
+        let (client, handle, mut client_events) = builder.build_embedded();
+        tokio::spawn(client.begin_event_loop());
+
+        // Drained as they arrive so the client never stalls.
+        let (tx, events) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(message) = client_events.next().await {
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        BenchClient { handle, events }
+    }
+
+    /// Waits for the first message `pick` returns something for, panicking
+    /// on errors.
+    pub async fn expect<T>(&mut self, mut pick: impl FnMut(ClientMessage) -> Option<T>) -> T {
+        let wait = async {
+            loop {
+                match self.events.recv().await.expect("Client exited") {
+                    ClientMessage::Error { message, context } => {
+                        panic!("Client failed while {context}: {message}")
+                    }
+                    message => {
+                        if let Some(picked) = pick(message) {
+                            return picked;
+                        }
+                    }
+                }
+            }
+        };
+        timeout(TIMEOUT, wait)
+            .await
+            .expect("Timed out waiting for client")
+    }
+
+    /// Creates a document holding `content`, returning its ID.
+    pub async fn create_document(&mut self, content: String) -> String {
+        self.handle
+            .create_document("bench", Some(content))
+            .await
+            .unwrap();
+        self.expect(|message| match message {
+            ClientMessage::DocumentCreated { document_id } => Some(document_id),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Returns the main text of a document, or `None` if the client doesn't
+    /// have it yet.
+    pub async fn text(&mut self, document_id: &str) -> Option<String> {
+        self.handle
+            .send(ClientMessage::GetText {
+                document_id: document_id.to_owned(),
+                container: "text".to_owned(),
+            })
+            .await
+            .unwrap();
+        let wait = async {
+            loop {
+                match self.events.recv().await.expect("Client exited") {
+                    ClientMessage::Text { content, .. } => return Some(content),
+                    ClientMessage::Error { message, .. }
+                        if message.contains("No such document") =>
+                    {
+                        return None
+                    }
+                    ClientMessage::Error { message, context } => {
+                        panic!("Client failed while {context}: {message}")
+                    }
+                    _ => {}
+                }
+            }
+        };
+        timeout(TIMEOUT, wait)
+            .await
+            .expect("Timed out waiting for client")
+    }
+
+    /// Waits until the client has the document, then opens it.
+    pub async fn join(&mut self, document_id: &str) {
+        while self.text(document_id).await.is_none() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        self.handle
+            .send(ClientMessage::JoinDocument {
+                id: document_id.to_owned(),
+            })
+            .await
+            .unwrap();
+        self.expect(|message| match message {
+            ClientMessage::JoinDocumentResponse { .. } => Some(()),
+            _ => None,
+        })
+        .await;
+    }
+
+    pub async fn traffic(&mut self) -> Traffic {
+        self.handle.send(ClientMessage::GetStats).await.unwrap();
+        self.expect(|message| match message {
+            ClientMessage::Stats {
+                bytes_sent,
+                messages_sent,
+                ..
+            } => Some(Traffic {
+                bytes_sent,
+                messages_sent,
+            }),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Waits for everything queued to be sent, returning the totals then.
+    pub async fn settled_traffic(&mut self) -> Traffic {
+        let mut traffic = self.traffic().await;
+        loop {
+            sleep(Duration::from_millis(50)).await;
+            let now = self.traffic().await;
+            if now == traffic {
+                return now;
+            }
+            traffic = now;
+        }
+    }
+
+    pub async fn shutdown(self) {
+        self.handle.shutdown().await.unwrap();
+    }
+}
+
+/// Connects two clients in memory, waiting for the handshake to complete.
+pub async fn connect(a: &mut BenchClient, b: &mut BenchClient) {
+    connect_in_memory(&a.handle, &b.handle).await.unwrap();
+    for client in [a, b] {
+        client
+            .expect(|message| match message {
+                ClientMessage::AddPeerResponse { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+    }
+}
+
+/// Inserts `text` at `index` in the main text of a document.
+pub fn insert(index: usize, text: &str) -> Change {
+    Change::Insert {
+        index,
+        text: text.to_owned(),
+    }
+}
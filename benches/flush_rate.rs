@@ -0,0 +1,64 @@
+//! Measures the rate of messages sent to a peer while typing continuously,
+//! sending each change as it's made compared to flushing them on an interval.
+
+mod common;
+
+use c3edit::client::ClientConfig;
+use common::{connect, insert, BenchClient};
+use std::time::{Duration, Instant};
+use tokio::time::{interval, MissedTickBehavior};
+
+const TYPING_FOR: Duration = Duration::from_secs(2);
+const KEYSTROKE_EVERY: Duration = Duration::from_millis(2);
+
+async fn typing_rate(flush_interval: Option<Duration>) -> (usize, f64, f64) {
+    let config = || ClientConfig {
+        flush_interval,
+        ..ClientConfig::default()
+    };
+    let mut editor = BenchClient::spawn(config()).await;
+    let mut peer = BenchClient::spawn(config()).await;
+    connect(&mut editor, &mut peer).await;
+    let document_id = editor.create_document(String::new()).await;
+    peer.join(&document_id).await;
+
+    let before = editor.settled_traffic().await;
+    let mut keystrokes = interval(KEYSTROKE_EVERY);
+    keystrokes.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let start = Instant::now();
+    let mut typed = 0;
+    while start.elapsed() < TYPING_FOR {
+        keystrokes.tick().await;
+        editor
+            .handle
+            .apply_change(&document_id, insert(typed, "x"))
+            .await
+            .unwrap();
+        typed += 1;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let after = editor.settled_traffic().await;
+    assert_eq!(
+        peer.text(&document_id).await.map(|text| text.len()),
+        Some(typed)
+    );
+
+    editor.shutdown().await;
+    peer.shutdown().await;
+    (
+        typed,
+        (after.messages_sent - before.messages_sent) as f64 / elapsed,
+        (after.bytes_sent - before.bytes_sent) as f64 / elapsed,
+    )
+}
+
+#[tokio::main]
+async fn main() {
+    for flush_interval in [None, Some(Duration::from_millis(50))] {
+        let (typed, messages, bytes) = typing_rate(flush_interval).await;
+        println!(
+            "flush interval {:<10}  {typed:>5} keystrokes  {messages:>7.1} messages/s  {bytes:>9.0} bytes/s",
+            format!("{flush_interval:?}"),
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Measures the bytes sent to bring a new peer up to date with a document
+//! whose history has 100k operations, when it's sent as a snapshot compared
+//! to as every update.
+
+mod common;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use c3edit::client::{ClientConfig, ClientMessage};
+use common::{connect, BenchClient};
+use loro::LoroDoc;
+use std::time::Instant;
+
+const OPERATIONS: usize = 100_000;
+
+/// A document built by `OPERATIONS` single-character edits, a fifth of them
+/// deletions, committed in small batches as typing would be.
+fn long_history() -> LoroDoc {
+    let doc = LoroDoc::new();
+    let text = doc.get_text("text");
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    for i in 0..OPERATIONS {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        let len = text.len_unicode();
+        let index = (state >> 33) as usize % (len + 1);
+        if len > 0 && i % 5 == 4 {
+            text.delete(index.min(len - 1), 1).unwrap();
+        } else {
+            let c = char::from(b'a' + (state >> 40) as u8 % 26);
+            text.insert(index, c.encode_utf8(&mut [0; 4])).unwrap();
+        }
+        if i % 20 == 19 {
+            doc.commit();
+        }
+    }
+    doc.commit();
+    doc
+}
+
+async fn sync_bytes(snapshot_threshold: Option<usize>, snapshot: &str) -> u64 {
+    let config = || ClientConfig {
+        snapshot_threshold,
+        ..ClientConfig::default()
+    };
+    let mut a = BenchClient::spawn(config()).await;
+    let mut b = BenchClient::spawn(config()).await;
+
+    a.handle
+        .send(ClientMessage::ImportSnapshot {
+            data: snapshot.to_owned(),
+            document_id: Some("history".to_owned()),
+        })
+        .await
+        .unwrap();
+    a.expect(|message| match message {
+        ClientMessage::DocumentCreated { .. } => Some(()),
+        _ => None,
+    })
+    .await;
+    let expected = a.text("history").await;
+
+    let before = a.settled_traffic().await;
+    connect(&mut a, &mut b).await;
+    b.join("history").await;
+    let after = a.settled_traffic().await;
+    assert_eq!(b.text("history").await, expected);
+
+    a.shutdown().await;
+    b.shutdown().await;
+    after.bytes_sent - before.bytes_sent
+}
+
+#[tokio::main]
+async fn main() {
+    let doc = long_history();
+    let snapshot = BASE64_STANDARD.encode(doc.export_snapshot());
+    println!(
+        "document: {} operations, {} characters",
+        OPERATIONS,
+        doc.get_text("text").len_unicode()
+    );
+    for (name, snapshot_threshold) in [("updates", None), ("snapshot", Some(10_000))] {
+        let start = Instant::now();
+        let bytes = sync_bytes(snapshot_threshold, &snapshot).await;
+        println!("{name:<8}  {bytes:>9} bytes sent  in {:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,47 @@
+//! Measures the bytes sent to a peer per keystroke over a long editing
+//! session, which should stay roughly constant as the history grows rather
+//! than growing with it.
+
+mod common;
+
+use c3edit::client::ClientConfig;
+use common::{connect, insert, BenchClient};
+use std::time::Instant;
+
+const KEYSTROKES: usize = 5_000;
+const WINDOW: usize = 500;
+
+#[tokio::main]
+async fn main() {
+    let mut editor = BenchClient::spawn(ClientConfig::default()).await;
+    let mut peer = BenchClient::spawn(ClientConfig::default()).await;
+    connect(&mut editor, &mut peer).await;
+    let document_id = editor.create_document(String::new()).await;
+    peer.join(&document_id).await;
+
+    println!("keystrokes  bytes/keystroke  messages/keystroke");
+    let start = Instant::now();
+    let mut before = editor.settled_traffic().await;
+    for window in 0..KEYSTROKES / WINDOW {
+        for i in 0..WINDOW {
+            let index = window * WINDOW + i;
+            editor
+                .handle
+                .apply_change(&document_id, insert(index, "x"))
+                .await
+                .unwrap();
+        }
+        let after = editor.settled_traffic().await;
+        println!(
+            "{:>10}  {:>15.1}  {:>18.2}",
+            (window + 1) * WINDOW,
+            (after.bytes_sent - before.bytes_sent) as f64 / WINDOW as f64,
+            (after.messages_sent - before.messages_sent) as f64 / WINDOW as f64,
+        );
+        before = after;
+    }
+    println!("Took {:?}", start.elapsed());
+
+    editor.shutdown().await;
+    peer.shutdown().await;
+}
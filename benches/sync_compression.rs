@@ -0,0 +1,68 @@
+//! Measures the bytes sent to sync a 1MB document to a peer, with and without
+//! compression.
+
+mod common;
+
+use c3edit::client::ClientConfig;
+use common::{connect, BenchClient};
+use std::time::Instant;
+
+const DOCUMENT_SIZE: usize = 1 << 20;
+
+const WORDS: &[&str] = &[
+    "the", "of", "and", "to", "in", "document", "peer", "edit", "sync", "text", "change", "client",
+    "version", "backend", "frontend", "cursor", "comment", "update",
+];
+
+/// Prose-like text of `len` bytes, the same on every run.
+fn prose(len: usize) -> String {
+    // A fixed LCG, so runs are comparable without pulling in `rand`.
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut text = String::with_capacity(len + 16);
+    while text.len() < len {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        text.push_str(WORDS[(state >> 33) as usize % WORDS.len()]);
+        text.push(if (state >> 20).is_multiple_of(12) { '\n' } else { ' ' });
+    }
+    text.truncate(len);
+    text
+}
+
+async fn sync_bytes(compression: bool, content: &str) -> u64 {
+    let config = || ClientConfig {
+        compression,
+        ..ClientConfig::default()
+    };
+    let mut a = BenchClient::spawn(config()).await;
+    let mut b = BenchClient::spawn(config()).await;
+    connect(&mut a, &mut b).await;
+
+    let before = a.settled_traffic().await;
+    let document_id = a.create_document(content.to_owned()).await;
+    b.join(&document_id).await;
+    let after = a.settled_traffic().await;
+    assert_eq!(b.text(&document_id).await.as_deref(), Some(content));
+
+    a.shutdown().await;
+    b.shutdown().await;
+    after.bytes_sent - before.bytes_sent
+}
+
+#[tokio::main]
+async fn main() {
+    let content = prose(DOCUMENT_SIZE);
+    println!("document: {} bytes", content.len());
+    for compression in [false, true] {
+        let start = Instant::now();
+        let bytes = sync_bytes(compression, &content).await;
+        println!(
+            "compression {:<5}  {:>9} bytes sent  ({:.2}x document)  in {:?}",
+            compression,
+            bytes,
+            bytes as f64 / content.len() as f64,
+            start.elapsed(),
+        );
+    }
+}
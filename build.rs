@@ -0,0 +1,19 @@
+use std::{env, fs, path::Path};
+
+/// Passes the version of Loro resolved in `Cargo.lock` to the crate as
+/// `LORO_VERSION`, since Loro doesn't expose it itself.
+fn main() {
+    let lock = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock.display());
+
+    let version = fs::read_to_string(&lock)
+        .ok()
+        .and_then(|lock| {
+            let mut lines = lock.lines();
+            lines.find(|line| *line == "name = \"loro\"")?;
+            let version = lines.next()?.strip_prefix("version = \"")?;
+            Some(version.trim_end_matches('"').to_owned())
+        })
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=LORO_VERSION={version}");
+}
@@ -63,12 +63,33 @@ cursor overlay.")
 
 ;; TODO Replace this with a callback-based approach with a macro in which a
 ;; function can be registered to handle the next message.
+(defvar c3edit--comments nil
+  "Alist mapping comment IDs to the overlays showing them.")
+
 (defvar c3edit--currently-creating-buffer nil
   "Buffer currently in the process of being created on the backend.")
 
 (defvar c3edit--pre-command-point nil
   "Point before the last command was executed.")
 
+(defvar c3edit--discovered-peers nil
+  "Alist mapping addresses of peers found on the local network to names.")
+
+(defvar c3edit--peer-latency nil
+  "Alist mapping addresses of connected peers to round-trip times in ms.")
+
+(defvar c3edit--peer-presence nil
+  "Alist mapping peer IDs to their display names and colors.
+The cdr of each element is a cons of the form (NAME . COLOR).")
+
+(defconst c3edit--style-faces
+  '(("bold" . bold)
+    ("italic" . italic)
+    ("underline" . underline)
+    ("highlight" . highlight)
+    ("code" . fixed-pitch))
+  "Faces used to display each style applied to a document's text.")
+
 (defvar c3edit--synced-changes-p nil
   "Whether current changes being inserted are from backend.
 Dynamically-scoped variable to prevent infinitely-recursing changes.")
@@ -91,6 +112,7 @@ Start as server if SERVER is non-nil."
                            :name "c3edit"
                            :command command
                            :connection-type 'pipe
+                           :coding 'utf-8-unix
                            :filter #'c3edit--process-filter
                            :stderr (get-buffer-create "*c3edit log*"))))
   (add-hook 'after-change-functions #'c3edit--after-change-function)
@@ -100,25 +122,275 @@ Start as server if SERVER is non-nil."
   (add-hook 'deactivate-mark-hook #'c3edit--deactivate-mark-function))
 
 (defun c3edit-stop ()
-  "Kill c3edit backend."
+  "Stop c3edit backend.
+Give the backend a moment to send pending changes to peers before
+killing it."
   (interactive)
   (unless c3edit--process
     (user-error "Backend for c3edit is not running"))
-  (kill-process c3edit--process)
+  (c3edit--send-message '((type . "shutdown")))
+  (with-timeout (2 (kill-process c3edit--process))
+    (while (process-live-p c3edit--process)
+      (accept-process-output c3edit--process 0.1)))
   (setq c3edit--process nil
         c3edit--buffers nil
-        c3edit--cursors-alist nil)
+        c3edit--cursors-alist nil
+        c3edit--discovered-peers nil
+        c3edit--peer-presence nil
+        c3edit--peer-latency nil)
   (remove-hook 'after-change-functions #'c3edit--after-change-function)
   (remove-hook 'post-command-hook #'c3edit--post-command-function)
   (remove-hook 'pre-command-hook #'c3edit--pre-command-function)
   (remove-hook 'activate-mark-hook #'c3edit--activate-mark-function))
 
 (defun c3edit-add-peer (address)
-  "Add a peer at ADDRESS."
-  (interactive "sAddress: ")
+  "Add a peer at ADDRESS.
+Interactively, offer peers found by `c3edit-enable-discovery' as
+completions."
+  (interactive
+   (list (completing-read "Address: " c3edit--discovered-peers)))
   (c3edit--send-message `((type . "add_peer")
                           (address . ,address))))
 
+(defun c3edit-add-peers (addresses)
+  "Add peers at each of ADDRESSES, a list of strings.
+Interactively, read them separated by spaces."
+  (interactive (list (split-string (read-string "Addresses: "))))
+  (c3edit--send-message `((type . "add_peers")
+                          (addresses . ,(vconcat addresses)))))
+
+(defun c3edit-enable-discovery (name)
+  "Advertise this backend on the local network as NAME.
+Also look for other backends doing the same, so that they can be chosen
+in `c3edit-add-peer'."
+  (interactive (list (read-string "Name: " (user-login-name))))
+  (c3edit--send-message `((type . "enable_discovery")
+                          (service_name . ,name))))
+
+(defun c3edit-set-presence (name color)
+  "Show this user to peers as NAME, in COLOR."
+  (interactive (list (read-string "Name: " (user-full-name))
+                     (read-color "Color: ")))
+  (c3edit--send-message `((type . "set_presence")
+                          (name . ,name)
+                          (color . ,color))))
+
+(defun c3edit-show-participants ()
+  "Display the names of connected peers that have set their presence."
+  (interactive)
+  (if c3edit--peer-presence
+      (message "Participants: %s"
+               (mapconcat (lambda (presence)
+                            (propertize (cadr presence)
+                                        'face `(:foreground ,(cddr presence))))
+                          c3edit--peer-presence ", "))
+    (message "No participants have set their presence")))
+
+(defun c3edit-remove-peer (address)
+  "Disconnect from the peer at ADDRESS."
+  (interactive "sAddress: ")
+  (c3edit--send-message `((type . "remove_peer")
+                          (address . ,address))))
+
+(defun c3edit-set-peer-permission (address read-only)
+  "Set whether changes from the peer at ADDRESS are rejected.
+Interactively, READ-ONLY is non-nil unless a prefix argument is given."
+  (interactive (list (read-string "Address: ") (not current-prefix-arg)))
+  (c3edit--send-message `((type . "set_peer_permission")
+                          (address . ,address)
+                          (read_only . ,(if read-only t :json-false)))))
+
+(defun c3edit-show-version ()
+  "Display the version of the current buffer's document.
+Documents with the same version on different peers have converged."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "get_version")
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-checkout (frontiers)
+  "Show the current buffer's document as it was at FRONTIERS.
+FRONTIERS is a comma-separated list of operation IDs, as displayed by
+`c3edit-show-version'."
+  (interactive "sFrontiers: ")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message
+       `((type . "checkout")
+         (document_id . ,document-id)
+         (frontiers . ,(vconcat (split-string frontiers "[, ]+" t)))))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-diff-versions (from to)
+  "Show the changes to the current buffer's document between FROM and TO.
+FROM and TO are frontiers, as read by `c3edit-checkout'."
+  (interactive "sFrom frontiers: \nsTo frontiers: ")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message
+       `((type . "diff_versions")
+         (document_id . ,document-id)
+         (from_frontiers . ,(vconcat (split-string from "[, ]+" t)))
+         (to_frontiers . ,(vconcat (split-string to "[, ]+" t)))))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-checkout-to-latest ()
+  "Return the current buffer's document to its latest version."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "checkout_to_latest")
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-clear-document ()
+  "Delete all text in the current buffer's document, for every peer."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "clear_document")
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-close-document (&optional force)
+  "Unload the current buffer's document from the backend.
+With prefix argument FORCE, close it even if it has edits peers haven't
+been sent."
+  (interactive "P")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "close_document")
+                              (document_id . ,document-id)
+                              (force . ,(if force t :json-false))))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-check-unsynced ()
+  "Show whether the current buffer's document has edits no peer has been sent."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "has_unsynced_changes")
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-history ()
+  "Show the changes made to the current buffer's document."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "get_history")
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-rename-document (new-id)
+  "Change the ID of the current buffer's document to NEW-ID for every peer."
+  (interactive "sNew ID: ")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "rename_document")
+                              (old_id . ,document-id)
+                              (new_id . ,new-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-set-metadata (key value)
+  "Set metadata KEY of the current buffer's document to VALUE for every peer.
+If VALUE is empty, remove KEY instead."
+  (interactive "sKey: \nsValue: ")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "set_metadata")
+                              (document_id . ,document-id)
+                              (key . ,key)
+                              ,@(unless (string-empty-p value)
+                                  `((value . ,value)))))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-set-title (title)
+  "Set the title of the current buffer's document to TITLE for every peer."
+  (interactive "sTitle: ")
+  (c3edit-set-metadata "title" title))
+
+(defun c3edit-add-comment (beg end body)
+  "Attach a comment with BODY to the region from BEG to END for every peer."
+  (interactive "r\nsComment: ")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "add_comment")
+                              (document_id . ,document-id)
+                              (start . ,(1- beg))
+                              (end . ,(1- end))
+                              (body . ,body)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-resolve-comment ()
+  "Resolve the comment at point for every peer."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (if-let ((comment-id (seq-some (lambda (overlay)
+                                       (overlay-get overlay 'c3edit-comment))
+                                     (overlays-at (point)))))
+          (c3edit--send-message `((type . "resolve_comment")
+                                  (document_id . ,document-id)
+                                  (comment_id . ,comment-id)))
+        (user-error "No comment at point"))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-pause-sync (&optional hold-incoming)
+  "Stop sending edits to the current buffer's document to peers.
+With prefix argument HOLD-INCOMING, also hold edits from peers until
+`c3edit-resume-sync' is called."
+  (interactive "P")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "pause_sync")
+                              (document_id . ,document-id)
+                              (hold_incoming . ,(if hold-incoming t :json-false))))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-resume-sync ()
+  "Send peers all edits made to the current buffer's document while paused."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "resume_sync")
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-request-sync (address)
+  "Exchange the full state of the current buffer's document with ADDRESS.
+Use this if the document appears to have diverged from the peer's copy."
+  (interactive "sAddress: ")
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "request_sync")
+                              (address . ,address)
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-verify-sync ()
+  "Check that peers' copies of the current buffer's document match this one."
+  (interactive)
+  (if-let ((document-id (cdr (assoc (current-buffer) c3edit--buffers))))
+      (c3edit--send-message `((type . "verify_sync")
+                              (document_id . ,document-id)))
+    (user-error "Current buffer is not a c3edit document")))
+
+(defun c3edit-subscribe (id)
+  "Receive updates from peers for the document with ID.
+Once any document is subscribed to, updates for other documents are no
+longer received."
+  (interactive "sDocument ID: ")
+  (c3edit--send-message `((type . "subscribe")
+                          (document_id . ,id))))
+
+(defun c3edit-list-peers ()
+  "Display the addresses of all connected peers."
+  (interactive)
+  (c3edit--send-message '((type . "list_peers"))))
+
+(defun c3edit-list-documents ()
+  "Display the IDs and lengths of all documents known to the backend."
+  (interactive)
+  (c3edit--send-message '((type . "list_documents"))))
+
+(defun c3edit-show-stats ()
+  "Display how much data has been exchanged with peers."
+  (interactive)
+  (c3edit--send-message '((type . "get_stats"))))
+
+(defun c3edit-show-info ()
+  "Show the versions of the c3edit backend and its peer ID."
+  (interactive)
+  (c3edit--send-message '((type . "get_info"))))
+
 (defun c3edit-create-document (buffer)
   "Create a new c3edit document with BUFFER's contents.
 When called interactively, BUFFER is the current buffer."
@@ -126,9 +398,21 @@ When called interactively, BUFFER is the current buffer."
   (c3edit--send-message `((type . "create_document")
                           (name . ,(buffer-name buffer))
                           (initial_content . ,(with-current-buffer buffer
-                                                (buffer-string)))))
+                                                (when (> (buffer-size) 0)
+                                                  (buffer-string))))))
   (setq c3edit--currently-creating-buffer buffer))
 
+(defun c3edit-create-document-from-file (file)
+  "Create a new c3edit document with the contents of FILE.
+The backend reads FILE itself, and its contents are shown in a new
+buffer."
+  (interactive "fFile: ")
+  (let ((buffer (generate-new-buffer (file-name-nondirectory file))))
+    (c3edit--send-message `((type . "create_document_from_file")
+                            (path . ,(expand-file-name file))))
+    (setq c3edit--currently-creating-buffer buffer)
+    (pop-to-buffer buffer)))
+
 (defun c3edit-join-document (id)
   "Join document with ID."
   (interactive "sDocument ID: ")
@@ -154,8 +438,8 @@ Returns list of read objects."
          (face (nth (mod peers (length c3edit-peer-faces)) c3edit-peer-faces)))
     `(:inverse-video t :inherit ,face)))
 
-(defun c3edit--handle-create-document-response (id)
-  "Handle `create_document_response` message with data ID."
+(defun c3edit--handle-document-created (id)
+  "Handle `document_created` message with data ID."
   (push `(,c3edit--currently-creating-buffer . ,id)
         c3edit--buffers)
   (push `(,id . nil) c3edit--cursors-alist)
@@ -172,6 +456,79 @@ Returns list of read objects."
     (pop-to-buffer buffer)
     (message "Joined document with ID %s" id)))
 
+(defun c3edit--handle-document-closed (id)
+  "Stop tracking the buffer of closed document ID."
+  (dolist (data c3edit--cursors-alist)
+    (when (and (equal (car data) id) (overlayp (cdr data)))
+      (delete-overlay (cdr data))))
+  (setq c3edit--cursors-alist
+        (seq-remove (lambda (data) (equal (car data) id)) c3edit--cursors-alist)
+        c3edit--buffers
+        (seq-remove (lambda (data) (equal (cdr data) id)) c3edit--buffers))
+  (message "Closed document %s" id))
+
+(defun c3edit--handle-history (id entries truncated)
+  "Show history ENTRIES of document ID, noting if they were TRUNCATED."
+  (with-current-buffer (get-buffer-create (format "*c3edit history: %s*" id))
+    (let ((inhibit-read-only t))
+      (erase-buffer)
+      (seq-do (lambda (entry)
+                (let-alist entry
+                  (insert (format "%s  peer %s  %d ops  %s\n"
+                                  (format-time-string "%F %T" .timestamp)
+                                  .peer_id .op_count .version))
+                  (when .message
+                    (insert (replace-regexp-in-string "^" "    " .message)
+                            "\n"))))
+              entries)
+      (when (eq truncated t)
+        (insert "(older changes not shown)\n")))
+    (goto-char (point-min))
+    (special-mode)
+    (display-buffer (current-buffer))))
+
+(defun c3edit--handle-diff (id changes)
+  "Show CHANGES between two versions of document ID."
+  (with-current-buffer (get-buffer-create (format "*c3edit diff: %s*" id))
+    (let ((inhibit-read-only t))
+      (erase-buffer)
+      (if (zerop (length changes))
+          (insert "No changes\n")
+        (seq-do (lambda (change)
+                  (let-alist change
+                    (pcase .type
+                      ("insert" (insert (format "+ %d: %S\n" .index .text)))
+                      ("delete" (insert (format "- %d: %d characters\n"
+                                                .index .len))))))
+                changes)))
+    (goto-char (point-min))
+    (special-mode)
+    (display-buffer (current-buffer))))
+
+(defun c3edit--handle-document-renamed (old-id new-id)
+  "Track the buffer of document OLD-ID under NEW-ID."
+  (dolist (data c3edit--buffers)
+    (when (equal (cdr data) old-id)
+      (setcdr data new-id)))
+  (dolist (data c3edit--cursors-alist)
+    (when (equal (car data) old-id)
+      (setcar data new-id)))
+  (message "Document %s renamed to %s" old-id new-id))
+
+(defun c3edit--handle-checkout-response (id content detached)
+  "Replace the contents of document ID's buffer with CONTENT.
+If DETACHED is non-nil, the buffer shows a past version and is made
+read-only."
+  (with-current-buffer (car (rassoc id c3edit--buffers))
+    (let ((inhibit-read-only t))
+      (erase-buffer)
+      (insert content))
+    (setq buffer-read-only (eq detached t))
+    (message (if (eq detached t)
+                 "Viewing past version of %s"
+               "Viewing latest version of %s")
+             id)))
+
 (defun c3edit--handle-change (id change)
   "Update buffer to reflect CHANGE in document ID.
 CHANGE should be a variant of the `Change' enum, deserialized into an
@@ -186,7 +543,51 @@ alist."
           ("delete"
            (delete-region
             (1+ .index)
-            (+ 1 .index .len))))))))
+            (+ 1 .index .len)))
+          ("mark"
+           (when-let ((face (cdr (assoc .key c3edit--style-faces))))
+             (add-face-text-property (1+ .index) (+ 1 .index .len) face)))
+          ("unmark"
+           (when-let ((face (cdr (assoc .key c3edit--style-faces))))
+             (c3edit--remove-face (1+ .index) (+ 1 .index .len) face))))))))
+
+(defun c3edit--check-length (id length)
+  "Warn if the buffer of document ID isn't LENGTH characters long.
+LENGTH is the length the backend reports after its latest change."
+  (when-let ((buffer (car (rassoc id c3edit--buffers)))
+             ((/= length (buffer-size buffer))))
+    (display-warning
+     'c3edit
+     (format "Buffer %s is %d characters long, but the backend expects %d"
+             (buffer-name buffer) (buffer-size buffer) length))))
+
+(defun c3edit--handle-comment-moved (id comment-id start end body)
+  "Show comment COMMENT-ID with BODY from START to END in document ID."
+  (when-let ((buffer (car (rassoc id c3edit--buffers))))
+    (if-let ((overlay (alist-get comment-id c3edit--comments nil nil #'equal)))
+        (move-overlay overlay (1+ start) (1+ end) buffer)
+      (setq overlay (make-overlay (1+ start) (1+ end) buffer))
+      (overlay-put overlay 'face 'highlight)
+      (overlay-put overlay 'c3edit-comment comment-id)
+      (setf (alist-get comment-id c3edit--comments nil nil #'equal) overlay))
+    (overlay-put (alist-get comment-id c3edit--comments nil nil #'equal)
+                 'help-echo body)))
+
+(defun c3edit--handle-comment-resolved (comment-id)
+  "Stop showing comment COMMENT-ID."
+  (when-let ((overlay (alist-get comment-id c3edit--comments nil nil #'equal)))
+    (delete-overlay overlay)
+    (setf (alist-get comment-id c3edit--comments nil t #'equal) nil)))
+
+(defun c3edit--remove-face (beg end face)
+  "Remove FACE from the `face' property of text between BEG and END."
+  (let ((pos beg))
+    (while (< pos end)
+      (let ((next (next-single-property-change pos 'face nil end))
+            (value (get-text-property pos 'face)))
+        (put-text-property pos next 'face
+                           (remq face (if (listp value) value (list value))))
+        (setq pos next)))))
 
 (defun c3edit--handle-new-cursor-location (id position mark peer-id)
   "Update cursor (mark if MARK) for PEER-ID in document ID to POSITION."
@@ -221,6 +622,13 @@ alist."
     (with-current-buffer buffer
       (move-overlay overlay point (1+ point)))))
 
+(defun c3edit--handle-cursor-removed (id _peer-id)
+  "Remove cursor overlay for PEER-ID in document ID."
+  (when-let ((data (assoc id c3edit--cursors-alist))
+             (overlay (cdr data)))
+    (delete-overlay overlay)
+    (setcdr data nil)))
+
 (defun c3edit--process-filter (_process text)
   "Process filter for c3edit backend messages.
 Processes message from TEXT."
@@ -232,17 +640,120 @@ Processes message from TEXT."
       (let-alist message
         (pcase .type
           ("change"
-           (c3edit--handle-change .document_id .change))
+           ;; Only the main text container is shown in the buffer.
+           (when (member .container '(nil "text"))
+             (c3edit--handle-change .document_id .change)
+             (when .new_length
+               (c3edit--check-length .document_id .new_length))))
+          ("change_batch"
+           (when (member .container '(nil "text"))
+             (seq-do (lambda (change)
+                       (c3edit--handle-change .document_id change))
+                     .changes)
+             (when .new_length
+               (c3edit--check-length .document_id .new_length))))
           ("add_peer_response"
            (message "Successfully added peer at %s" .address))
-          ("create_document_response"
-           (c3edit--handle-create-document-response .id))
+          ("peer_removed"
+           (setf (alist-get .address c3edit--peer-latency nil t #'equal) nil)
+           (message "Removed peer at %s" .address))
+          ("peer_disconnected"
+           (setf (alist-get .address c3edit--peer-latency nil t #'equal) nil)
+           (message "Peer at %s disconnected" .address))
+          ("checkout_response"
+           (c3edit--handle-checkout-response .document_id .content .detached))
+          ("version"
+           (message "Version of %s: %s" .document_id
+                    (mapconcat #'identity .frontiers ", ")))
+          ("peer_permission_set"
+           (message (if (eq .read_only t)
+                        "Peer at %s is now read-only"
+                      "Peer at %s can now edit")
+                    .address))
+          ("peer_discovered"
+           (setf (alist-get .address c3edit--discovered-peers nil nil #'equal)
+                 .name)
+           (message "Discovered peer %s at %s" .name .address))
+          ("info"
+           (message "c3edit %s (Loro %s, protocol %d), peer ID %s"
+                    .crate_version .loro_version .protocol_version .peer_id))
+          ("stats"
+           (message "%d peers; sent %d messages (%s), received %d messages (%s)"
+                    .peer_count
+                    .messages_sent (file-size-human-readable .bytes_sent)
+                    .messages_received (file-size-human-readable .bytes_received)))
+          ("peer_list"
+           (if (zerop (length .peers))
+               (message "No connected peers")
+             (message "Connected peers: %s"
+                      (mapconcat
+                       (lambda (address)
+                         (if-let ((rtt (alist-get address c3edit--peer-latency
+                                                  nil nil #'equal)))
+                             (format "%s (%d ms)" address rtt)
+                           address))
+                       .peers ", "))))
+          ("document_list"
+           (if (zerop (length .documents))
+               (message "No documents")
+             (message "Documents: %s"
+                      (mapconcat
+                       (lambda (document)
+                         (format "%s (%d characters)"
+                                 (alist-get 'id document)
+                                 (alist-get 'length document)))
+                       .documents ", "))))
+          ("peer_latency"
+           (setf (alist-get .address c3edit--peer-latency nil nil #'equal)
+                 .rtt_ms))
+          ("peer_reconnecting"
+           (message "Reconnecting to peer at %s (attempt %d)" .address .attempt))
+          ("metadata_changed"
+           (if .value
+               (message "%s of %s is now %s" .key .document_id .value)
+             (message "%s of %s was removed" .key .document_id)))
+          ("unsynced_changes"
+           (if (eq .unsynced t)
+               (message "Document %s has %d operations no peer has been sent"
+                        .document_id .pending_ops)
+             (message "Document %s has no unsynced changes" .document_id)))
+          ("divergence_detected"
+           (message "c3edit document %s has diverged from peer at %s"
+                    .document_id .address))
+          ("backpressure"
+           (message (if (eq .active t)
+                        "c3edit backend is falling behind sending to peers"
+                      "c3edit backend has caught up sending to peers")))
+          ("history"
+           (c3edit--handle-history .document_id .entries .truncated))
+          ("diff"
+           (c3edit--handle-diff .document_id .changes))
+          ("document_renamed"
+           (c3edit--handle-document-renamed .old_id .new_id))
+          ("document_closed"
+           (c3edit--handle-document-closed .document_id))
+          ("document_created"
+           (c3edit--handle-document-created .document_id))
           ("join_document_response"
            (c3edit--handle-join-document-response .id .current_content))
           ("set_cursor"
            (c3edit--handle-new-cursor-location .document_id .location .mark .peer_id))
           ("unset_mark"
            (c3edit--handle-unset-mark .document_id .peer_id))
+          ("cursor_removed"
+           (c3edit--handle-cursor-removed .document_id .peer_id))
+          ("comment_moved"
+           (c3edit--handle-comment-moved
+            .document_id .comment_id .start .end .body))
+          ("comment_resolved"
+           (c3edit--handle-comment-resolved .comment_id))
+          ("peer_presence"
+           (setf (alist-get .peer_id c3edit--peer-presence) (cons .name .color)))
+          ("presence_removed"
+           (setf (alist-get .peer_id c3edit--peer-presence nil t) nil))
+          ("error"
+           (display-warning 'c3edit (format "Error while %s: %s" .context .message)
+                            :error))
           (_
            (display-warning
             'c3edit (format "Unknown message type: %s" .type) :warning)))))))
@@ -1,68 +1,501 @@
 mod channels;
+mod discovery;
+mod handle;
+#[cfg(test)]
+mod harness;
+mod logging;
+mod recorder;
 mod tasks;
+mod transport;
 mod utils;
 
-use channels::{Channels, MainTaskMessage, OutgoingMessage};
-use loro::{cursor::Cursor, LoroDoc, PeerID, SubID};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use channels::{Channels, IncomingMessage, MainTaskMessage, OutgoingMessage};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report, Result,
+};
+use discovery::Discovery;
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+use either::Either;
+use futures::SinkExt;
+pub use handle::{connect_in_memory, ClientEvents, ClientHandle};
+pub use logging::{init_logging, LogConfig, LogFile};
+use loro::{
+    cursor::{Cursor, Side},
+    CommitOptions, Container, Frontiers, LoroDoc, LoroMap, LoroValue, PeerID, SubID, UndoManager,
+    VersionVector, ID,
+};
+pub use recorder::replay_log;
+use recorder::Recorder;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tasks::*;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream,
-    },
+    io::AsyncWrite,
+    net::{TcpListener, ToSocketAddrs},
     sync::mpsc::Receiver,
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, error, info, warn};
+use transport::{
+    frame_stream, BoxedConnection, ConnectionOptions, Listener, ReadSocket, TrafficStats,
+    Transport, WriteSocket,
 };
-use tokio_serde::formats::SymmetricalJson;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use tracing::{error, info};
+pub use transport::{ProxyAuth, SigningConfig, Socks5Proxy, TcpOptions, TlsConfig, WireFormat};
 use utils::*;
 
-// I hate Rust sometimes.
-type WriteSocket = tokio_serde::SymmetricallyFramed<
-    FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
-    BackendMessage,
-    SymmetricalJson<BackendMessage>,
->;
-type ReadSocket = tokio_serde::SymmetricallyFramed<
-    FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
-    BackendMessage,
-    SymmetricalJson<BackendMessage>,
->;
+/// Version of the peer-to-peer protocol. Peers must agree on this exactly.
+const PROTOCOL_VERSION: u32 = 11;
+
+/// How long to wait after applying updates from peers before reporting it to
+/// the frontend, so a burst of updates is reported only once.
+const SYNC_REPORT_DELAY: Duration = Duration::from_millis(250);
+
+/// Number of syncs of a document a peer may send ahead of one that hasn't
+/// arrived before they're dropped and a full resync is requested instead.
+const MAX_HELD_SYNCS: usize = 64;
+
+/// Name of the text container holding each document's main content, which
+/// cursors refer to. Documents may hold other named text containers as well.
+const TEXT_CONTAINER: &str = "text";
+
+/// Name of the map container holding each document's metadata, such as its
+/// title.
+const METADATA_CONTAINER: &str = "meta";
+
+/// Name of the map container holding the messages given to changes, keyed
+/// by the ID of each change's first operation.
+const COMMITS_CONTAINER: &str = "commits";
+
+/// Name of the map container holding comments on the main text, keyed by
+/// comment ID. Each is a map of its `body`, whether it's `resolved`, and the
+/// encoded cursors at its `start` and `end`.
+const COMMENTS_CONTAINER: &str = "comments";
+
+fn default_container() -> String {
+    TEXT_CONTAINER.to_owned()
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
 #[serde(tag = "type")]
-enum ClientMessage {
+pub enum ClientMessage {
     AddPeer {
         address: String,
     },
+    /// Connects to each of `addresses` at once, reporting an error for any
+    /// that can't be connected to without affecting the others.
+    AddPeers {
+        addresses: Vec<String>,
+    },
     AddPeerResponse {
         address: String,
     },
+    RemovePeer {
+        address: String,
+    },
+    PeerRemoved {
+        address: String,
+    },
+    /// Sent when the connection to a peer is closed by the peer or fails.
+    PeerDisconnected {
+        address: String,
+    },
+    /// Sent before each attempt to reconnect to a peer that was added with
+    /// `AddPeer` and then dropped.
+    PeerReconnecting {
+        address: String,
+        attempt: u32,
+    },
+    /// Sent when messages to peers start or stop queueing up faster than
+    /// they can be sent, so the frontend can slow down its own.
+    Backpressure {
+        active: bool,
+    },
+    /// Sent whenever the connection to the peer at `address` changes state.
+    PeerState {
+        address: String,
+        state: PeerState,
+    },
+    /// Round-trip time to the peer at `address`, measured on each heartbeat.
+    PeerLatency {
+        address: String,
+        rtt_ms: u64,
+    },
+    /// Sets whether changes from the peer at `address` are rejected. Updates
+    /// are still sent to read-only peers.
+    SetPeerPermission {
+        address: String,
+        read_only: bool,
+    },
+    PeerPermissionSet {
+        address: String,
+        read_only: bool,
+    },
+    ListPeers,
+    PeerList {
+        peers: Vec<String>,
+    },
+    /// Starts advertising this client on the local network as `service_name`
+    /// and looking for other clients, which are reported with
+    /// `PeerDiscovered` but never connected to automatically.
+    EnableDiscovery {
+        service_name: String,
+    },
+    PeerDiscovered {
+        name: String,
+        address: String,
+    },
+    GetStats,
+    /// Asks for the versions of this backend, to check peers are running
+    /// compatible builds.
+    GetInfo,
+    Info {
+        crate_version: String,
+        loro_version: String,
+        /// Peers can only connect if they use the same protocol version.
+        protocol_version: u32,
+        peer_id: PeerID,
+    },
+    /// Totals since the backend started, over all peers. Bytes are counted as
+    /// encoded messages, excluding framing and encryption.
+    Stats {
+        bytes_sent: u64,
+        bytes_received: u64,
+        messages_sent: u64,
+        messages_received: u64,
+        peer_count: usize,
+    },
+    /// Fails if a document with the same ID is already known. If a peer
+    /// creates one with the same ID before hearing of ours, the two are merged
+    /// once they sync, with both initial contents in an order every peer
+    /// agrees on.
     CreateDocument {
         name: String,
-        initial_content: String,
+        /// Left out to create an empty document.
+        #[serde(default)]
+        initial_content: Option<String>,
+        /// Text container to hold `initial_content`.
+        #[serde(default = "default_container")]
+        container: String,
+        // If provided, used as the document's ID instead of one generated from
+        // `name`.
+        #[serde(default)]
+        document_id: Option<String>,
     },
-    CreateDocumentResponse {
-        id: String,
+    ListDocuments,
+    DocumentList {
+        documents: Vec<DocumentSummary>,
+    },
+    /// Creates a document holding the contents of the text file at `path`,
+    /// named after the file.
+    CreateDocumentFromFile {
+        path: String,
+    },
+    DocumentCreated {
+        document_id: String,
+    },
+    GetText {
+        document_id: String,
+        #[serde(default = "default_container")]
+        container: String,
+    },
+    /// The document's text, as shown by its current version.
+    Text {
+        document_id: String,
+        container: String,
+        content: String,
+    },
+    GetVersion {
+        document_id: String,
+    },
+    Version {
+        document_id: String,
+        /// IDs of the latest operations in the document's current state, in the
+        /// form `counter@peer` and sorted so they can be compared directly.
+        frontiers: Vec<String>,
+    },
+    /// Asks whether the document has local edits that no peer has
+    /// acknowledged, e.g. before closing it.
+    HasUnsyncedChanges {
+        document_id: String,
+    },
+    UnsyncedChanges {
+        document_id: String,
+        unsynced: bool,
+        /// Operations made by this client that no peer has acknowledged, not
+        /// counting edits still waiting to be committed.
+        pending_ops: usize,
+    },
+    /// Lists the changes made to a document, most recent first.
+    GetHistory {
+        document_id: String,
+    },
+    History {
+        document_id: String,
+        entries: Vec<HistoryEntry>,
+        /// Whether older changes were left out to stay within the configured
+        /// limit.
+        truncated: bool,
+    },
+    /// Asks peers to check that they have the same contents for the document
+    /// as this client, wherever they have the same version.
+    VerifySync {
+        document_id: String,
+    },
+    /// Sent when the peer at `address` has the same version of the document
+    /// as this client, but different contents, which means sync went wrong.
+    DivergenceDetected {
+        document_id: String,
+        address: String,
+        /// Hash algorithm the contents were compared with.
+        algorithm: String,
+    },
+    /// Sent shortly after updates from peers are applied to a document, at
+    /// most once per burst of updates.
+    SyncApplied {
+        document_id: String,
+        /// The document's version afterwards, as in `Version`.
+        frontiers: Vec<String>,
+    },
+    /// Shows the document as it was at `frontiers`, in the form returned by
+    /// `GetVersion`. Until `CheckoutToLatest`, the document can't be edited and
+    /// changes from peers aren't shown, though they are still received.
+    Checkout {
+        document_id: String,
+        frontiers: Vec<String>,
+    },
+    CheckoutToLatest {
+        document_id: String,
+    },
+    CheckoutResponse {
+        document_id: String,
+        content: String,
+        /// Whether the document is showing a past version.
+        detached: bool,
+    },
+    /// Asks for the changes that turn `container` at `from_frontiers` into
+    /// its contents at `to_frontiers`, both in the form returned by
+    /// `GetVersion`. The document is left at the version it was at.
+    DiffVersions {
+        document_id: String,
+        #[serde(default = "default_container")]
+        container: String,
+        from_frontiers: Vec<String>,
+        to_frontiers: Vec<String>,
+    },
+    /// Answers `DiffVersions` with insertions and deletions to apply in
+    /// order, starting from the earlier version.
+    Diff {
+        document_id: String,
+        container: String,
+        changes: Vec<Change>,
+    },
+    SaveDocument {
+        document_id: String,
+        path: String,
+    },
+    DocumentSaved {
+        document_id: String,
+        path: String,
+    },
+    LoadDocument {
+        path: String,
+    },
+    /// Unloads a document, so it no longer uses memory or is sent to peers.
+    /// Peers may still send it again, unless it's excluded by `Subscribe`.
+    CloseDocument {
+        document_id: String,
+        /// If provided, the document is saved here first, as with
+        /// `SaveDocument`.
+        #[serde(default)]
+        path: Option<String>,
+        /// Closes the document even if it has local changes no peer has
+        /// acknowledged, as `HasUnsyncedChanges` reports, discarding them.
+        #[serde(default)]
+        force: bool,
+    },
+    DocumentClosed {
+        document_id: String,
+    },
+    /// Changes a document's ID, for every peer. Fails if `new_id` is already
+    /// in use.
+    RenameDocument {
+        old_id: String,
+        new_id: String,
+    },
+    /// Sent when a document's ID is changed, whether by the frontend or a
+    /// peer.
+    DocumentRenamed {
+        old_id: String,
+        new_id: String,
+    },
+    /// Exports a compact snapshot of the document, without needing a file.
+    ExportSnapshot {
+        document_id: String,
+    },
+    /// Holds the base64-encoded document, in the same format as
+    /// `SaveDocument`'s files.
+    Snapshot {
+        document_id: String,
+        data: String,
+    },
+    /// Opens a document from base64 `data` produced by `ExportSnapshot`.
+    ImportSnapshot {
+        data: String,
+        /// If provided, used as the document's ID instead of a generated one.
+        #[serde(default)]
+        document_id: Option<String>,
     },
     Change {
         document_id: String,
+        /// Text container the change applies to.
+        #[serde(default = "default_container")]
+        container: String,
         change: Change,
+        /// Where the change was made. Only set on changes sent to the
+        /// frontend, and left out when replaying a joined document's contents.
+        #[serde(default)]
+        origin: Option<ChangeOrigin>,
+        /// Seconds since the Unix epoch to record the change as made at,
+        /// rather than now. Only used on changes from the frontend.
+        #[serde(default)]
+        timestamp: Option<i64>,
+        /// Describes the change in the document's history. Only used on
+        /// changes from the frontend.
+        #[serde(default)]
+        message: Option<String>,
+        /// Length of the container's text after the change, in the units of
+        /// the client's `IndexMode`, so the frontend can check it agrees.
+        /// Only set on edits forwarded to the frontend as they happen.
+        #[serde(default)]
+        new_length: Option<usize>,
+    },
+    /// Several changes applied, in order, as a single edit.
+    ChangeBatch {
+        document_id: String,
+        #[serde(default = "default_container")]
+        container: String,
+        changes: Vec<Change>,
+        #[serde(default)]
+        origin: Option<ChangeOrigin>,
+        #[serde(default)]
+        timestamp: Option<i64>,
+        #[serde(default)]
+        message: Option<String>,
+        /// Length of the container's text after the whole batch.
+        #[serde(default)]
+        new_length: Option<usize>,
+    },
+    /// Sets `key` in the document's metadata for every peer, e.g. `title`.
+    /// Leaving out `value` removes the key.
+    SetMetadata {
+        document_id: String,
+        key: String,
+        #[serde(default)]
+        value: Option<String>,
+    },
+    /// Sent when a peer changes the document's metadata, and for each entry
+    /// when a document is joined. `value` is left out if the key was removed.
+    MetadataChanged {
+        document_id: String,
+        key: String,
+        value: Option<String>,
+    },
+    /// Attaches a comment to the main text from `start` up to `end`, which
+    /// then moves with edits around it.
+    AddComment {
+        document_id: String,
+        start: usize,
+        end: usize,
+        body: String,
+    },
+    /// Marks a comment as resolved for every peer.
+    ResolveComment {
+        document_id: String,
+        comment_id: String,
+    },
+    /// Sent when a comment is added, or edits move either end of it, and for
+    /// each comment when a document is joined.
+    CommentMoved {
+        document_id: String,
+        comment_id: String,
+        start: usize,
+        end: usize,
+        body: String,
+    },
+    /// Sent when a comment is resolved, or all of the text it's attached to
+    /// is deleted, so should no longer be shown.
+    CommentResolved {
+        document_id: String,
+        comment_id: String,
+    },
+    /// Stops sending local changes to the document to peers, so a set of edits
+    /// can be published all at once with `ResumeSync`.
+    PauseSync {
+        document_id: String,
+        /// Also holds updates from peers until sync is resumed, rather than
+        /// applying them.
+        #[serde(default)]
+        hold_incoming: bool,
+    },
+    /// Sends peers every local change made since `PauseSync`, and applies any
+    /// held updates from them.
+    ResumeSync {
+        document_id: String,
+    },
+    /// Deletes all text in a container, for every peer. The deletion is sent
+    /// back to the frontend as a `Change`.
+    ClearDocument {
+        document_id: String,
+        #[serde(default = "default_container")]
+        container: String,
+    },
+    Undo {
+        document_id: String,
+    },
+    Redo {
+        document_id: String,
     },
     JoinDocument {
         id: String,
     },
+    /// Holds the content of the main text container, followed by the rest of
+    /// the document as `Change`s.
     JoinDocumentResponse {
         id: String,
         current_content: String,
     },
+    /// Only receives updates from peers for the documents subscribed to this
+    /// way, rather than for every document.
+    Subscribe {
+        document_id: String,
+    },
+    /// Exchanges the full state of a document with the peer at `address`, to
+    /// recover from updates that were lost or misapplied.
+    RequestSync {
+        address: String,
+        document_id: String,
+    },
     SetCursor {
         document_id: String,
         // This field should be none for the client's cursor.
         peer_id: Option<PeerID>,
+        /// Index in Unicode code points, as in `Change`.
         location: usize,
         #[serde(default)]
         mark: bool,
@@ -71,20 +504,190 @@ enum ClientMessage {
         document_id: String,
         peer_id: Option<PeerID>,
     },
+    CursorRemoved {
+        document_id: String,
+        peer_id: PeerID,
+    },
+    /// Sets how this client is shown to other users, e.g. in a list of
+    /// participants.
+    SetPresence {
+        name: String,
+        color: String,
+    },
+    PeerPresence {
+        peer_id: PeerID,
+        name: String,
+        color: String,
+    },
+    /// The peer disconnected, so should no longer be shown.
+    PresenceRemoved {
+        peer_id: PeerID,
+    },
+    /// Stops the backend, first sending any changes not yet sent to peers.
+    Shutdown,
+    Error {
+        message: String,
+        // What the backend was doing when the error occurred.
+        context: String,
+    },
 }
 
+/// A document known to the backend, as listed by `DocumentList`.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentSummary {
+    pub id: String,
+    /// Length of the main text container, counted according to the client's
+    /// `IndexMode`.
+    pub length: usize,
+}
+
+/// A single edit to a document's text.
+///
+/// All indices and lengths are counted in Unicode code points (not bytes or
+/// UTF-16 code units), matching Loro's text API, unless the client's
+/// `IndexMode` says otherwise.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
+#[serde(tag = "type")]
+pub enum Change {
+    Insert {
+        index: usize,
+        text: String,
+    },
+    /// Inserts `text` at the end of the text, however long it is by the time
+    /// the change is applied. Only accepted from the frontend.
+    Append {
+        text: String,
+    },
+    Delete {
+        index: usize,
+        len: usize,
+    },
+    /// Applies the style `key` to a range of text. The supported keys are
+    /// `bold`, `italic`, `underline`, `link`, `highlight`, `comment`, and
+    /// `code`.
+    Mark {
+        index: usize,
+        len: usize,
+        key: String,
+        value: LoroValue,
+    },
+    Unmark {
+        index: usize,
+        len: usize,
+        key: String,
+    },
+}
+
+/// Stage of the connection to a peer, as reported by `PeerState`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+    /// Opening a connection requested with `AddPeer`.
+    Connecting,
+    /// Connected, but waiting for the peer's hello.
+    Handshaking,
+    /// Ready to exchange updates.
+    Connected,
+    /// Trying to restore a dropped connection.
+    Reconnecting,
+    /// No longer connected, and not trying to reconnect unless followed by
+    /// `Reconnecting`.
+    Disconnected,
+}
+
+/// How the frontend counts positions in text, for the indices and lengths
+/// of changes and cursor locations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Unicode code points, which is how documents count them.
+    #[default]
+    CodePoint,
+    /// UTF-16 code units, as used by JavaScript and many editors, where
+    /// characters outside the Basic Multilingual Plane count twice.
+    Utf16,
+}
+
+/// Where messages to the frontend are written, one JSON object per line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// A file, appended to and created if needed, or a named pipe.
+    Path(PathBuf),
+}
+
+/// Where a change sent to the frontend was made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all(serialize = "snake_case", deserialize = "snake_case"))]
 #[serde(tag = "type")]
-enum Change {
-    Insert { index: usize, text: String },
-    Delete { index: usize, len: usize },
+pub enum ChangeOrigin {
+    /// Made by this client, e.g. by undoing an edit.
+    Local,
+    /// Received from the peer with this ID. Peers forward each other's
+    /// changes, so this is the peer that sent it, not necessarily its author.
+    Remote { peer_id: PeerID },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum BackendMessage {
+    /// First message sent by each side of a new connection.
+    Hello {
+        protocol_version: u32,
+        peer_id: PeerID,
+        /// Whether the sender only receives updates, never sending its own.
+        #[serde(default)]
+        read_only: bool,
+        /// Encoding the sender would like to receive messages in.
+        #[serde(default)]
+        wire_format: WireFormat,
+        /// Whether the sender would like document updates compressed, which
+        /// is only done if both sides do.
+        #[serde(default)]
+        compression: bool,
+        /// Documents the sender wants updates for, or every document if left
+        /// out.
+        #[serde(default)]
+        subscriptions: Option<Vec<String>>,
+        /// Ed25519 key the sender signs its messages with, if it does.
+        #[serde(default)]
+        public_key: Option<ByteBuf>,
+        /// Random challenge the receiver must answer with a `PskProof`, sent
+        /// if the sender requires a pre-shared key.
+        #[serde(default)]
+        psk_nonce: Option<ByteBuf>,
+    },
+    /// Proves the sender knows the pre-shared key, in answer to the nonce in
+    /// the receiver's hello.
+    PskProof {
+        mac: ByteBuf,
+    },
+    /// Wraps another encoded message along with the sender's signature of it.
+    /// Only seen by the transport, which unwraps it.
+    Signed {
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+    },
+    /// Operations in a document, as exported by Loro.
+    ///
+    /// Within a connection, a peer applies the syncs of each document in the
+    /// order they were sent, holding any that arrive early until the ones
+    /// before them do. Syncs of different documents, and cursor and presence
+    /// updates, may be applied in any order relative to each other. After a
+    /// reconnect, numbering starts again, and the sync on connecting sends
+    /// whatever the dropped connection didn't deliver.
     DocumentSync {
+        document_id: String,
+        #[serde(with = "serde_bytes")]
         data: Vec<u8>,
+        /// Whether `data` is compressed with gzip, as agreed in the handshake.
+        #[serde(default)]
+        compressed: bool,
+        /// Position of this sync among those of the document sent over this
+        /// connection, starting from 0.
+        seq: u64,
     },
     CursorUpdate {
         document_id: String,
@@ -96,71 +699,793 @@ enum BackendMessage {
         document_id: String,
         peer_id: PeerID,
     },
+    Presence(Presence),
+    /// Sent by each side once the handshake completes, holding the version of
+    /// every document the sender has, encoded with `VersionVector::encode`.
+    /// Each side answers with the operations the other is missing.
+    Versions {
+        versions: HashMap<String, ByteBuf>,
+    },
+    /// Asks the peer to send updates for the document from now on. Once a
+    /// peer subscribes to any document, it's only sent updates for the
+    /// documents it has subscribed to.
+    Subscribe {
+        document_id: String,
+    },
+    /// Asks the peer to send the full state of the document.
+    SyncRequest {
+        document_id: String,
+    },
+    /// Hash of a document's contents at the version given by `frontiers`,
+    /// encoded with `Frontiers::encode`, to check that peers converged.
+    StateHash {
+        document_id: String,
+        /// Name of the hash algorithm, so hashes made differently are never
+        /// compared.
+        algorithm: String,
+        hash: u64,
+        frontiers: ByteBuf,
+    },
+    /// Tells the peer a document's ID has changed, so that IDs keep matching.
+    /// Forwarded to other peers, like updates.
+    RenameDocument {
+        old_id: String,
+        new_id: String,
+    },
+    /// Sent after importing a `DocumentSync`, holding the receiver's
+    /// frontiers of the document encoded with `Frontiers::encode`, so the
+    /// sender knows which operations it no longer needs to send.
+    Ack {
+        document_id: String,
+        frontiers: ByteBuf,
+    },
+    /// Sent periodically to check that the peer is still reachable.
+    Ping,
+    Pong,
+}
+
+impl BackendMessage {
+    /// Creates a `DocumentSync` message with uncompressed `data`, which is
+    /// compressed when sent to peers that have agreed to it.
+    fn document_sync(document_id: &str, data: Vec<u8>, seq: u64) -> Self {
+        BackendMessage::DocumentSync {
+            document_id: document_id.to_owned(),
+            data,
+            compressed: false,
+            seq,
+        }
+    }
+
+    /// Whether this message carries something from the peer's user, rather
+    /// than being sent on a timer or in reply to another message. Only these
+    /// keep a connection from counting as idle, since heartbeats would
+    /// otherwise keep every connection alive.
+    fn is_data(&self) -> bool {
+        matches!(
+            self,
+            BackendMessage::DocumentSync { .. }
+                | BackendMessage::CursorUpdate { .. }
+                | BackendMessage::UnsetMark { .. }
+                | BackendMessage::Presence(_)
+                | BackendMessage::RenameDocument { .. }
+        )
+    }
+}
+
+/// Summary of one change to a document, i.e. a run of operations committed
+/// together by one peer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub peer_id: PeerID,
+    /// Seconds since the Unix epoch at which the change was made, according
+    /// to its author's clock.
+    pub timestamp: i64,
+    pub op_count: usize,
+    /// ID of the change's last operation, in the `counter@peer` form accepted
+    /// by `Checkout`, to show the document as of this change.
+    pub version: String,
+    /// Messages given with the changes the frontend made in this change,
+    /// joined by newlines.
+    pub message: Option<String>,
+}
+
+/// How a client is shown to other users.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Presence {
+    name: String,
+    color: String,
 }
 
 pub struct ClientBuilder {
     listener: TcpListener,
+    /// Listeners besides `listener`, each accepting connections in its own
+    /// task.
+    listeners: Vec<(Listener, Transport)>,
+    config: ClientConfig,
+    /// Documents to open before any peer connects, keyed by ID.
+    documents: HashMap<String, LoroDoc>,
+    output: Option<FrontendWriter>,
 }
 
 impl ClientBuilder {
     pub fn new(listener: TcpListener) -> Self {
-        ClientBuilder { listener }
+        ClientBuilder {
+            listener,
+            listeners: Vec::new(),
+            config: ClientConfig::default(),
+            documents: HashMap::new(),
+            output: None,
+        }
+    }
+
+    /// Binds a TCP listener to `addr` and accepts peer connections on it.
+    /// Binding to port 0 picks a free port, which [`Self::local_addr`]
+    /// reports.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(ClientBuilder::new(TcpListener::bind(addr).await?))
+    }
+
+    /// Address the TCP listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Additionally accepts peer connections over TCP on `listener`. May be
+    /// called more than once, like the other listener methods.
+    pub fn tcp_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners
+            .push((Listener::Tcp(listener), Transport::Stream));
+        self
+    }
+
+    /// Additionally accepts peer connections over WebSocket on `listener`.
+    pub fn websocket_listener(mut self, listener: TcpListener) -> Self {
+        self.listeners
+            .push((Listener::Tcp(listener), Transport::WebSocket));
+        self
+    }
+
+    /// Additionally accepts peer connections from the local machine on the
+    /// Unix domain socket `listener`.
+    #[cfg(unix)]
+    pub fn unix_listener(mut self, listener: UnixListener) -> Self {
+        self.listeners
+            .push((Listener::Unix(listener, 0), Transport::Stream));
+        self
+    }
+
+    pub fn config(mut self, config: ClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Writes messages to the frontend to `writer` rather than the config's
+    /// `output`.
+    pub fn output(mut self, writer: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        self.output = Some(Box::new(writer));
+        self
+    }
+
+    /// Starts the client with the document in `snapshot`, in the format
+    /// written by `SaveDocument`, under the ID `id`. It's sent to peers as
+    /// soon as they connect, and can be opened by the frontend with
+    /// `JoinDocument`.
+    pub fn document(mut self, id: impl Into<String>, snapshot: &[u8]) -> Result<Self> {
+        let id = id.into();
+        if self.documents.contains_key(&id) {
+            bail!("Document already exists: {id}");
+        }
+        let doc = LoroDoc::new();
+        doc.import(snapshot)
+            .wrap_err_with(|| format!("Failed to import snapshot for {id}"))?;
+
+        self.documents.insert(id, doc);
+        Ok(self)
+    }
+
+    /// Builds a client driven by the frontend over stdin and the configured
+    /// output, or by nothing at all if configured as a relay.
+    pub fn build(mut self) -> Client {
+        let relay = self.config.relay;
+        let shutdown_on_stdin_close = self.config.shutdown_on_stdin_close;
+        let output = match self.output.take() {
+            Some(writer) => OutputWriter::Writer(writer),
+            None => OutputWriter::Target(self.config.output.clone()),
+        };
+        let (mut client, handle, events) = Client::new(self);
+        if relay {
+            client.tasks.stdout = Some(begin_discard_task(events));
+            return client;
+        }
+        client.tasks.stdout = Some(begin_stdout_task(events, output));
+        client.tasks.background.push(begin_stdin_task(
+            handle,
+            client.channels.stdout_tx.clone(),
+            shutdown_on_stdin_close,
+        ));
+
+        client
     }
 
-    pub fn build(self) -> Client {
+    /// Builds a client driven from Rust through the returned handle, leaving
+    /// stdin and stdout alone. Its event loop must still be run.
+    pub fn build_embedded(self) -> (Client, ClientHandle, ClientEvents) {
         Client::new(self)
     }
 }
 
+/// Tuning for the client's tasks and connections.
+///
+/// Channel capacities must all be nonzero. When a channel is full, its sender
+/// waits for space, so a small capacity makes a slow consumer hold up its
+/// producers.
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// Messages to the main event loop from every other task. When full,
+    /// reading from peers and the frontend pauses.
+    pub main_capacity: usize,
+    /// Messages to the frontend. When full, the main event loop stalls until
+    /// the frontend catches up.
+    pub stdout_capacity: usize,
+    /// Sockets to be registered with the incoming task. Rarely has more than
+    /// one message in flight.
+    pub incoming_capacity: usize,
+    /// Messages to peers, before they're queued for each peer. When full,
+    /// the main event loop stalls until they're queued.
+    pub outgoing_capacity: usize,
+    /// Messages waiting to be written to each peer. A peer that lets its
+    /// queue fill up is disconnected, so a slow peer can't hold up the rest.
+    pub peer_queue_capacity: usize,
+    /// Options for TCP connections to peers.
+    pub tcp: TcpOptions,
+    /// Encrypts all peer connections, both incoming and outgoing, with TLS.
+    /// Connections over Unix domain sockets are left unencrypted.
+    pub tls: Option<TlsConfig>,
+    /// How often every peer is pinged.
+    pub heartbeat_interval: Duration,
+    /// Disconnects peers that miss this many pongs in a row.
+    pub max_missed_pongs: u32,
+    /// Logging setup, applied by [`init_logging`] before the client is built.
+    pub log: LogConfig,
+    /// Disconnects peers that send no document updates, cursors or presence
+    /// for this long, however many pings and pongs they send.
+    /// Only checked on each heartbeat, so peers may be idle for up to one
+    /// heartbeat interval longer.
+    pub idle_timeout: Option<Duration>,
+    /// Largest size in bytes a document's text may grow to, through either
+    /// local edits or updates from peers. Each update is assumed to add up to
+    /// its own encoded size, so updates carrying a lot of history may be
+    /// refused before the text itself reaches this size.
+    pub max_document_size: usize,
+    /// Messages read from each peer are delayed once they exceed this rate,
+    /// so one peer flooding the client can't starve the rest. Unlimited by
+    /// default.
+    pub incoming_rate: Option<RateLimit>,
+    /// Only receives updates from peers: local edits are refused, and peers
+    /// are told not to accept changes from this client.
+    pub read_only: bool,
+    /// Coalesces local changes made within this long of each other into a
+    /// single broadcast, rather than sending one per change.
+    pub debounce: Option<Duration>,
+    /// Sends local changes to peers once every interval, however fast they
+    /// are made, so each is sent within the interval and traffic stays
+    /// steady. Takes precedence over `debounce`.
+    pub flush_interval: Option<Duration>,
+    /// Derives this client's peer ID from this string instead of choosing a
+    /// random one, so peers recognize it as the same client after it
+    /// restarts. Every client must have a different identity, since two
+    /// clients with the same peer ID editing a document would corrupt it.
+    pub identity: Option<String>,
+    /// Runs without a frontend, only holding documents and passing updates
+    /// between peers, so that a session stays available while editors come
+    /// and go. Only affects [`ClientBuilder::build`].
+    pub relay: bool,
+    /// Times to try connecting to a peer added with `AddPeer` before giving
+    /// up, e.g. while it's still starting. Must be nonzero.
+    pub connect_attempts: u32,
+    /// Delay before retrying a failed connection, doubled after each attempt.
+    pub connect_retry_delay: Duration,
+    /// Automatically reconnects to peers added with `AddPeer` when their
+    /// connection drops.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Makes connections to peers added with `AddPeer` through this proxy.
+    pub proxy: Option<Socks5Proxy>,
+    /// Whether to ask peers to compress document updates sent to this client,
+    /// and to compress those sent to peers that ask for it. Worthwhile on slow
+    /// links, but costs CPU time on fast ones.
+    pub compression: bool,
+    /// Encodes messages to peers that also prefer this format with it. Other
+    /// peers are always sent JSON.
+    pub wire_format: WireFormat,
+    /// Most peers to be connected to at once, including connections still
+    /// being made. Further incoming connections are closed, and `AddPeer`
+    /// fails.
+    pub max_peers: Option<usize>,
+    /// Signs messages to peers and requires them to sign theirs, so that
+    /// edits can't be forged even without TLS.
+    pub signing: Option<SigningConfig>,
+    /// Password peers must prove they know before syncing, without sending
+    /// it. Simpler to set up than signing, but only as strong as the
+    /// password, and doesn't protect messages once connected.
+    pub psk: Option<String>,
+    /// Largest message in bytes accepted from a peer over TCP or a Unix
+    /// domain socket. Peers announcing a larger one are disconnected, so they
+    /// can't make the client allocate arbitrarily large buffers.
+    pub max_frame_length: usize,
+    /// Shuts down gracefully on SIGINT or SIGTERM, sending any updates still
+    /// queued for peers before exiting. Embedders handling signals themselves
+    /// should turn this off and send `Shutdown` instead.
+    pub handle_signals: bool,
+    /// Peers without any of a document are sent a snapshot of it, rather than
+    /// every update, once its history has this many operations. Snapshots of
+    /// long histories are much smaller, but slower to produce for short ones.
+    pub snapshot_threshold: Option<usize>,
+    /// Most entries returned by `GetHistory`, keeping only the most recent.
+    pub max_history_entries: usize,
+    /// Reports `Backpressure` to the frontend once queueing a message for
+    /// peers takes this long, which means the outgoing channel is full.
+    pub backpressure_threshold: Option<Duration>,
+    /// Sends peers a hash of each document's contents whenever updates from
+    /// them have been applied, so divergence is noticed without asking with
+    /// `VerifySync`.
+    pub verify_after_sync: bool,
+    /// How the frontend counts positions in text.
+    pub index_mode: IndexMode,
+    /// Exchanges the full state of a document with any peer found to have
+    /// diverged from this client.
+    pub resync_on_divergence: bool,
+    /// Records every message exchanged with peers to this file, for
+    /// reproducing sync bugs with `replay_log`.
+    pub record_path: Option<PathBuf>,
+    /// Shuts the client down once stdin closes, i.e. when the frontend
+    /// exits, rather than leaving it running to serve peers. Only applies to
+    /// clients built with `ClientBuilder::build`.
+    pub shutdown_on_stdin_close: bool,
+    /// Where messages to the frontend are written, unless a writer is given
+    /// with `ClientBuilder::output`. Only applies to clients built with
+    /// `ClientBuilder::build`, other than relays.
+    pub output: Output,
+}
+
+// Written out so the pre-shared key never ends up in logs.
+impl fmt::Debug for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientConfig")
+            .field("main_capacity", &self.main_capacity)
+            .field("stdout_capacity", &self.stdout_capacity)
+            .field("incoming_capacity", &self.incoming_capacity)
+            .field("outgoing_capacity", &self.outgoing_capacity)
+            .field("peer_queue_capacity", &self.peer_queue_capacity)
+            .field("tcp", &self.tcp)
+            .field("tls", &self.tls.as_ref().map(|_| "<configured>"))
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("max_missed_pongs", &self.max_missed_pongs)
+            .field("log", &self.log)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_document_size", &self.max_document_size)
+            .field("incoming_rate", &self.incoming_rate)
+            .field("read_only", &self.read_only)
+            .field("debounce", &self.debounce)
+            .field("flush_interval", &self.flush_interval)
+            .field("identity", &self.identity)
+            .field("relay", &self.relay)
+            .field("connect_attempts", &self.connect_attempts)
+            .field("connect_retry_delay", &self.connect_retry_delay)
+            .field("reconnect", &self.reconnect)
+            .field("proxy", &self.proxy)
+            .field("compression", &self.compression)
+            .field("wire_format", &self.wire_format)
+            .field("max_peers", &self.max_peers)
+            .field("signing", &self.signing)
+            .field("psk", &self.psk.as_ref().map(|_| "<redacted>"))
+            .field("max_frame_length", &self.max_frame_length)
+            .field("handle_signals", &self.handle_signals)
+            .field("snapshot_threshold", &self.snapshot_threshold)
+            .field("max_history_entries", &self.max_history_entries)
+            .field("backpressure_threshold", &self.backpressure_threshold)
+            .field("verify_after_sync", &self.verify_after_sync)
+            .field("index_mode", &self.index_mode)
+            .field("resync_on_divergence", &self.resync_on_divergence)
+            .field("record_path", &self.record_path)
+            .field("shutdown_on_stdin_close", &self.shutdown_on_stdin_close)
+            .field("output", &self.output)
+            .finish()
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            main_capacity: 10,
+            stdout_capacity: 10,
+            incoming_capacity: 1,
+            outgoing_capacity: 10,
+            peer_queue_capacity: 256,
+            tcp: TcpOptions::default(),
+            tls: None,
+            heartbeat_interval: Duration::from_secs(5),
+            max_missed_pongs: 3,
+            log: LogConfig::default(),
+            idle_timeout: None,
+            max_document_size: 64 * 1024 * 1024,
+            incoming_rate: None,
+            read_only: false,
+            debounce: None,
+            flush_interval: None,
+            identity: None,
+            relay: false,
+            connect_attempts: 3,
+            connect_retry_delay: Duration::from_millis(500),
+            reconnect: None,
+            proxy: None,
+            compression: true,
+            wire_format: WireFormat::default(),
+            max_peers: None,
+            signing: None,
+            psk: None,
+            max_frame_length: 8 * 1024 * 1024,
+            handle_signals: true,
+            snapshot_threshold: Some(10_000),
+            max_history_entries: 1000,
+            backpressure_threshold: Some(Duration::from_millis(100)),
+            verify_after_sync: false,
+            index_mode: IndexMode::CodePoint,
+            resync_on_divergence: true,
+            record_path: None,
+            output: Output::Stdout,
+            shutdown_on_stdin_close: true,
+        }
+    }
+}
+
+/// How to retry connecting to a dropped peer.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt, doubled after each failed attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: 10,
+        }
+    }
+}
+
+/// A maximum sustained rate of messages, with bursts allowed above it.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: u32,
+    /// Messages that can be handled at once after a quiet period.
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            per_second: 100,
+            burst: 200,
+        }
+    }
+}
+
 pub struct Client {
-    doc: LoroDoc,
+    peer_id: PeerID,
+    connection: ConnectionOptions,
+    documents: HashMap<String, LoroDoc>,
     channels: Channels,
+    tasks: TaskHandles,
     main_channel_rx: Receiver<MainTaskMessage>,
     active_documents: HashMap<String, DocumentInfo>,
+    /// Currently connected peers, keyed by address.
+    peers: HashMap<String, PeerInfo>,
+    config: ClientConfig,
+    /// Tasks connecting or reconnecting to peers added with `AddPeer`, keyed
+    /// by address.
+    connecting: HashMap<String, JoinHandle<()>>,
+    /// Whether `Backpressure` was last reported as active. Atomic only so it
+    /// can be updated through `&self`.
+    backpressure: AtomicBool,
+    /// Documents with updates from peers applied but not yet reported to the
+    /// frontend.
+    sync_report_pending: HashSet<String>,
+    /// Address of the TCP listener, whose port is advertised when discovery
+    /// is enabled.
+    local_addr: Option<SocketAddr>,
+    discovery: Option<Discovery>,
+    presence: Option<Presence>,
+    /// Documents peers are asked to send updates for, or every document if
+    /// none have been subscribed to.
+    subscriptions: Option<HashSet<String>>,
 }
 
 impl Client {
+    /// Address the TCP listener is bound to, if it could be determined.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
     pub async fn begin_event_loop(mut self) {
         info!("Entering main event loop");
 
         while let Some(message) = self.main_channel_rx.recv().await {
-            match message {
-                MainTaskMessage::NewConnection(connection) => {
-                    self.accept_new_connection(connection).await;
+            let (result, context) = match message {
+                MainTaskMessage::ClientMessage(ClientMessage::Shutdown) => break,
+                MainTaskMessage::NewConnection(sockets, address) => (
+                    self.accept_new_connection(sockets, &address).await,
+                    "accepting connection",
+                ),
+                MainTaskMessage::ClientMessage(c_message) => (
+                    self.handle_client_message(c_message).await,
+                    "handling message from frontend",
+                ),
+                MainTaskMessage::BackendMessage(address, data) => (
+                    self.handle_backend_message(&address, data).await,
+                    "handling message from peer",
+                ),
+                MainTaskMessage::DocumentChanged(id) => (
+                    self.handle_document_changed(&id).await,
+                    "updating cursor locations",
+                ),
+                MainTaskMessage::PeerDisconnected(address) => (
+                    self.handle_peer_disconnected(&address).await,
+                    "handling disconnected peer",
+                ),
+                MainTaskMessage::PeerConnected(address, sockets) => (
+                    self.handle_peer_connected(&address, sockets).await,
+                    "connecting to peer",
+                ),
+                MainTaskMessage::NewStream(address, connection, outgoing) => (
+                    self.attach_stream(&address, connection, outgoing).await,
+                    "attaching connection",
+                ),
+                MainTaskMessage::FlushDocument(id) => {
+                    (self.flush_document(&id).await, "broadcasting changes")
                 }
-                MainTaskMessage::ClientMessage(c_message) => {
-                    self.handle_client_message(c_message).await;
+                MainTaskMessage::FlushAll => {
+                    (self.flush_pending_documents().await, "broadcasting changes")
                 }
-                MainTaskMessage::BackendMessage(data) => {
-                    self.handle_backend_message(data).await;
+                MainTaskMessage::ReportSync(id) => {
+                    (self.report_sync(&id).await, "reporting applied updates")
                 }
-                MainTaskMessage::DocumentChanged(id) => {
-                    info!("Updating cursor locations for document {}", id);
-
-                    let doc_info = self.active_documents.get(&id).unwrap();
-
-                    self.broadcast_cursor_update(&id).await;
-                    for peer_id in doc_info.cursors.keys() {
-                        self.update_frontend_cursor(&id, Some(*peer_id), false)
-                            .await;
-                    }
-                    for peer_id in doc_info.marks.keys() {
-                        self.update_frontend_cursor(&id, Some(*peer_id), true).await;
-                    }
+                MainTaskMessage::Heartbeat => {
+                    (self.handle_heartbeat().await, "checking peer connections")
                 }
+            };
+
+            if let Err(e) = result {
+                self.report_error(e, context).await;
             }
         }
+
+        self.shutdown().await;
     }
 
-    fn new(builder: ClientBuilder) -> Self {
+    /// Stops accepting connections, sends any changes not yet sent to peers,
+    /// and closes every connection, waiting for all messages to be written.
+    async fn shutdown(mut self) {
+        info!("Shutting down");
+
+        let mut aborted = self.tasks.abort_background().await;
+        for (_, handle) in self.connecting.drain() {
+            handle.abort();
+            let _ = handle.await;
+            aborted += 1;
+        }
+        debug!("Stopped {} background tasks", aborted);
+        if let Some(discovery) = self.discovery.take() {
+            discovery.stop();
+        }
+
+        let pending: Vec<_> = self
+            .active_documents
+            .iter()
+            .filter(|(_, doc_info)| doc_info.flush_pending)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in pending {
+            if let Err(e) = self.flush_document(&id).await {
+                self.report_error(e, "broadcasting changes").await;
+            }
+        }
+
+        // Moving out of a temporary drops everything else right away,
+        // including documents whose subscriptions hold senders to the stdout
+        // task. Each task then exits once its channel is closed and drained.
+        let Client {
+            channels, tasks, ..
+        } = { self };
+        drop(channels.incoming_to_tx);
+        let _ = tasks.incoming.await;
+        drop(channels.outgoing_tx);
+        let _ = tasks.outgoing.await;
+        drop(channels.stdout_tx);
+        if let Some(stdout) = tasks.stdout {
+            let _ = stdout.await;
+        }
+
+        info!("Shutdown complete");
+    }
+
+    /// Logs `error` and reports it to the frontend.
+    async fn report_error(&self, error: Report, context: &str) {
+        error!("Error while {}: {:?}", context, error);
+
+        let message = ClientMessage::Error {
+            message: format!("{error:#}"),
+            context: context.to_owned(),
+        };
+        if self.channels.stdout_tx.send(message).await.is_err() {
+            error!("Stdout task exited; could not report error");
+        }
+    }
+
+    /// Sends `message` to every peer that has finished its handshake.
+    async fn broadcast(&self, message: BackendMessage) -> Result<()> {
+        let addresses: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.handshake_complete())
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in addresses {
+            self.send_outgoing(OutgoingMessage::DirectMessage {
+                address,
+                message: message.clone(),
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Queues a message for the outgoing task, telling the frontend whenever
+    /// doing so starts or stops being slow.
+    async fn send_outgoing(&self, message: OutgoingMessage) -> Result<()> {
+        let start = Instant::now();
+        self.channels.outgoing_tx.send(message).await?;
+        let Some(threshold) = self.config.backpressure_threshold else {
+            return Ok(());
+        };
+
+        let tx = &self.channels.outgoing_tx;
+        let active = if start.elapsed() >= threshold {
+            true
+        } else if tx.capacity() * 2 >= tx.max_capacity() {
+            // Wait for the queue to drain somewhat first, so that reports
+            // don't flap while it hovers around full.
+            false
+        } else {
+            return Ok(());
+        };
+        if self.backpressure.swap(active, Ordering::SeqCst) == active {
+            return Ok(());
+        }
+
+        if active {
+            warn!("Messages to peers are backing up");
+        } else {
+            info!("Messages to peers are no longer backing up");
+        }
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::Backpressure { active })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Informs the frontend that the connection to `address` is now in
+    /// `state`.
+    async fn report_peer_state(&self, address: &str, state: PeerState) -> Result<()> {
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::PeerState {
+                address: address.to_owned(),
+                state,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_document_changed(&mut self, id: &str) -> Result<()> {
+        info!("Updating cursor locations for document {}", id);
+
+        let Some(doc_info) = self.active_documents.get(id) else {
+            // Document is no longer active.
+            return Ok(());
+        };
+
+        self.broadcast_cursor_update(id).await?;
+        for peer_id in doc_info.cursors.keys() {
+            self.update_frontend_cursor(id, Some(*peer_id), false)
+                .await?;
+        }
+        for peer_id in doc_info.marks.keys() {
+            self.update_frontend_cursor(id, Some(*peer_id), true)
+                .await?;
+        }
+        self.update_comments(id).await?;
+
+        Ok(())
+    }
+
+    /// Tells the frontend about comments in the given document that were
+    /// added or moved since it was last told, and those no longer open.
+    async fn update_comments(&mut self, document_id: &str) -> Result<()> {
+        let Some(doc_info) = self.active_documents.get(document_id) else {
+            return Ok(());
+        };
+        let doc = &self.documents[document_id];
+
+        let mut comments = HashMap::new();
+        let mut messages = Vec::new();
+        for (comment_id, start, end, body) in open_comments(doc) {
+            let (Ok(start), Ok(end)) = (doc.get_cursor_pos(&start), doc.get_cursor_pos(&end))
+            else {
+                continue;
+            };
+            // The end cursor is anchored on the last commented character, so
+            // the range ends just past it unless that character was deleted.
+            let end = match end.current.side {
+                Side::Left => end.current.pos,
+                _ => end.current.pos + 1,
+            };
+            let start = self.position_to_frontend(document_id, start.current.pos);
+            let end = self.position_to_frontend(document_id, end);
+            // Nothing is left to comment on once all its text is deleted.
+            if start >= end {
+                continue;
+            }
+
+            if doc_info.comments.get(&comment_id) != Some(&(start, end)) {
+                messages.push(ClientMessage::CommentMoved {
+                    document_id: document_id.to_owned(),
+                    comment_id: comment_id.clone(),
+                    start,
+                    end,
+                    body,
+                });
+            }
+            comments.insert(comment_id, (start, end));
+        }
+        for comment_id in doc_info.comments.keys() {
+            if !comments.contains_key(comment_id) {
+                messages.push(ClientMessage::CommentResolved {
+                    document_id: document_id.to_owned(),
+                    comment_id: comment_id.clone(),
+                });
+            }
+        }
+        self.active_documents.get_mut(document_id).unwrap().comments = comments;
+
+        for message in messages {
+            self.channels.stdout_tx.send(message).await?;
+        }
+
+        Ok(())
+    }
+
+    fn new(builder: ClientBuilder) -> (Self, ClientHandle, ClientEvents) {
         let listener = builder.listener;
+        let local_addr = listener.local_addr().ok();
+        let config = builder.config;
 
         // Setup tasks
-        let (main_task_channel_tx, main_task_channel_rx) = tokio::sync::mpsc::channel(10);
-        let (stdout_task_channel_tx, stdout_task_channel_rx) = tokio::sync::mpsc::channel(10);
+        let (main_task_channel_tx, main_task_channel_rx) =
+            tokio::sync::mpsc::channel(config.main_capacity);
+        let (stdout_task_channel_tx, stdout_task_channel_rx) =
+            tokio::sync::mpsc::channel(config.stdout_capacity);
         let (incoming_task_to_channel_tx, incoming_task_to_channel_rx) =
-            tokio::sync::mpsc::channel(1);
-        let (outgoing_task_channel_tx, outgoing_task_channel_rx) = tokio::sync::mpsc::channel(10);
+            tokio::sync::mpsc::channel(config.incoming_capacity);
+        let (outgoing_task_channel_tx, outgoing_task_channel_rx) =
+            tokio::sync::mpsc::channel(config.outgoing_capacity);
         info!("Channels created");
 
         let channels = Channels {
@@ -170,164 +1495,910 @@ impl Client {
             stdout_tx: stdout_task_channel_tx,
         };
 
-        begin_incoming_task(main_task_channel_tx.clone(), incoming_task_to_channel_rx);
-        begin_outgoing_task(outgoing_task_channel_rx);
-        begin_stdin_task(channels.main_tx.clone());
-        begin_stdout_task(stdout_task_channel_rx);
-        begin_listening_task(listener, main_task_channel_tx.clone());
+        let stats = Arc::new(TrafficStats::default());
+        let connection = ConnectionOptions {
+            tls: config.tls.clone(),
+            format: config.wire_format,
+            tcp: config.tcp,
+            proxy: config.proxy.clone(),
+            max_frame_length: config.max_frame_length,
+            compression: config.compression,
+            signing: config.signing.clone(),
+            stats: stats.clone(),
+        };
+        // Recording is only a debugging aid, so carry on without it.
+        let recorder = config.record_path.as_deref().and_then(|path| {
+            Recorder::create(path)
+                .inspect_err(|e| error!("Not recording messages: {:#}", e))
+                .ok()
+                .map(Arc::new)
+        });
+        let mut tasks = TaskHandles {
+            incoming: begin_incoming_task(
+                main_task_channel_tx.clone(),
+                channels.stdout_tx.clone(),
+                stats.clone(),
+                recorder.clone(),
+                config.incoming_rate,
+                incoming_task_to_channel_rx,
+            ),
+            outgoing: begin_outgoing_task(
+                channels.stdout_tx.clone(),
+                channels.main_tx.clone(),
+                stats.clone(),
+                recorder,
+                config.peer_queue_capacity,
+                outgoing_task_channel_rx,
+            ),
+            stdout: None,
+            background: vec![begin_heartbeat_task(
+                config.heartbeat_interval,
+                main_task_channel_tx.clone(),
+            )],
+            timers: JoinSet::new(),
+        };
+        if let Some(interval) = config.flush_interval {
+            tasks
+                .background
+                .push(begin_flush_task(interval, main_task_channel_tx.clone()));
+        }
+        tasks.background.push(begin_listening_task(
+            Listener::Tcp(listener),
+            Transport::Stream,
+            connection.clone(),
+            main_task_channel_tx.clone(),
+        ));
+        for (listener, transport) in builder.listeners {
+            let options = match listener {
+                // Unix domain sockets are local-only, so TLS is unnecessary.
+                #[cfg(unix)]
+                Listener::Unix(..) => ConnectionOptions {
+                    tls: None,
+                    ..connection.clone()
+                },
+                Listener::Tcp(_) => connection.clone(),
+            };
+            tasks.background.push(begin_listening_task(
+                listener,
+                transport,
+                options,
+                main_task_channel_tx.clone(),
+            ));
+        }
+        if config.handle_signals {
+            tasks
+                .background
+                .push(begin_signal_task(main_task_channel_tx.clone()));
+        }
         info!("Tasks started");
 
-        Client {
-            doc: LoroDoc::new(),
+        // Preloaded documents must edit as this client, like any other.
+        let peer_id = config
+            .identity
+            .as_deref()
+            .map_or_else(rand::random, peer_id_from_identity);
+        for doc in builder.documents.values() {
+            doc.set_peer_id(peer_id)
+                .expect("peer ID is only set before editing");
+            doc.set_record_timestamp(true);
+        }
+
+        let handle = ClientHandle::new(main_task_channel_tx.clone());
+        let events = ClientEvents::new(stdout_task_channel_rx);
+        let client = Client {
+            peer_id,
+            connection,
+            documents: builder.documents,
             channels,
+            tasks,
             main_channel_rx: main_task_channel_rx,
             active_documents: HashMap::new(),
-        }
+            peers: HashMap::new(),
+            config,
+            connecting: HashMap::new(),
+            backpressure: AtomicBool::new(false),
+            sync_report_pending: HashSet::new(),
+            local_addr,
+            discovery: None,
+            presence: None,
+            subscriptions: None,
+        };
+
+        (client, handle, events)
     }
 
-    fn add_doc_change_subscription(&mut self, id: &str) -> SubID {
-        let c_id = self.doc.get_text(id).id();
+    /// Creates a new, empty document using this client's peer ID.
+    fn new_document(&self) -> Result<LoroDoc> {
+        let doc = LoroDoc::new();
+        doc.set_peer_id(self.peer_id)?;
+        // Shown in each change's history entry.
+        doc.set_record_timestamp(true);
+
+        Ok(doc)
+    }
+
+    /// Starts tracking the given document as open in the frontend.
+    fn activate_document(&mut self, id: &str) {
+        let emit_local = Arc::new(AtomicBool::new(false));
+        let sub_id = self.add_doc_change_subscription(id, emit_local.clone());
+
+        // Commit any pending changes first so they can't be undone.
+        self.documents[id].commit();
+        let undo_manager = UndoManager::new(&self.documents[id]);
+
+        self.active_documents.insert(
+            id.to_owned(),
+            DocumentInfo {
+                sub_id,
+                undo_manager,
+                emit_local,
+                flush_pending: false,
+                paused: None,
+                cursor: None,
+                mark: None,
+                cursors: HashMap::new(),
+                marks: HashMap::new(),
+                comments: HashMap::new(),
+            },
+        );
+    }
+
+    /// Subscribes to changes in the given document, forwarding them to the
+    /// frontend.
+    ///
+    /// Only imported changes are forwarded, since local ones originate from
+    /// the frontend, unless `emit_local` is set.
+    fn add_doc_change_subscription(&mut self, id: &str, emit_local: Arc<AtomicBool>) -> SubID {
+        let doc = &self.documents[id];
         let id = id.to_owned();
         let channel = self.channels.stdout_tx.clone();
         let notify_channel = self.channels.main_tx.clone();
-        self.doc.subscribe(
-            &c_id,
-            Arc::new(move |change| {
-                if !change.triggered_by.is_import() {
-                    return;
-                }
-
-                let changes = diffs_to_changes(&change.events);
-
-                // We have to spawn a new task here because this callback can't
-                // be async, and we can't use `blocking_send` because this runs
-                // inside a Tokio thread, which should never block (and will
-                // panic if it does).
-                let stdout_task_channel_tx = channel.clone();
-                let notify_channel = notify_channel.clone();
-                let id = id.clone();
-                tokio::spawn(async move {
-                    for change in changes {
-                        let message = ClientMessage::Change {
-                            document_id: id.clone(),
-                            change,
-                        };
-                        stdout_task_channel_tx.send(message).await.unwrap();
+        let index_mode = self.config.index_mode;
+        // Pending changes are already counted below, so they mustn't be
+        // counted again when committed.
+        doc.commit();
+        // Converting to UTF-16 needs the text each change was made to, so
+        // keep a copy of every container up to date.
+        let utf16_texts = (index_mode == IndexMode::Utf16)
+            .then(|| Mutex::new(text_contents(doc).into_iter().collect::<HashMap<_, _>>()));
+        let lengths = Mutex::new(
+            text_contents(doc)
+                .into_iter()
+                .map(|(name, text)| {
+                    let len = length_after(0, &Change::Append { text }, index_mode);
+                    (name, len)
+                })
+                .collect::<HashMap<_, _>>(),
+        );
+        doc.subscribe_root(Arc::new(move |change| {
+            let mut changes = diffs_to_changes(&change.events);
+            if let Some(ref texts) = utf16_texts {
+                let mut texts = texts.lock().unwrap();
+                changes = changes
+                    .into_iter()
+                    .map(|(container, change)| {
+                        let text = texts.entry(container.clone()).or_default();
+                        (container, change_to_utf16(text, change))
+                    })
+                    .collect();
+            }
+            // Each container's changes are contiguous, so its length once
+            // they're all applied is the length after each message about it.
+            let new_lengths: HashMap<_, _> = {
+                let mut lengths = lengths.lock().unwrap();
+                for (container, change) in &changes {
+                    let len = lengths.entry(container.clone()).or_default();
+                    *len = length_after(*len, change, index_mode);
+                }
+                changes
+                    .iter()
+                    .map(|(container, _)| (container.clone(), lengths[container]))
+                    .collect()
+            };
+
+            if !change.triggered_by.is_import() && !emit_local.load(Ordering::SeqCst) {
+                return;
+            }
+
+            // Imports are tagged with the ID of the peer they came from.
+            let origin = if change.triggered_by.is_import() {
+                change
+                    .origin
+                    .parse()
+                    .ok()
+                    .map(|peer_id| ChangeOrigin::Remote { peer_id })
+            } else {
+                Some(ChangeOrigin::Local)
+            };
+            let mut messages = changes_to_messages(&id, origin, changes);
+            for message in &mut messages {
+                if let ClientMessage::Change {
+                    container,
+                    new_length,
+                    ..
+                }
+                | ClientMessage::ChangeBatch {
+                    container,
+                    new_length,
+                    ..
+                } = message
+                {
+                    *new_length = new_lengths.get(container).copied();
+                }
+            }
+            messages.extend(
+                diffs_to_metadata(&change.events)
+                    .into_iter()
+                    .map(|(key, value)| ClientMessage::MetadataChanged {
+                        document_id: id.clone(),
+                        key,
+                        value,
+                    }),
+            );
+
+            // We have to spawn a new task here because this callback can't
+            // be async, and we can't use `blocking_send` because this runs
+            // inside a Tokio thread, which should never block (and will
+            // panic if it does).
+            let stdout_task_channel_tx = channel.clone();
+            let notify_channel = notify_channel.clone();
+            let id = id.clone();
+            tokio::spawn(async move {
+                for message in messages {
+                    if stdout_task_channel_tx.send(message).await.is_err() {
+                        error!("Stdout task exited; dropping changes for {}", id);
+                        return;
                     }
+                }
 
-                    notify_channel
-                        .send(MainTaskMessage::DocumentChanged(id))
-                        .await
-                        .unwrap();
-                });
-            }),
-        )
+                if notify_channel
+                    .send(MainTaskMessage::DocumentChanged(id))
+                    .await
+                    .is_err()
+                {
+                    error!("Main task exited; not updating cursors");
+                }
+            });
+        }))
     }
 
-    async fn broadcast_cursor_update(&self, document_id: &str) {
-        let doc_info = self.active_documents.get(document_id).unwrap();
-        let peer_id = self.doc.peer_id();
+    /// Returns messages describing this client's cursor and mark in the given
+    /// document.
+    fn cursor_update_messages(&self, document_id: &str) -> Vec<BackendMessage> {
+        let doc_info = &self.active_documents[document_id];
+        let peer_id = self.peer_id;
+        let mut messages = Vec::new();
 
         if let Some(ref cursor) = doc_info.cursor {
-            self.channels
-                .outgoing_tx
-                .send(OutgoingMessage::BackendMessage(
-                    BackendMessage::CursorUpdate {
-                        document_id: document_id.to_owned(),
-                        peer_id,
-                        cursor: cursor.clone(),
-                        mark: false,
-                    },
-                ))
-                .await
-                .unwrap();
+            messages.push(BackendMessage::CursorUpdate {
+                document_id: document_id.to_owned(),
+                peer_id,
+                cursor: cursor.clone(),
+                mark: false,
+            });
         }
 
-        self.channels
-            .outgoing_tx
-            .send(OutgoingMessage::BackendMessage(
-                if let Some(ref mark) = doc_info.mark {
-                    BackendMessage::CursorUpdate {
-                        document_id: document_id.to_owned(),
-                        peer_id,
-                        cursor: mark.clone(),
-                        mark: true,
-                    }
-                } else {
-                    BackendMessage::UnsetMark {
-                        document_id: document_id.to_owned(),
-                        peer_id,
-                    }
-                },
-            ))
-            .await
-            .unwrap();
+        messages.push(if let Some(ref mark) = doc_info.mark {
+            BackendMessage::CursorUpdate {
+                document_id: document_id.to_owned(),
+                peer_id,
+                cursor: mark.clone(),
+                mark: true,
+            }
+        } else {
+            BackendMessage::UnsetMark {
+                document_id: document_id.to_owned(),
+                peer_id,
+            }
+        });
+
+        messages
     }
 
-    async fn broadcast_all_data(&mut self) {
-        self.channels
-            .outgoing_tx
-            .send(OutgoingMessage::BackendMessage(
-                BackendMessage::DocumentSync {
-                    data: self.doc.export_from(&Default::default()),
-                },
-            ))
-            .await
-            .unwrap();
+    async fn broadcast_cursor_update(&self, document_id: &str) -> Result<()> {
+        let doc_info = &self.active_documents[document_id];
+        if doc_info.flush_pending || doc_info.paused.is_some() {
+            // Our cursors may refer to text peers don't have yet, so wait
+            // until it's sent.
+            return Ok(());
+        }
+
+        for message in self.cursor_update_messages(document_id) {
+            self.broadcast(message).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Broadcasts all operations in the given document that have not yet been
+    /// sent to peers, waiting for the next flush or the debounce window first
+    /// if there is one.
+    async fn broadcast_document(&mut self, document_id: &str) -> Result<()> {
+        if self.config.flush_interval.is_some() {
+            self.active_documents
+                .get_mut(document_id)
+                .unwrap()
+                .flush_pending = true;
+            return Ok(());
+        }
+        let Some(window) = self.config.debounce else {
+            return self.flush_document(document_id).await;
+        };
+
+        let doc_info = self.active_documents.get_mut(document_id).unwrap();
+        if !doc_info.flush_pending {
+            doc_info.flush_pending = true;
+            begin_timer(
+                &mut self.tasks.timers,
+                MainTaskMessage::FlushDocument(document_id.to_owned()),
+                window,
+                self.channels.main_tx.clone(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Sends peers the changes to every document waiting to be broadcast, in
+    /// a single export each.
+    async fn flush_pending_documents(&mut self) -> Result<()> {
+        let pending: Vec<_> = self
+            .active_documents
+            .iter()
+            .filter(|(_, doc_info)| doc_info.flush_pending)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in pending {
+            self.flush_document(&id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Immediately sends peers all operations in the given document they
+    /// don't have yet.
+    async fn flush_document(&mut self, document_id: &str) -> Result<()> {
+        let Some(doc_info) = self.active_documents.get_mut(document_id) else {
+            // Document was closed while a flush was pending.
+            return Ok(());
+        };
+        let was_pending = std::mem::take(&mut doc_info.flush_pending);
+
+        self.documents[document_id].commit();
+        if doc_info.paused.is_some() {
+            // Peers are sent what they're missing once sync is resumed.
+            return Ok(());
+        }
+        self.send_deltas(document_id).await?;
+        if was_pending {
+            self.broadcast_cursor_update(document_id).await?;
+        }
+
+        Ok(())
+    }
 
-        for id in self.active_documents.keys() {
-            self.broadcast_cursor_update(id).await;
+    /// Sends each peer the operations in the given document it doesn't have,
+    /// going by what it has sent us, acknowledged and already been sent.
+    ///
+    /// Imported operations are sent this way too, so each peer only receives
+    /// the ones it didn't get from someone else.
+    async fn send_deltas(&mut self, document_id: &str) -> Result<()> {
+        // Peers reject documents from read-only clients.
+        if self.config.read_only {
+            return Ok(());
         }
+        let doc = &self.documents[document_id];
+
+        let mut messages = Vec::new();
+        for (address, peer) in self.peers.iter_mut() {
+            let Some(data) = peer.take_delta(document_id, doc, self.config.snapshot_threshold)
+            else {
+                continue;
+            };
+            debug!(
+                "Exported {} bytes of updates for document {} to peer at {}",
+                data.len(),
+                document_id,
+                address
+            );
+            messages.push((address.clone(), peer.sync_message(document_id, data)));
+        }
+
+        for (address, message) in messages {
+            self.send_outgoing(OutgoingMessage::DirectMessage { address, message })
+                .await?;
+        }
+
+        Ok(())
     }
 
-    async fn accept_new_connection(&mut self, (socket, addr): (TcpStream, std::net::SocketAddr)) {
-        let (read, write) = socket.into_split();
+    /// Starts connecting to the peer at `address` in the background, so
+    /// several peers can be connected to at once.
+    async fn add_peer(&mut self, address: String) -> Result<()> {
+        if self.peers.contains_key(&address) {
+            bail!("Already connected to peer: {address}");
+        }
+        self.cancel_connect(&address);
+        if self.at_peer_limit() {
+            bail!("Cannot connect to {address}: already connected to the maximum number of peers");
+        }
 
-        let read_framed = tokio_serde::SymmetricallyFramed::new(
-            FramedRead::new(read, LengthDelimitedCodec::new()),
-            SymmetricalJson::<BackendMessage>::default(),
+        info!("Connecting to peer at {}", address);
+        self.report_peer_state(&address, PeerState::Connecting)
+            .await?;
+        // Connect in the background, since the peer may need a few attempts
+        // if it's still starting up.
+        let handle = begin_connect_task(
+            address.clone(),
+            self.connection.clone(),
+            self.config.connect_attempts,
+            self.config.connect_retry_delay,
+            self.channels.main_tx.clone(),
+            self.channels.stdout_tx.clone(),
         );
-        let write_framed = tokio_serde::SymmetricallyFramed::new(
-            FramedWrite::new(write, LengthDelimitedCodec::new()),
-            SymmetricalJson::<BackendMessage>::default(),
+        self.connecting.insert(address, handle);
+
+        Ok(())
+    }
+
+    /// Exchanges the full state of a document with the peer at `address`.
+    async fn request_full_sync(&mut self, address: &str, document_id: &str) -> Result<()> {
+        info!(
+            "Requesting full sync of document {} with peer at {}",
+            document_id, address
         );
+        self.send_outgoing(OutgoingMessage::DirectMessage {
+            address: address.to_owned(),
+            message: BackendMessage::SyncRequest {
+                document_id: document_id.to_owned(),
+            },
+        })
+        .await?;
+        // Peers reject documents from read-only clients.
+        if !self.config.read_only {
+            self.send_full_document(address, document_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends peers a hash of the given document's contents, so any that have
+    /// the same version but different contents can tell.
+    async fn send_state_hash(&self, document_id: &str) -> Result<()> {
+        let doc = &self.documents[document_id];
+        // A past version's contents could only match by chance.
+        if doc.is_detached() {
+            return Ok(());
+        }
+
+        let message = BackendMessage::StateHash {
+            document_id: document_id.to_owned(),
+            algorithm: CONTENT_HASH_ALGORITHM.to_owned(),
+            hash: content_hash(doc),
+            frontiers: ByteBuf::from(doc.oplog_frontiers().encode()),
+        };
+        self.broadcast(message).await
+    }
+
+    /// Sends the peer at `address` the whole of the given document, regardless
+    /// of what it's known to have.
+    async fn send_full_document(&mut self, address: &str, document_id: &str) -> Result<()> {
+        let doc = &self.documents[document_id];
+        let Some(peer) = self.peers.get_mut(address) else {
+            bail!("Not connected to peer: {address}");
+        };
+        let message =
+            peer.sync_message(document_id, export_all(doc, self.config.snapshot_threshold));
+
+        self.send_outgoing(OutgoingMessage::DirectMessage {
+            address: address.to_owned(),
+            message,
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Tells the peer at `address` which version of each document we have, so
+    /// it can send only the operations we're missing.
+    async fn send_versions(&self, address: &str) -> Result<()> {
+        let versions = self
+            .documents
+            .iter()
+            .map(|(id, doc)| (id.clone(), ByteBuf::from(doc.oplog_vv().encode())))
+            .collect();
+
+        self.send_outgoing(OutgoingMessage::DirectMessage {
+            address: address.to_owned(),
+            message: BackendMessage::Versions { versions },
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sends the peer at `address` the operations it's missing from every
+    /// document it's subscribed to, given the `versions` it has, along with our
+    /// cursors and presence.
+    ///
+    /// Both sides do this on connecting, so peers that both edited while
+    /// disconnected converge without resending operations the other has.
+    ///
+    /// Until then the peer is sent no updates, and afterwards each one starts
+    /// exactly where the last left off, so a peer joining while edits are
+    /// being made neither misses nor repeats any. Everything sent to a peer
+    /// goes through the outgoing task in order, so they also arrive in order.
+    async fn send_initial_sync(
+        &mut self,
+        address: &str,
+        versions: HashMap<String, ByteBuf>,
+    ) -> Result<()> {
+        let mut messages = Vec::new();
+        let mut known = HashMap::new();
+        for (id, data) in versions {
+            let version = VersionVector::decode(&data)
+                .wrap_err_with(|| format!("Peer at {address} sent an invalid version for {id}"))?;
+            known.insert(id, version);
+        }
+        let peer = self.peers.get_mut(address).unwrap();
+        peer.versions = Some(known);
 
+        // Peers reject documents from read-only clients.
+        if !self.config.read_only {
+            for (id, doc) in self.documents.iter() {
+                // Sending paused documents would include local changes that
+                // haven't been published yet, so they're sent on resuming.
+                let paused = self
+                    .active_documents
+                    .get(id)
+                    .is_some_and(|doc_info| doc_info.paused.is_some());
+                if paused {
+                    continue;
+                }
+                if let Some(data) = peer.take_delta(id, doc, self.config.snapshot_threshold) {
+                    messages.push(peer.sync_message(id, data));
+                }
+            }
+        }
+        for (id, doc_info) in self.active_documents.iter() {
+            if doc_info.paused.is_none() {
+                messages.extend(self.cursor_update_messages(id));
+            }
+        }
+        if let Some(ref presence) = self.presence {
+            messages.push(BackendMessage::Presence(presence.clone()));
+        }
+
+        for message in messages {
+            self.send_outgoing(OutgoingMessage::DirectMessage {
+                address: address.to_owned(),
+                message,
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Registers a newly-established connection with the network tasks and
+    /// brings the peer up to date.
+    async fn register_connection(
+        &mut self,
+        address: &str,
+        (read, write): (ReadSocket, WriteSocket),
+        outgoing: bool,
+    ) -> Result<()> {
         self.channels
             .incoming_to_tx
-            .send(read_framed)
-            .await
-            .unwrap();
-        self.channels
-            .outgoing_tx
-            .send(OutgoingMessage::NewSocket(write_framed))
-            .await
-            .unwrap();
+            .send(IncomingMessage::NewSocket(address.to_owned(), read))
+            .await?;
+        self.send_outgoing(OutgoingMessage::NewSocket(address.to_owned(), write))
+            .await?;
+        let psk_nonce = self.config.psk.is_some().then(rand::random::<[u8; 32]>);
+        self.peers.insert(
+            address.to_owned(),
+            PeerInfo {
+                peer_id: None,
+                missed_pongs: 0,
+                pings_sent: VecDeque::new(),
+                last_seen: Instant::now(),
+                outgoing,
+                read_only: false,
+                presence: None,
+                subscriptions: None,
+                versions: None,
+                sent_syncs: HashMap::new(),
+                received_syncs: HashMap::new(),
+                psk_nonce,
+            },
+        );
+
+        // Versions are exchanged once the peer's hello is received.
+        self.send_outgoing(OutgoingMessage::DirectMessage {
+            address: address.to_owned(),
+            message: BackendMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                peer_id: self.peer_id,
+                read_only: self.config.read_only,
+                wire_format: self.connection.format,
+                compression: self.connection.compression,
+                subscriptions: self.subscriptions.as_ref().map(|subscriptions| {
+                    let mut ids: Vec<_> = subscriptions.iter().cloned().collect();
+                    ids.sort();
+                    ids
+                }),
+                public_key: self
+                    .connection
+                    .signing
+                    .as_ref()
+                    .map(|signing| ByteBuf::from(signing.key.verifying_key().to_bytes().to_vec())),
+                psk_nonce: psk_nonce.map(|nonce| ByteBuf::from(nonce.to_vec())),
+            },
+        })
+        .await?;
+        self.report_peer_state(address, PeerState::Handshaking)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Whether connecting to another peer would exceed the limit, counting
+    /// connections still being made.
+    fn at_peer_limit(&self) -> bool {
+        let connecting = self
+            .connecting
+            .values()
+            .filter(|handle| !handle.is_finished())
+            .count();
+
+        self.config
+            .max_peers
+            .is_some_and(|max| self.peers.len() + connecting >= max)
+    }
+
+    async fn accept_new_connection(
+        &mut self,
+        sockets: (ReadSocket, WriteSocket),
+        address: &str,
+    ) -> Result<()> {
+        if self.at_peer_limit() {
+            warn!(
+                "Refusing connection from peer at {}: too many peers",
+                address
+            );
+            let (_, mut write) = sockets;
+            if let Err(e) = write.close().await {
+                info!("Failed to close connection to peer at {}: {:#}", address, e);
+            }
+            return Ok(());
+        }
+        self.register_connection(address, sockets, false).await?;
+
+        info!("Accepted connection from peer at {}", address);
+
+        Ok(())
+    }
+
+    /// Disconnects peers that have stopped answering pings or been idle for
+    /// too long, then pings the remaining ones.
+    async fn handle_heartbeat(&mut self) -> Result<()> {
+        if let Some(timeout) = self.config.idle_timeout {
+            let idle_peers: Vec<_> = self
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.last_seen.elapsed() >= timeout)
+                .map(|(address, _)| address.clone())
+                .collect();
+            for address in idle_peers {
+                info!("Peer at {} has been idle too long", address);
+                self.handle_peer_disconnected(&address).await?;
+            }
+        }
 
-        info!("Accepted connection from peer at {}", addr);
-        self.broadcast_all_data().await;
+        let dead_peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.missed_pongs >= self.config.max_missed_pongs)
+            .map(|(address, peer)| (address.clone(), peer.outgoing))
+            .collect();
+        for (address, outgoing) in dead_peers {
+            info!("Peer at {} stopped responding, disconnecting", address);
+
+            self.remove_connection(&address).await?;
+            self.channels
+                .stdout_tx
+                .send(ClientMessage::PeerRemoved {
+                    address: address.clone(),
+                })
+                .await?;
+            if outgoing {
+                self.begin_reconnect(address);
+            }
+        }
+
+        // Peers still handshaking would take a ping as a protocol violation.
+        let now = Instant::now();
+        let mut addresses = Vec::new();
+        for (address, peer) in self.peers.iter_mut() {
+            if !peer.handshake_complete() {
+                continue;
+            }
+            peer.missed_pongs += 1;
+            peer.pings_sent.push_back(now);
+            addresses.push(address.clone());
+        }
+        for address in addresses {
+            self.send_outgoing(OutgoingMessage::DirectMessage {
+                address,
+                message: BackendMessage::Ping,
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_peer_disconnected(&mut self, address: &str) -> Result<()> {
+        // The connection may already have been removed locally.
+        let Some(peer) = self.peers.get(address) else {
+            return Ok(());
+        };
+        let outgoing = peer.outgoing;
+
+        info!("Peer at {} disconnected", address);
+        self.remove_connection(address).await?;
         self.channels
             .stdout_tx
-            .send(ClientMessage::AddPeerResponse {
-                address: addr.to_string(),
+            .send(ClientMessage::PeerDisconnected {
+                address: address.to_owned(),
             })
-            .await
-            .unwrap();
+            .await?;
+        if outgoing {
+            self.begin_reconnect(address.to_owned());
+        }
+
+        Ok(())
     }
 
-    // TODO Refactor
-    async fn update_frontend_cursor(&self, document_id: &str, peer_id: Option<PeerID>, mark: bool) {
-        let doc_info = self.active_documents.get(document_id).unwrap();
+    /// Starts reconnecting to the dropped peer at `address`, if a reconnect
+    /// policy is set. Incoming connections are the other side's job to
+    /// restore, so this should only be called for outgoing ones.
+    fn begin_reconnect(&mut self, address: String) {
+        let Some(policy) = self.config.reconnect else {
+            return;
+        };
 
-        if mark {
-            let mark = if let Some(peer_id) = peer_id {
-                doc_info.marks.get(&peer_id)
-            } else {
-                doc_info.mark.as_ref()
-            };
+        let handle = begin_reconnect_task(
+            address.clone(),
+            self.connection.clone(),
+            policy,
+            self.channels.main_tx.clone(),
+            self.channels.stdout_tx.clone(),
+        );
+        self.connecting.insert(address, handle);
+    }
 
-            if let Some(mark) = mark {
-                let pos = self.doc.get_cursor_pos(mark).unwrap().current.pos;
-                self.channels
+    async fn handle_peer_connected(
+        &mut self,
+        address: &str,
+        sockets: (ReadSocket, WriteSocket),
+    ) -> Result<()> {
+        self.connecting.remove(address);
+        self.register_connection(address, sockets, true).await?;
+
+        info!("Connected to peer at {}", address);
+
+        Ok(())
+    }
+
+    /// Registers a connection made outside the client, framing it the same
+    /// way as a TCP connection. TLS is never used, since the embedder is
+    /// responsible for the stream.
+    async fn attach_stream(
+        &mut self,
+        address: &str,
+        connection: BoxedConnection,
+        outgoing: bool,
+    ) -> Result<()> {
+        if self.peers.contains_key(address) {
+            bail!("Already connected to peer: {address}");
+        }
+        let options = ConnectionOptions {
+            tls: None,
+            ..self.connection.clone()
+        };
+        let sockets = frame_stream(connection, &options);
+
+        if outgoing {
+            self.handle_peer_connected(address, sockets).await
+        } else {
+            self.accept_new_connection(sockets, address).await
+        }
+    }
+
+    /// Stops trying to connect or reconnect to `address`, returning whether an
+    /// attempt was in progress.
+    fn cancel_connect(&mut self, address: &str) -> bool {
+        let Some(handle) = self.connecting.remove(address) else {
+            return false;
+        };
+        let in_progress = !handle.is_finished();
+        handle.abort();
+
+        in_progress
+    }
+
+    /// Closes the connection to the peer at `address`, stopping its tasks.
+    async fn remove_connection(&mut self, address: &str) -> Result<()> {
+        self.channels
+            .incoming_to_tx
+            .send(IncomingMessage::RemoveSocket(address.to_owned()))
+            .await?;
+        self.send_outgoing(OutgoingMessage::RemoveSocket(address.to_owned()))
+            .await?;
+        let Some(peer) = self.peers.remove(address) else {
+            return Ok(());
+        };
+        self.report_peer_state(address, PeerState::Disconnected)
+            .await?;
+
+        if let Some(peer_id) = peer.peer_id {
+            self.remove_peer_cursors(peer_id).await?;
+            if peer.presence.is_some() {
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::PresenceRemoved { peer_id })
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the cursors of the given peer in all documents, informing the
+    /// frontend.
+    async fn remove_peer_cursors(&mut self, peer_id: PeerID) -> Result<()> {
+        for (id, doc_info) in self.active_documents.iter_mut() {
+            let had_cursor = doc_info.cursors.remove(&peer_id).is_some();
+            let had_mark = doc_info.marks.remove(&peer_id).is_some();
+
+            if had_cursor || had_mark {
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::CursorRemoved {
+                        document_id: id.clone(),
+                        peer_id,
+                    })
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // TODO Refactor
+    async fn update_frontend_cursor(
+        &self,
+        document_id: &str,
+        peer_id: Option<PeerID>,
+        mark: bool,
+    ) -> Result<()> {
+        let doc_info = &self.active_documents[document_id];
+
+        if mark {
+            let mark = if let Some(peer_id) = peer_id {
+                doc_info.marks.get(&peer_id)
+            } else {
+                doc_info.mark.as_ref()
+            };
+
+            if let Some(mark) = mark {
+                let pos = self.documents[document_id]
+                    .get_cursor_pos(mark)?
+                    .current
+                    .pos;
+                let pos = self.position_to_frontend(document_id, pos);
+                self.channels
                     .stdout_tx
                     .send(ClientMessage::SetCursor {
                         document_id: document_id.to_owned(),
@@ -335,184 +2406,1226 @@ impl Client {
                         location: pos,
                         mark: true,
                     })
-                    .await
-                    .unwrap();
-            } else {
+                    .await?;
+            } else {
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::UnsetMark {
+                        document_id: document_id.to_owned(),
+                        peer_id,
+                    })
+                    .await?;
+            }
+        } else {
+            let Some(cursor) = peer_id
+                .and_then(|id| {
+                    if mark {
+                        doc_info.marks.get(&id)
+                    } else {
+                        doc_info.cursors.get(&id)
+                    }
+                })
+                .or(doc_info.cursor.as_ref())
+            else {
+                return Ok(());
+            };
+
+            let pos = self.documents[document_id]
+                .get_cursor_pos(cursor)?
+                .current
+                .pos;
+            let pos = self.position_to_frontend(document_id, pos);
+            self.channels
+                .stdout_tx
+                .send(ClientMessage::SetCursor {
+                    document_id: document_id.to_owned(),
+                    peer_id,
+                    location: pos,
+                    mark,
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates and shares a new document with the given ID, holding
+    /// `initial_content`, if any, in `container`.
+    async fn create_document(
+        &mut self,
+        id: &str,
+        container: &str,
+        initial_content: Option<&str>,
+    ) -> Result<()> {
+        // Replacing the document would lose its history, along with any
+        // changes not yet sent to peers.
+        if self.documents.contains_key(id) {
+            bail!("Document already exists: {id}");
+        }
+        let doc = self.new_document()?;
+        if let Some(content) = initial_content {
+            check_document_size(0, content.len(), self.config.max_document_size)?;
+            doc.get_text(container).update(content);
+        }
+        self.documents.insert(id.to_owned(), doc);
+
+        self.activate_document(id);
+
+        info!("Created new document with id {}", id);
+
+        self.broadcast_document(id).await?;
+        self.broadcast_cursor_update(id).await?;
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::DocumentCreated {
+                document_id: id.to_owned(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Opens `doc`, restored from a snapshot, as a new document with the given
+    /// ID, sharing it with peers and sending its contents to the frontend.
+    async fn open_imported_document(&mut self, id: &str, doc: LoroDoc) -> Result<()> {
+        check_document_size(document_size(&doc), 0, self.config.max_document_size)?;
+        let changes = self.changes_to_frontend(doc_to_changes(&doc)?);
+        self.documents.insert(id.to_owned(), doc);
+        self.activate_document(id);
+
+        self.broadcast_document(id).await?;
+        self.broadcast_cursor_update(id).await?;
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::DocumentCreated {
+                document_id: id.to_owned(),
+            })
+            .await?;
+        for message in changes_to_messages(id, Some(ChangeOrigin::Local), changes) {
+            self.channels.stdout_tx.send(message).await?;
+        }
+        self.update_comments(id).await?;
+
+        Ok(())
+    }
+
+    async fn save_document(&self, document_id: String, path: String) -> Result<()> {
+        let doc = self
+            .documents
+            .get(&document_id)
+            .ok_or_else(|| eyre!("No such document: {document_id}"))?;
+
+        tokio::fs::write(&path, doc.export_snapshot())
+            .await
+            .wrap_err_with(|| format!("Failed to write snapshot to {path}"))?;
+
+        info!("Saved document {} to {}", document_id, path);
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::DocumentSaved { document_id, path })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes a document from memory, after sending peers any pending
+    /// changes and saving it to `path` if given.
+    async fn close_document(
+        &mut self,
+        document_id: &str,
+        path: Option<String>,
+        force: bool,
+    ) -> Result<()> {
+        if !self.documents.contains_key(document_id) {
+            bail!("No such document: {document_id}");
+        }
+
+        if let Some(doc_info) = self.active_documents.get(document_id) {
+            // Debounced changes would otherwise never be sent.
+            if doc_info.flush_pending && doc_info.paused.is_none() {
+                self.flush_document(document_id).await?;
+            }
+        }
+        let (unsynced, _) = self.unsynced_changes(document_id, &self.documents[document_id]);
+        if unsynced && !force {
+            bail!("Document {document_id} has changes no peer has acknowledged; sync it first");
+        }
+        if let Some(path) = path {
+            self.save_document(document_id.to_owned(), path).await?;
+        }
+
+        let doc = self.documents.remove(document_id).unwrap();
+        if let Some(doc_info) = self.active_documents.remove(document_id) {
+            doc.unsubscribe(doc_info.sub_id);
+        }
+        self.sync_report_pending.remove(document_id);
+        // A document later opened with the same ID may have none of the same
+        // operations.
+        for peer in self.peers.values_mut() {
+            if let Some(versions) = peer.versions.as_mut() {
+                versions.remove(document_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a document has local changes no peer has acknowledged, and how
+    /// many of those operations have been committed.
+    fn unsynced_changes(&self, document_id: &str, doc: &LoroDoc) -> (bool, usize) {
+        let own = doc.peer_id();
+        let made = doc.oplog_vv().get(&own).map_or(0, |&end| end as usize);
+        // Any peer that has an edit can pass it on, so only edits no peer has
+        // acknowledged count.
+        let acknowledged = self
+            .peers
+            .values()
+            .filter_map(|peer| peer.versions.as_ref()?.get(document_id))
+            .filter_map(|version| version.get(&own))
+            .map(|&end| end as usize)
+            .max()
+            .unwrap_or(0);
+        let pending_ops = made.saturating_sub(acknowledged);
+        // Edits waiting for the next flush haven't been committed, so aren't
+        // counted in the oplog yet.
+        let flush_pending = self
+            .active_documents
+            .get(document_id)
+            .is_some_and(|doc_info| doc_info.flush_pending);
+
+        (pending_ops > 0 || flush_pending, pending_ops)
+    }
+
+    /// Moves everything kept about a document from `old_id` to `new_id`, then
+    /// tells the frontend.
+    async fn rename_document(&mut self, old_id: &str, new_id: &str) -> Result<()> {
+        if !self.documents.contains_key(old_id) {
+            bail!("No such document: {old_id}");
+        }
+        if self.documents.contains_key(new_id) {
+            bail!("Document already exists: {new_id}");
+        }
+        // Pending timers refer to the document by its old ID.
+        if self
+            .active_documents
+            .get(old_id)
+            .is_some_and(|doc_info| doc_info.flush_pending)
+        {
+            self.flush_document(old_id).await?;
+        }
+        self.sync_report_pending.remove(old_id);
+
+        let doc = self.documents.remove(old_id).unwrap();
+        self.documents.insert(new_id.to_owned(), doc);
+        if let Some(mut doc_info) = self.active_documents.remove(old_id) {
+            // The subscription tags changes with the document's ID.
+            self.documents[new_id].unsubscribe(doc_info.sub_id);
+            doc_info.sub_id = self.add_doc_change_subscription(new_id, doc_info.emit_local.clone());
+            self.active_documents.insert(new_id.to_owned(), doc_info);
+        }
+        for peer in self.peers.values_mut() {
+            if let Some(seq) = peer.sent_syncs.remove(old_id) {
+                peer.sent_syncs.insert(new_id.to_owned(), seq);
+            }
+            if let Some(received) = peer.received_syncs.remove(old_id) {
+                peer.received_syncs.insert(new_id.to_owned(), received);
+            }
+            if let Some(versions) = peer.versions.as_mut() {
+                if let Some(version) = versions.remove(old_id) {
+                    versions.insert(new_id.to_owned(), version);
+                }
+            }
+            if let Some(subscriptions) = peer.subscriptions.as_mut() {
+                if subscriptions.remove(old_id) {
+                    subscriptions.insert(new_id.to_owned());
+                }
+            }
+        }
+        if let Some(subscriptions) = self.subscriptions.as_mut() {
+            if subscriptions.remove(old_id) {
+                subscriptions.insert(new_id.to_owned());
+            }
+        }
+
+        info!("Renamed document {} to {}", old_id, new_id);
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::DocumentRenamed {
+                old_id: old_id.to_owned(),
+                new_id: new_id.to_owned(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Publishes the local changes made to a document while its sync was
+    /// paused, after applying any updates held from peers.
+    async fn resume_sync(&mut self, document_id: &str) -> Result<()> {
+        let doc_info = self
+            .active_documents
+            .get_mut(document_id)
+            .ok_or_else(|| eyre!("Document is not active: {document_id}"))?;
+        let Some(paused) = doc_info.paused.take() else {
+            bail!("Sync of document {document_id} is not paused");
+        };
+        let doc = &self.documents[document_id];
+
+        // One bad update shouldn't keep the rest from being applied.
+        for (peer_id, data) in paused.held_updates {
+            if let Err(e) = doc.import_with(&data, &peer_id.to_string()) {
+                let source = format!("peer {peer_id}");
+                let error = import_error(e, document_id, &source, data.len());
+                self.report_error(error, "resuming sync").await;
+            }
+        }
+
+        // Each peer is sent what it's missing, including peers that connected
+        // in the meantime.
+        self.flush_document(document_id).await?;
+        self.broadcast_cursor_update(document_id).await
+    }
+
+    /// Tells the frontend the version of a document that updates from peers
+    /// have been applied to.
+    async fn report_sync(&mut self, document_id: &str) -> Result<()> {
+        self.sync_report_pending.remove(document_id);
+        let Some(doc) = self.documents.get(document_id) else {
+            return Ok(());
+        };
+
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::SyncApplied {
+                document_id: document_id.to_owned(),
+                frontiers: format_frontiers(doc),
+            })
+            .await?;
+        let paused = self
+            .active_documents
+            .get(document_id)
+            .is_some_and(|doc_info| doc_info.paused.is_some());
+        if self.config.verify_after_sync && !paused {
+            self.send_state_hash(document_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the frontend the full text of a document after checking out a
+    /// version.
+    async fn send_checkout_response(&self, document_id: String) -> Result<()> {
+        let doc = &self.documents[&document_id];
+
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::CheckoutResponse {
+                content: doc.get_text(TEXT_CONTAINER).to_string(),
+                detached: doc.is_detached(),
+                document_id,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Undoes (or redoes, if `redo`) the last local change in the given
+    /// document, informing both the frontend and peers.
+    async fn undo_redo(&mut self, document_id: &str, redo: bool) -> Result<()> {
+        let doc = &self.documents[document_id];
+        let doc_info = self
+            .active_documents
+            .get_mut(document_id)
+            .ok_or_else(|| eyre!("Document is not active: {document_id}"))?;
+        ensure_attached(doc, document_id)?;
+        if self.config.read_only {
+            bail!("Cannot edit documents in read-only mode");
+        }
+
+        // Events are emitted synchronously when the change is committed, so
+        // this only affects the changes made here.
+        doc_info.emit_local.store(true, Ordering::SeqCst);
+        let result = if redo {
+            doc_info.undo_manager.redo(doc)
+        } else {
+            doc_info.undo_manager.undo(doc)
+        };
+        doc.commit();
+        doc_info.emit_local.store(false, Ordering::SeqCst);
+
+        if !result? {
+            info!("Nothing to undo/redo in document {}", document_id);
+            return Ok(());
+        }
+
+        self.broadcast_document(document_id).await
+    }
+
+    /// Converts changes from the frontend to `text` so their indices count
+    /// Unicode code points.
+    fn changes_from_frontend(&self, text: &str, changes: Vec<Change>) -> Result<Vec<Change>> {
+        if self.config.index_mode == IndexMode::CodePoint {
+            return Ok(changes);
+        }
+
+        let mut text = text.to_owned();
+        changes
+            .into_iter()
+            .enumerate()
+            .map(|(i, change)| {
+                change_from_utf16(&mut text, change).wrap_err_with(|| format!("Invalid change {i}"))
+            })
+            .collect()
+    }
+
+    /// Converts changes building a document from nothing, as returned by
+    /// `doc_to_changes`, to the frontend's index mode.
+    fn changes_to_frontend(&self, changes: Vec<(String, Change)>) -> Vec<(String, Change)> {
+        if self.config.index_mode == IndexMode::CodePoint {
+            return changes;
+        }
+
+        let mut texts: HashMap<String, String> = HashMap::new();
+        changes
+            .into_iter()
+            .map(|(container, change)| {
+                let text = texts.entry(container.clone()).or_default();
+                (container, change_to_utf16(text, change))
+            })
+            .collect()
+    }
+
+    /// Converts a position in the main text of a document from Unicode code
+    /// points to the frontend's index mode.
+    fn position_to_frontend(&self, document_id: &str, pos: usize) -> usize {
+        match self.config.index_mode {
+            IndexMode::CodePoint => pos,
+            IndexMode::Utf16 => utf16_index(
+                &self.documents[document_id]
+                    .get_text(TEXT_CONTAINER)
+                    .to_string(),
+                pos,
+            ),
+        }
+    }
+
+    /// Applies `changes` to a document in order and broadcasts them to peers
+    /// as a single commit.
+    ///
+    /// Changes are checked against the document's length and size limit
+    /// first, so a batch that fails those checks applies nothing.
+    ///
+    /// If given, the commit is recorded as made at `timestamp`, and
+    /// `message` is kept in the document to be shown in its history.
+    /// Returns the document with the given ID for a local edit, failing if
+    /// it isn't open, is checked out at an earlier version, or this client is
+    /// read-only.
+    fn editable_document(&self, document_id: &str) -> Result<&LoroDoc> {
+        let Some(doc) = self.documents.get(document_id) else {
+            bail!("No such document: {document_id}");
+        };
+        if !self.active_documents.contains_key(document_id) {
+            bail!("Document is not active: {document_id}");
+        }
+        ensure_attached(doc, document_id)?;
+        if self.config.read_only {
+            bail!("Cannot edit documents in read-only mode");
+        }
+
+        Ok(doc)
+    }
+
+    async fn apply_changes(
+        &mut self,
+        document_id: &str,
+        container: &str,
+        changes: Vec<Change>,
+        timestamp: Option<i64>,
+        message: Option<String>,
+    ) -> Result<()> {
+        let doc = self.editable_document(document_id)?;
+        let text = doc.get_text(check_container_name(container)?);
+        let changes = self.changes_from_frontend(&text.to_string(), changes)?;
+        validate_changes(text.len_unicode(), &changes)?;
+        let added = changes
+            .iter()
+            .map(|change| match change {
+                Change::Insert { text, .. } | Change::Append { text } => text.len(),
+                _ => 0,
+            })
+            .sum();
+        check_document_size(document_size(doc), added, self.config.max_document_size)?;
+
+        // Keep earlier pending changes out of a commit with its own timestamp
+        // or message.
+        let described = timestamp.is_some() || message.is_some();
+        if described {
+            doc.commit();
+        }
+        if let Some(message) = message {
+            // Recorded first, so it's the commit's first operation.
+            let id = ID::new(
+                doc.peer_id(),
+                doc.oplog_vv().get(&doc.peer_id()).copied().unwrap_or(0),
+            );
+            doc.get_map(COMMITS_CONTAINER)
+                .insert(&id.to_string(), message)
+                .wrap_err("Failed to record commit message")?;
+        }
+
+        let result = changes.into_iter().try_for_each(|change| {
+            match change {
+                Change::Insert {
+                    index,
+                    text: content,
+                } => {
+                    text.insert(index, &content)
+                        .wrap_err("Failed to apply insertion")?;
+                }
+                Change::Append { text: content } => {
+                    text.insert(text.len_unicode(), &content)
+                        .wrap_err("Failed to apply insertion")?;
+                }
+                Change::Delete { index, len } => {
+                    text.delete(index, len)
+                        .wrap_err("Failed to apply deletion")?;
+                }
+                // Loro refuses to style empty ranges, though there's nothing
+                // to do for them anyway.
+                Change::Mark { len: 0, .. } | Change::Unmark { len: 0, .. } => {}
+                Change::Mark {
+                    index,
+                    len,
+                    key,
+                    value,
+                } => {
+                    text.mark(index..index + len, &key, value)
+                        .wrap_err("Failed to apply style")?;
+                }
+                Change::Unmark { index, len, key } => {
+                    text.unmark(index..index + len, &key)
+                        .wrap_err("Failed to remove style")?;
+                }
+            }
+
+            Ok(())
+        });
+        if described {
+            let mut options = CommitOptions::new();
+            if let Some(timestamp) = timestamp {
+                options = options.timestamp(timestamp);
+            }
+            doc.commit_with(options);
+        }
+
+        // Exporting commits everything applied so far at once.
+        self.broadcast_document(document_id).await?;
+        self.update_comments(document_id).await?;
+
+        result
+    }
+
+    async fn handle_client_message(&mut self, message: ClientMessage) -> Result<()> {
+        info!("Main task received from stdin: {:?}", message);
+
+        match message {
+            // Messages that should only ever be sent to the client.
+            ClientMessage::AddPeerResponse { .. }
+            | ClientMessage::PeerRemoved { .. }
+            | ClientMessage::PeerDisconnected { .. }
+            | ClientMessage::PeerReconnecting { .. }
+            | ClientMessage::PeerState { .. }
+            | ClientMessage::Backpressure { .. }
+            | ClientMessage::PeerLatency { .. }
+            | ClientMessage::PeerList { .. }
+            | ClientMessage::Stats { .. }
+            | ClientMessage::Info { .. }
+            | ClientMessage::Diff { .. }
+            | ClientMessage::UnsyncedChanges { .. }
+            | ClientMessage::CommentMoved { .. }
+            | ClientMessage::CommentResolved { .. }
+            | ClientMessage::PeerDiscovered { .. }
+            | ClientMessage::PeerPermissionSet { .. }
+            | ClientMessage::CursorRemoved { .. }
+            | ClientMessage::PeerPresence { .. }
+            | ClientMessage::PresenceRemoved { .. }
+            | ClientMessage::DocumentCreated { .. }
+            | ClientMessage::DocumentList { .. }
+            | ClientMessage::DocumentSaved { .. }
+            | ClientMessage::DocumentClosed { .. }
+            | ClientMessage::DocumentRenamed { .. }
+            | ClientMessage::Snapshot { .. }
+            | ClientMessage::Text { .. }
+            | ClientMessage::Version { .. }
+            | ClientMessage::SyncApplied { .. }
+            | ClientMessage::DivergenceDetected { .. }
+            | ClientMessage::History { .. }
+            | ClientMessage::MetadataChanged { .. }
+            | ClientMessage::CheckoutResponse { .. }
+            | ClientMessage::JoinDocumentResponse { .. }
+            | ClientMessage::Error { .. } => {
+                bail!("Received message which should only be sent to the client: {message:?}");
+            }
+            ClientMessage::Shutdown => unreachable!("handled by the event loop"),
+            ClientMessage::AddPeer { address } => self.add_peer(address).await?,
+            ClientMessage::AddPeers { addresses } => {
+                let mut seen = HashSet::new();
+                for address in addresses {
+                    if !seen.insert(address.clone()) {
+                        continue;
+                    }
+                    // One bad address shouldn't stop the rest from being
+                    // connected to.
+                    if let Err(e) = self.add_peer(address.clone()).await {
+                        self.report_error(e, &format!("adding peer at {address}"))
+                            .await;
+                    }
+                }
+            }
+            ClientMessage::RemovePeer { address } => {
+                if self.cancel_connect(&address) {
+                    info!("Stopped connecting to peer at {}", address);
+                    self.report_peer_state(&address, PeerState::Disconnected)
+                        .await?;
+                } else if self.peers.contains_key(&address) {
+                    self.remove_connection(&address).await?;
+                } else {
+                    bail!("Not connected to peer: {address}");
+                }
+
+                info!("Removed peer at {}", address);
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::PeerRemoved { address })
+                    .await?;
+            }
+            ClientMessage::SetPeerPermission { address, read_only } => {
+                let Some(peer) = self.peers.get_mut(&address) else {
+                    bail!("Not connected to peer: {address}");
+                };
+                peer.read_only = read_only;
+
+                info!("Set peer at {} to read-only: {}", address, read_only);
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::PeerPermissionSet { address, read_only })
+                    .await?;
+            }
+            ClientMessage::ListPeers => {
+                // Peers still mid-handshake haven't been reported to the
+                // frontend yet.
+                let mut peers: Vec<_> = self
+                    .peers
+                    .iter()
+                    .filter(|(_, peer)| peer.peer_id.is_some())
+                    .map(|(address, _)| address.clone())
+                    .collect();
+                peers.sort();
+
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::PeerList { peers })
+                    .await?;
+            }
+            ClientMessage::Subscribe { document_id } => {
+                let subscriptions = self.subscriptions.get_or_insert_with(HashSet::new);
+                if !subscriptions.insert(document_id.clone()) {
+                    return Ok(());
+                }
+
+                info!("Subscribed to document {}", document_id);
+                self.broadcast(BackendMessage::Subscribe { document_id })
+                    .await?;
+            }
+            ClientMessage::RequestSync {
+                address,
+                document_id,
+            } => {
+                if !self.peers.contains_key(&address) {
+                    bail!("Not connected to peer: {address}");
+                }
+                if !self.documents.contains_key(&document_id) {
+                    bail!("No such document: {document_id}");
+                }
+
+                self.request_full_sync(&address, &document_id).await?;
+            }
+            ClientMessage::VerifySync { document_id } => {
+                if !self.documents.contains_key(&document_id) {
+                    bail!("No such document: {document_id}");
+                }
+
+                self.send_state_hash(&document_id).await?;
+            }
+            ClientMessage::EnableDiscovery { service_name } => {
+                if self.discovery.is_some() {
+                    bail!("Discovery is already enabled");
+                }
+                let Some(port) = self.local_addr.map(|addr| addr.port()) else {
+                    bail!("Listening port is unknown, so it can't be advertised");
+                };
+
+                self.discovery = Some(Discovery::start(
+                    &service_name,
+                    port,
+                    self.peer_id,
+                    self.channels.stdout_tx.clone(),
+                )?);
+                info!("Advertising as {} on port {}", service_name, port);
+            }
+            ClientMessage::GetInfo => {
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::Info {
+                        crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+                        loro_version: env!("LORO_VERSION").to_owned(),
+                        protocol_version: PROTOCOL_VERSION,
+                        peer_id: self.peer_id,
+                    })
+                    .await?;
+            }
+            ClientMessage::GetStats => {
+                let stats = &self.connection.stats;
+                let message = ClientMessage::Stats {
+                    bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+                    bytes_received: stats.bytes_received.load(Ordering::Relaxed),
+                    messages_sent: stats.messages_sent.load(Ordering::Relaxed),
+                    messages_received: stats.messages_received.load(Ordering::Relaxed),
+                    peer_count: self
+                        .peers
+                        .values()
+                        .filter(|peer| peer.peer_id.is_some())
+                        .count(),
+                };
+
+                self.channels.stdout_tx.send(message).await?;
+            }
+            ClientMessage::Change {
+                document_id,
+                container,
+                change,
+                timestamp,
+                message,
+                ..
+            } => {
+                self.apply_changes(&document_id, &container, vec![change], timestamp, message)
+                    .await?;
+            }
+            ClientMessage::ChangeBatch {
+                document_id,
+                container,
+                changes,
+                timestamp,
+                message,
+                ..
+            } => {
+                self.apply_changes(&document_id, &container, changes, timestamp, message)
+                    .await?;
+            }
+            ClientMessage::ClearDocument {
+                document_id,
+                container,
+            } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
+                // Counted the way the frontend does, since the change is
+                // applied and reported as if it came from there.
+                let text = doc.get_text(check_container_name(&container)?);
+                let len = match self.config.index_mode {
+                    IndexMode::CodePoint => text.len_unicode(),
+                    IndexMode::Utf16 => text.len_utf16(),
+                };
+                if len == 0 {
+                    return Ok(());
+                }
+
+                let change = Change::Delete { index: 0, len };
+                self.apply_changes(&document_id, &container, vec![change], None, None)
+                    .await?;
+
+                info!("Cleared {} in document {}", container, document_id);
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::Change {
+                        document_id,
+                        container,
+                        change: Change::Delete { index: 0, len },
+                        origin: Some(ChangeOrigin::Local),
+                        timestamp: None,
+                        message: None,
+                        new_length: Some(0),
+                    })
+                    .await?;
+            }
+            ClientMessage::RenameDocument { old_id, new_id } => {
+                if self.config.read_only {
+                    bail!("Cannot rename documents in read-only mode");
+                }
+                self.rename_document(&old_id, &new_id).await?;
+
+                self.broadcast(BackendMessage::RenameDocument { old_id, new_id })
+                    .await?;
+            }
+            ClientMessage::SetMetadata {
+                document_id,
+                key,
+                value,
+            } => {
+                let doc = self.editable_document(&document_id)?;
+
+                let map = doc.get_map(METADATA_CONTAINER);
+                match value {
+                    Some(value) => {
+                        let added = key.len() + value.len();
+                        check_document_size(
+                            document_size(doc),
+                            added,
+                            self.config.max_document_size,
+                        )?;
+                        map.insert(&key, value).wrap_err("Failed to set metadata")?;
+                    }
+                    None => {
+                        map.delete(&key).wrap_err("Failed to remove metadata")?;
+                    }
+                }
+
+                info!("Set metadata {} of document {}", key, document_id);
+                self.broadcast_document(&document_id).await?;
+            }
+            ClientMessage::AddComment {
+                document_id,
+                start,
+                end,
+                body,
+            } => {
+                let doc = self.editable_document(&document_id)?;
+
+                let text = doc.get_text(TEXT_CONTAINER);
+                let (start, end) = match self.config.index_mode {
+                    IndexMode::CodePoint => (start, end),
+                    IndexMode::Utf16 => {
+                        let content = text.to_string();
+                        (
+                            index_from_utf16(&content, start)?,
+                            index_from_utf16(&content, end)?,
+                        )
+                    }
+                };
+                if start >= end || end > text.len_unicode() {
+                    bail!(
+                        "Comment range {start}..{end} is empty or out of bounds for text of \
+                         length {}",
+                        text.len_unicode()
+                    );
+                }
+                check_document_size(
+                    document_size(doc),
+                    body.len(),
+                    self.config.max_document_size,
+                )?;
+                // Anchoring to the first and last characters keeps text
+                // inserted just outside the range out of the comment.
+                let (Some(start), Some(end)) = (
+                    text.get_cursor(start, Side::Left),
+                    text.get_cursor(end - 1, Side::Right),
+                ) else {
+                    bail!("Failed to anchor comment in document {document_id}");
+                };
+
+                let comment_id = format!("{:016x}", rand::random::<u64>());
+                let comment = doc
+                    .get_map(COMMENTS_CONTAINER)
+                    .insert_container(&comment_id, LoroMap::new())
+                    .wrap_err("Failed to add comment")?;
+                comment
+                    .insert("body", body)
+                    .and_then(|_| comment.insert("resolved", false))
+                    .and_then(|_| comment.insert("start", start.encode()))
+                    .and_then(|_| comment.insert("end", end.encode()))
+                    .wrap_err("Failed to add comment")?;
+
+                info!("Added comment {} to document {}", comment_id, document_id);
+                self.broadcast_document(&document_id).await?;
+                self.update_comments(&document_id).await?;
+            }
+            ClientMessage::ResolveComment {
+                document_id,
+                comment_id,
+            } => {
+                let doc = self.editable_document(&document_id)?;
+
+                let Some(Either::Right(Container::Map(comment))) =
+                    doc.get_map(COMMENTS_CONTAINER).get(&comment_id)
+                else {
+                    bail!("No such comment in document {document_id}: {comment_id}");
+                };
+                comment
+                    .insert("resolved", true)
+                    .wrap_err("Failed to resolve comment")?;
+
+                info!(
+                    "Resolved comment {} in document {}",
+                    comment_id, document_id
+                );
+                self.broadcast_document(&document_id).await?;
+                self.update_comments(&document_id).await?;
+            }
+            ClientMessage::PauseSync {
+                document_id,
+                hold_incoming,
+            } => {
+                let doc_info = self
+                    .active_documents
+                    .get_mut(&document_id)
+                    .ok_or_else(|| eyre!("Document is not active: {document_id}"))?;
+                if doc_info.paused.is_some() {
+                    bail!("Sync of document {document_id} is already paused");
+                }
+                let was_pending = doc_info.flush_pending;
+
+                // Publish changes made before pausing first.
+                if was_pending {
+                    self.flush_document(&document_id).await?;
+                }
+                self.active_documents.get_mut(&document_id).unwrap().paused = Some(PausedSync {
+                    hold_incoming,
+                    ..Default::default()
+                });
+
+                info!("Paused sync of document {}", document_id);
+            }
+            ClientMessage::ResumeSync { document_id } => {
+                self.resume_sync(&document_id).await?;
+
+                info!("Resumed sync of document {}", document_id);
+            }
+            ClientMessage::Undo { document_id } => {
+                self.undo_redo(&document_id, false).await?;
+            }
+            ClientMessage::Redo { document_id } => {
+                self.undo_redo(&document_id, true).await?;
+            }
+            ClientMessage::CreateDocument {
+                name,
+                initial_content,
+                container,
+                document_id,
+            } => {
+                check_container_name(&container)?;
+                let id = document_id.unwrap_or_else(|| generate_unique_id(&name, &self.documents));
+
+                self.create_document(&id, &container, initial_content.as_deref())
+                    .await?;
+            }
+            ClientMessage::ListDocuments => {
+                let mut documents: Vec<_> = self
+                    .documents
+                    .iter()
+                    .map(|(id, doc)| DocumentSummary {
+                        id: id.clone(),
+                        length: match self.config.index_mode {
+                            IndexMode::CodePoint => doc.get_text(TEXT_CONTAINER).len_unicode(),
+                            IndexMode::Utf16 => doc.get_text(TEXT_CONTAINER).len_utf16(),
+                        },
+                    })
+                    .collect();
+                documents.sort_by(|a, b| a.id.cmp(&b.id));
+
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::DocumentList { documents })
+                    .await?;
+            }
+            ClientMessage::CreateDocumentFromFile { path } => {
+                let data = tokio::fs::read(&path)
+                    .await
+                    .wrap_err_with(|| format!("Failed to read {path}"))?;
+                let content = String::from_utf8(data)
+                    .wrap_err_with(|| format!("{path} is not valid UTF-8"))?;
+
+                let name = Path::new(&path)
+                    .file_name()
+                    .map_or_else(|| path.clone(), |s| s.to_string_lossy().into_owned());
+                let id = generate_unique_id(&name, &self.documents);
+                self.create_document(&id, TEXT_CONTAINER, Some(&content))
+                    .await?;
+
+                // The frontend hasn't seen the file's contents.
+                if !content.is_empty() {
+                    let change = Change::Insert {
+                        index: 0,
+                        text: content,
+                    };
+                    let new_length = length_after(0, &change, self.config.index_mode);
+                    self.channels
+                        .stdout_tx
+                        .send(ClientMessage::Change {
+                            document_id: id,
+                            container: TEXT_CONTAINER.to_owned(),
+                            change,
+                            origin: Some(ChangeOrigin::Local),
+                            timestamp: None,
+                            message: None,
+                            new_length: Some(new_length),
+                        })
+                        .await?;
+                }
+            }
+            ClientMessage::GetText {
+                document_id,
+                container,
+            } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
+                // Imports are applied synchronously, so this includes
+                // everything received so far.
+                let content = doc.get_text(check_container_name(&container)?).to_string();
+
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::Text {
+                        document_id,
+                        container,
+                        content,
+                    })
+                    .await?;
+            }
+            ClientMessage::GetVersion { document_id } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
+
+                let frontiers = format_frontiers(doc);
+
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::Version {
+                        document_id,
+                        frontiers,
+                    })
+                    .await?;
+            }
+            ClientMessage::HasUnsyncedChanges { document_id } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
+
+                let (unsynced, pending_ops) = self.unsynced_changes(&document_id, doc);
+
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::UnsyncedChanges {
+                        document_id,
+                        unsynced,
+                        pending_ops,
+                    })
+                    .await?;
+            }
+            ClientMessage::GetHistory { document_id } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
+
+                let (entries, truncated) = document_history(doc, self.config.max_history_entries);
+
                 self.channels
                     .stdout_tx
-                    .send(ClientMessage::UnsetMark {
-                        document_id: document_id.to_owned(),
-                        peer_id,
+                    .send(ClientMessage::History {
+                        document_id,
+                        entries,
+                        truncated,
                     })
-                    .await
-                    .unwrap();
+                    .await?;
             }
-        } else {
-            let Some(cursor) = peer_id
-                .and_then(|id| {
-                    if mark {
-                        doc_info.marks.get(&id)
-                    } else {
-                        doc_info.cursors.get(&id)
-                    }
-                })
-                .or(doc_info.cursor.as_ref())
-            else {
-                return;
-            };
+            ClientMessage::Checkout {
+                document_id,
+                frontiers,
+            } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
 
-            let pos = self.doc.get_cursor_pos(cursor).unwrap().current.pos;
-            self.channels
-                .stdout_tx
-                .send(ClientMessage::SetCursor {
-                    document_id: document_id.to_owned(),
-                    peer_id,
-                    location: pos,
-                    mark,
-                })
-                .await
-                .unwrap();
-        }
-    }
+                doc.checkout(&parse_frontiers(&frontiers)?)
+                    .wrap_err("Failed to check out version")?;
 
-    async fn handle_client_message(&mut self, message: ClientMessage) {
-        info!("Main task received from stdin: {:?}", message);
+                info!("Checked out document {} at {:?}", document_id, frontiers);
+                self.send_checkout_response(document_id).await?;
+            }
+            ClientMessage::CheckoutToLatest { document_id } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
 
-        match message {
-            // Messages that should only ever be sent to the client.
-            ClientMessage::AddPeerResponse { .. }
-            | ClientMessage::CreateDocumentResponse { .. }
-            | ClientMessage::JoinDocumentResponse { .. } => {
-                error!(
-                    "Received message which should only be sent to the client: {:?}",
-                    message
-                );
+                doc.checkout_to_latest();
+
+                info!("Checked out latest version of document {}", document_id);
+                self.send_checkout_response(document_id).await?;
             }
-            ClientMessage::AddPeer { address } => {
-                info!("Connecting to peer at {}", address);
-                let socket = TcpStream::connect(&address).await.unwrap();
-                socket.set_nodelay(true).unwrap();
+            ClientMessage::DiffVersions {
+                document_id,
+                container,
+                from_frontiers,
+                to_frontiers,
+            } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    bail!("No such document: {document_id}");
+                };
+                let from = parse_frontiers(&from_frontiers)?;
+                let to = parse_frontiers(&to_frontiers)?;
+                let text = doc.get_text(check_container_name(&container)?);
 
-                let (read, write) = socket.into_split();
-                let read_framed = tokio_serde::SymmetricallyFramed::new(
-                    FramedRead::new(read, LengthDelimitedCodec::new()),
-                    SymmetricalJson::<BackendMessage>::default(),
-                );
-                let write_framed = tokio_serde::SymmetricallyFramed::new(
-                    FramedWrite::new(write, LengthDelimitedCodec::new()),
-                    SymmetricalJson::<BackendMessage>::default(),
-                );
+                // Return to the version shown before, even if either version
+                // can't be checked out.
+                let detached = doc.is_detached();
+                let current = doc.state_frontiers();
+                let versions = doc.checkout(&from).and_then(|()| {
+                    let old = text.to_string();
+                    doc.checkout(&to).map(|()| (old, text.to_string()))
+                });
+                if detached {
+                    doc.checkout(&current)
+                        .wrap_err("Failed to restore checked out version")?;
+                } else {
+                    doc.checkout_to_latest();
+                }
+                let (old, new) = versions.wrap_err("Failed to check out version")?;
 
-                self.channels
-                    .incoming_to_tx
-                    .send(read_framed)
-                    .await
-                    .unwrap();
-                self.channels
-                    .outgoing_tx
-                    .send(OutgoingMessage::NewSocket(write_framed))
-                    .await
-                    .unwrap();
+                let mut changes = text_diff(&old, &new);
+                if self.config.index_mode == IndexMode::Utf16 {
+                    let mut text = old;
+                    changes = changes
+                        .into_iter()
+                        .map(|change| change_to_utf16(&mut text, change))
+                        .collect();
+                }
 
-                info!("Connected to peer at {}", address);
-                self.broadcast_all_data().await;
+                info!(
+                    "Diffed document {} from {:?} to {:?}",
+                    document_id, from_frontiers, to_frontiers
+                );
                 self.channels
                     .stdout_tx
-                    .send(ClientMessage::AddPeerResponse { address })
-                    .await
-                    .unwrap();
+                    .send(ClientMessage::Diff {
+                        document_id,
+                        container,
+                        changes,
+                    })
+                    .await?;
             }
-            ClientMessage::Change {
+            ClientMessage::SaveDocument { document_id, path } => {
+                self.save_document(document_id, path).await?;
+            }
+            ClientMessage::CloseDocument {
                 document_id,
-                change,
+                path,
+                force,
             } => {
-                match change {
-                    Change::Insert { index, text } => {
-                        self.doc
-                            .get_text(document_id.as_str())
-                            .insert(index, &text)
-                            .unwrap();
-                    }
-                    Change::Delete { index, len } => {
-                        self.doc
-                            .get_text(document_id.as_str())
-                            .delete(index, len)
-                            .unwrap();
-                    }
-                }
+                self.close_document(&document_id, path, force).await?;
 
-                // TODO Only send deltas to other clients.
-                self.broadcast_all_data().await;
+                info!("Closed document {}", document_id);
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::DocumentClosed { document_id })
+                    .await?;
             }
-            ClientMessage::CreateDocument {
-                name,
-                initial_content,
-            } => {
-                let id = generate_unique_id(&name, &mut self.doc);
-
-                self.doc.get_text(id.as_str()).update(&initial_content);
-
-                let subscription = self.add_doc_change_subscription(&id);
-                self.active_documents.insert(
-                    id.clone(),
-                    // TODO Use Default trait
-                    DocumentInfo {
-                        sub_id: subscription,
-                        cursor: None,
-                        mark: None,
-                        cursors: HashMap::new(),
-                        marks: HashMap::new(),
-                    },
-                );
+            ClientMessage::LoadDocument { path } => {
+                let data = tokio::fs::read(&path)
+                    .await
+                    .wrap_err_with(|| format!("Failed to read snapshot from {path}"))?;
+                let doc = self.new_document()?;
+                doc.import(&data)
+                    .wrap_err_with(|| format!("Failed to import snapshot from {path}"))?;
 
-                info!("Created new document with id {}", id);
+                let name = Path::new(&path)
+                    .file_stem()
+                    .map_or_else(|| path.clone(), |s| s.to_string_lossy().into_owned());
+                let id = generate_unique_id(&name, &self.documents);
+                self.open_imported_document(&id, doc).await?;
+
+                info!("Loaded document with id {} from {}", id, path);
+            }
+            ClientMessage::ExportSnapshot { document_id } => {
+                let doc = self
+                    .documents
+                    .get(&document_id)
+                    .ok_or_else(|| eyre!("No such document: {document_id}"))?;
+                let data = BASE64_STANDARD.encode(doc.export_snapshot());
 
-                self.broadcast_all_data().await;
                 self.channels
                     .stdout_tx
-                    .send(ClientMessage::CreateDocumentResponse { id })
-                    .await
-                    .unwrap();
+                    .send(ClientMessage::Snapshot { document_id, data })
+                    .await?;
+            }
+            ClientMessage::ImportSnapshot { data, document_id } => {
+                let data = BASE64_STANDARD
+                    .decode(data)
+                    .wrap_err("Snapshot is not valid base64")?;
+                let doc = self.new_document()?;
+                doc.import(&data).wrap_err("Failed to import snapshot")?;
+
+                let id = match document_id {
+                    Some(id) if self.documents.contains_key(&id) => {
+                        bail!("Document already exists: {id}");
+                    }
+                    Some(id) => id,
+                    None => generate_unique_id("snapshot", &self.documents),
+                };
+                self.open_imported_document(&id, doc).await?;
+
+                info!("Imported snapshot as document {}", id);
             }
             ClientMessage::JoinDocument { id } => {
                 if self.active_documents.contains_key(&id) {
-                    error!(
-                        "Client attempted to join document that is already active: {}",
-                        id
-                    );
-                    // TODO Broadcast error to frontend.
-
-                    return;
+                    bail!("Document is already active: {id}");
                 }
-                if self.doc.get_text(id.as_str()).is_empty() {
-                    error!("Client attempted to join document with no content: {}", id);
-                    // TODO Broadcast error to frontend.
-
-                    return;
+                if !self.documents.contains_key(&id) {
+                    bail!("No such document: {id}");
                 }
 
-                let subscription = self.add_doc_change_subscription(&id);
-                self.active_documents.insert(
-                    id.clone(),
-                    DocumentInfo {
-                        sub_id: subscription,
-                        cursor: None,
-                        mark: None,
-                        cursors: HashMap::new(),
-                        marks: HashMap::new(),
-                    },
-                );
+                self.activate_document(&id);
 
                 info!("Joined document with id {}", id);
 
+                let doc = &self.documents[&id];
                 self.channels
                     .stdout_tx
                     .send(ClientMessage::JoinDocumentResponse {
                         id: id.clone(),
-                        current_content: self.doc.get_text(id.as_str()).to_string(),
+                        current_content: doc.get_text(TEXT_CONTAINER).to_string(),
                     })
-                    .await
-                    .unwrap();
+                    .await?;
+                // The response only carries the main container's plain text,
+                // so follow it with its formatting and any other containers.
+                for (container, change) in self.changes_to_frontend(doc_to_changes(doc)?) {
+                    if container == TEXT_CONTAINER && !matches!(change, Change::Mark { .. }) {
+                        continue;
+                    }
+                    self.channels
+                        .stdout_tx
+                        .send(ClientMessage::Change {
+                            document_id: id.clone(),
+                            container,
+                            change,
+                            origin: None,
+                            timestamp: None,
+                            message: None,
+                            new_length: None,
+                        })
+                        .await?;
+                }
+                for (key, value) in metadata_entries(doc) {
+                    self.channels
+                        .stdout_tx
+                        .send(ClientMessage::MetadataChanged {
+                            document_id: id.clone(),
+                            key,
+                            value: Some(value),
+                        })
+                        .await?;
+                }
+                self.update_comments(&id).await?;
             }
             ClientMessage::SetCursor {
                 document_id,
@@ -520,8 +3633,15 @@ impl Client {
                 mark,
                 ..
             } => {
-                let doc_info = self.active_documents.get_mut(&document_id).unwrap();
-                let text = self.doc.get_text(document_id.as_str());
+                let doc_info = self
+                    .active_documents
+                    .get_mut(&document_id)
+                    .ok_or_else(|| eyre!("Document is not active: {document_id}"))?;
+                let text = self.documents[&document_id].get_text(TEXT_CONTAINER);
+                let location = match self.config.index_mode {
+                    IndexMode::CodePoint => location,
+                    IndexMode::Utf16 => index_from_utf16(&text.to_string(), location)?,
+                };
 
                 if mark {
                     doc_info.mark = text.get_cursor(location, Default::default());
@@ -529,13 +3649,16 @@ impl Client {
                     doc_info.cursor = text.get_cursor(location, Default::default());
                 }
 
-                self.broadcast_cursor_update(&document_id).await;
+                self.broadcast_cursor_update(&document_id).await?;
             }
             ClientMessage::UnsetMark {
                 document_id,
                 peer_id,
             } => {
-                let doc_info = self.active_documents.get_mut(&document_id).unwrap();
+                let doc_info = self
+                    .active_documents
+                    .get_mut(&document_id)
+                    .ok_or_else(|| eyre!("Document is not active: {document_id}"))?;
 
                 if let Some(peer_id) = peer_id {
                     doc_info.marks.remove(&peer_id);
@@ -543,16 +3666,361 @@ impl Client {
                     doc_info.mark = None;
                 }
 
-                self.broadcast_cursor_update(&document_id).await;
+                self.broadcast_cursor_update(&document_id).await?;
+            }
+            ClientMessage::SetPresence { name, color } => {
+                let presence = Presence { name, color };
+                self.presence = Some(presence.clone());
+
+                self.broadcast(BackendMessage::Presence(presence)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_hello(
+        &mut self,
+        address: &str,
+        protocol_version: u32,
+        peer_id: PeerID,
+        read_only: bool,
+        subscriptions: Option<Vec<String>>,
+        psk_nonce: Option<ByteBuf>,
+    ) -> Result<()> {
+        if protocol_version != PROTOCOL_VERSION {
+            self.remove_connection(address).await?;
+            bail!(
+                "Peer at {address} uses protocol version {protocol_version}, \
+                 but we use {PROTOCOL_VERSION}"
+            );
+        }
+
+        let Some(peer) = self.peers.get_mut(address) else {
+            return Ok(());
+        };
+        if peer.peer_id.is_some() {
+            bail!("Peer at {address} sent a second hello");
+        }
+
+        if peer_id == self.peer_id {
+            self.remove_connection(address).await?;
+            bail!("Peer at {address} is this client");
+        }
+        let peer = self.peers.get_mut(address).unwrap();
+        peer.peer_id = Some(peer_id);
+        peer.read_only = read_only;
+        peer.subscriptions = subscriptions.map(|ids| ids.into_iter().collect());
+
+        if self.config.psk.is_some() != psk_nonce.is_some() {
+            self.remove_connection(address).await?;
+            bail!("Only one of this client and the peer at {address} requires a pre-shared key");
+        }
+        // Our proof depends on our peer ID, which differs from the peer's, so
+        // it can't be passed back to us as the peer's own.
+        let proof = self
+            .config
+            .psk
+            .as_deref()
+            .zip(psk_nonce)
+            .map(|(psk, nonce)| psk_proof(psk, &nonce, self.peer_id));
+        if let Some(mac) = proof {
+            self.send_outgoing(OutgoingMessage::DirectMessage {
+                address: address.to_owned(),
+                message: BackendMessage::PskProof {
+                    mac: ByteBuf::from(mac),
+                },
+            })
+            .await?;
+            // The handshake is finished once the peer's proof arrives.
+            return Ok(());
+        }
+
+        self.finish_handshake(address, peer_id).await
+    }
+
+    /// Checks the peer at `address` knows the pre-shared key, finishing the
+    /// handshake if so and disconnecting from it otherwise.
+    async fn handle_psk_proof(&mut self, address: &str, mac: &[u8]) -> Result<()> {
+        let Some(peer) = self.peers.get_mut(address) else {
+            return Ok(());
+        };
+        let (Some(peer_id), Some(nonce), Some(psk)) =
+            (peer.peer_id, peer.psk_nonce, &self.config.psk)
+        else {
+            bail!("Peer at {address} sent an unexpected pre-shared key proof");
+        };
+
+        if !verify_psk_proof(psk, &nonce, peer_id, mac) {
+            self.remove_connection(address).await?;
+            bail!("Peer at {address} doesn't know the pre-shared key");
+        }
+        peer.psk_nonce = None;
+
+        self.finish_handshake(address, peer_id).await
+    }
+
+    /// Starts exchanging updates with the peer at `address` once it's been
+    /// identified as `peer_id`.
+    async fn finish_handshake(&mut self, address: &str, peer_id: PeerID) -> Result<()> {
+        // Only now is the peer known to be who it says, so an unverified
+        // connection can't push out a real one.
+        let outgoing = self.peers[address].outgoing;
+        let duplicate = self
+            .peers
+            .iter()
+            .find(|(other, peer)| {
+                *other != address && peer.peer_id == Some(peer_id) && peer.handshake_complete()
+            })
+            .map(|(other, peer)| (other.clone(), peer.outgoing));
+        if let Some((existing, existing_outgoing)) = duplicate {
+            // When two clients connect to each other at the same time, both
+            // must agree which connection to keep, so keep the one opened by
+            // the client with the lower ID.
+            let opener = |outgoing| if outgoing { self.peer_id } else { peer_id };
+            if opener(outgoing) < opener(existing_outgoing) {
+                info!(
+                    "Replacing connection to peer {} at {} with {}",
+                    peer_id, existing, address
+                );
+                self.remove_connection(&existing).await?;
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::PeerRemoved { address: existing })
+                    .await?;
+            } else {
+                self.remove_connection(address).await?;
+                if outgoing {
+                    bail!("Already connected to peer {peer_id} at {existing}");
+                }
+                info!(
+                    "Closed duplicate connection from peer {} at {}",
+                    peer_id, address
+                );
+                return Ok(());
+            }
+        }
+
+        info!("Completed handshake with peer {} at {}", peer_id, address);
+        self.report_peer_state(address, PeerState::Connected)
+            .await?;
+        // The rest of the initial sync is sent once the peer's versions are
+        // received.
+        self.send_versions(address).await?;
+        self.channels
+            .stdout_tx
+            .send(ClientMessage::AddPeerResponse {
+                address: address.to_owned(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Applies operations in a document sent by the peer at `address`, and
+    /// acknowledges them.
+    async fn import_sync(&mut self, address: &str, document_id: &str, data: Vec<u8>) -> Result<()> {
+        let meta = LoroDoc::decode_import_blob_meta(&data).map_err(|e| {
+            let source = format!("peer at {address}");
+            import_error(e, document_id, &source, data.len())
+        })?;
+        if let Some(doc) = self.documents.get(document_id) {
+            // The same operations can still reach us through several peers.
+            if doc.oplog_vv().includes_vv(&meta.partial_end_vv) {
+                debug!("Ignoring already-applied updates for {}", document_id);
+                self.peers
+                    .get_mut(address)
+                    .unwrap()
+                    .record_version(document_id, &meta.partial_end_vv);
+                // Acknowledge them anyway, or the peer will keep sending them.
+                return self
+                    .send_outgoing(OutgoingMessage::DirectMessage {
+                        address: address.to_owned(),
+                        message: BackendMessage::Ack {
+                            document_id: document_id.to_owned(),
+                            frontiers: ByteBuf::from(doc.oplog_frontiers().encode()),
+                        },
+                    })
+                    .await;
+            }
+        }
+        // The text can't grow by more than the update's size.
+        let size = self.documents.get(document_id).map_or(0, document_size);
+        check_document_size(size, data.len(), self.config.max_document_size)
+            .wrap_err_with(|| format!("Rejected data for {document_id} from peer at {address}"))?;
+        let created = !self.documents.contains_key(document_id);
+        if created {
+            let doc = self.new_document()?;
+            self.documents.insert(document_id.to_owned(), doc);
+        }
+        // Tag the import so the frontend can tell which peer the changes came
+        // from.
+        let peer_id = self.peers[address].peer_id.unwrap();
+        if let Some(paused) = self
+            .active_documents
+            .get_mut(document_id)
+            .and_then(|doc_info| doc_info.paused.as_mut())
+            .filter(|paused| paused.hold_incoming)
+        {
+            // Held updates count towards the size too, as they will be
+            // imported together.
+            let held: usize = paused.held_updates.iter().map(|(_, data)| data.len()).sum();
+            check_document_size(size + held, data.len(), self.config.max_document_size)
+                .wrap_err_with(|| {
+                    format!("Rejected data for {document_id} from peer at {address}")
+                })?;
+            debug!("Holding updates for paused document {}", document_id);
+            paused.held_updates.push((peer_id, data));
+            return Ok(());
+        }
+        let doc = &self.documents[document_id];
+        if let Err(e) = doc.import_with(&data, &peer_id.to_string()) {
+            // Don't keep an empty document the peer never managed to send.
+            if created {
+                self.documents.remove(document_id);
             }
+            let source = format!("peer at {address}");
+            return Err(import_error(e, document_id, &source, data.len()));
         }
+        self.peers
+            .get_mut(address)
+            .unwrap()
+            .record_version(document_id, &meta.partial_end_vv);
+        self.send_outgoing(OutgoingMessage::DirectMessage {
+            address: address.to_owned(),
+            message: BackendMessage::Ack {
+                document_id: document_id.to_owned(),
+                frontiers: ByteBuf::from(doc.oplog_frontiers().encode()),
+            },
+        })
+        .await?;
+        if self.sync_report_pending.insert(document_id.to_owned()) {
+            begin_timer(
+                &mut self.tasks.timers,
+                MainTaskMessage::ReportSync(document_id.to_owned()),
+                SYNC_REPORT_DELAY,
+                self.channels.main_tx.clone(),
+            );
+        }
+
+        // Relays never make local changes to forward updates along with, so
+        // forward them right away.
+        if self.config.relay {
+            self.send_deltas(document_id).await?;
+        }
+
+        Ok(())
     }
 
-    async fn handle_backend_message(&mut self, message: BackendMessage) {
+    async fn handle_backend_message(
+        &mut self,
+        address: &str,
+        message: BackendMessage,
+    ) -> Result<()> {
+        if let BackendMessage::Hello {
+            protocol_version,
+            peer_id,
+            read_only,
+            subscriptions,
+            psk_nonce,
+            ..
+        } = message
+        {
+            return self
+                .handle_hello(
+                    address,
+                    protocol_version,
+                    peer_id,
+                    read_only,
+                    subscriptions,
+                    psk_nonce,
+                )
+                .await;
+        }
+        if let BackendMessage::PskProof { mac } = message {
+            return self.handle_psk_proof(address, &mac).await;
+        }
+        let handshake_complete = self
+            .peers
+            .get(address)
+            .is_some_and(PeerInfo::handshake_complete);
+        if !handshake_complete {
+            bail!("Peer at {address} sent {message:?} before completing handshake");
+        }
+        if message.is_data() {
+            self.peers.get_mut(address).unwrap().last_seen = Instant::now();
+        }
+
         match message {
-            BackendMessage::DocumentSync { data } => {
-                info!("Received document sync data");
-                self.doc.import(&data).unwrap();
+            BackendMessage::Hello { .. } | BackendMessage::PskProof { .. } => unreachable!(),
+            BackendMessage::Signed { .. } => unreachable!("unwrapped by the transport"),
+            BackendMessage::DocumentSync {
+                document_id,
+                data,
+                compressed,
+                seq,
+            } => {
+                if self.peers[address].read_only {
+                    bail!("Rejected changes to {document_id} from read-only peer at {address}");
+                }
+                info!("Received document sync data for document {}", document_id);
+
+                let data = if compressed {
+                    decompress_bounded(&data, self.config.max_document_size)
+                        .wrap_err_with(|| format!("Failed to decompress data for {document_id}"))?
+                } else {
+                    data
+                };
+
+                let received = self
+                    .peers
+                    .get_mut(address)
+                    .unwrap()
+                    .received_syncs
+                    .entry(document_id.clone())
+                    .or_default();
+                if seq < received.next {
+                    debug!(
+                        "Ignoring repeated sync {} of {} from peer at {}",
+                        seq, document_id, address
+                    );
+                    return Ok(());
+                }
+                if seq > received.next {
+                    let held: usize = received.held.values().map(Vec::len).sum();
+                    if received.held.len() < MAX_HELD_SYNCS
+                        && check_document_size(held, data.len(), self.config.max_document_size)
+                            .is_ok()
+                    {
+                        debug!(
+                            "Holding sync {} of {} from peer at {} until {} arrives",
+                            seq, document_id, address, received.next
+                        );
+                        received.held.insert(seq, data);
+                        return Ok(());
+                    }
+
+                    // Whatever's missing may never arrive, so start over.
+                    warn!(
+                        "Too many syncs of {} from peer at {} arrived out of order; requesting a resync",
+                        document_id, address
+                    );
+                    received.held.clear();
+                    received.next = seq + 1;
+                    return self.request_full_sync(address, &document_id).await;
+                }
+
+                received.next += 1;
+                let mut ready = vec![data];
+                while let Some(data) = received.held.remove(&received.next) {
+                    ready.push(data);
+                    received.next += 1;
+                }
+                for data in ready {
+                    if let Err(e) = self.import_sync(address, &document_id, data).await {
+                        self.report_error(e, "applying updates from peer").await;
+                    }
+                }
             }
             BackendMessage::CursorUpdate {
                 document_id,
@@ -568,7 +4036,7 @@ impl Client {
 
                 let Some(doc_info) = self.active_documents.get_mut(&document_id) else {
                     // Document not active.
-                    return;
+                    return Ok(());
                 };
 
                 if mark {
@@ -578,7 +4046,7 @@ impl Client {
                 }
 
                 self.update_frontend_cursor(&document_id, Some(peer_id), mark)
-                    .await;
+                    .await?;
             }
             BackendMessage::UnsetMark {
                 document_id,
@@ -591,23 +4059,337 @@ impl Client {
 
                 let Some(doc_info) = self.active_documents.get_mut(&document_id) else {
                     // Document not active.
-                    return;
+                    return Ok(());
                 };
 
                 doc_info.marks.remove(&peer_id);
 
                 self.update_frontend_cursor(&document_id, Some(peer_id), true)
-                    .await;
+                    .await?;
+            }
+            BackendMessage::Versions { versions } => {
+                info!(
+                    "Received versions of {} documents from peer at {}",
+                    versions.len(),
+                    address
+                );
+                self.send_initial_sync(address, versions).await?;
+            }
+            BackendMessage::Subscribe { document_id } => {
+                info!("Peer at {} subscribed to document {}", address, document_id);
+                let peer = self.peers.get_mut(address).unwrap();
+                // Peers subscribed to everything already have the document.
+                let newly_subscribed = peer
+                    .subscriptions
+                    .as_ref()
+                    .is_some_and(|subscriptions| !subscriptions.contains(&document_id));
+                peer.subscriptions
+                    .get_or_insert_with(HashSet::new)
+                    .insert(document_id.clone());
+
+                if newly_subscribed && self.documents.contains_key(&document_id) {
+                    self.send_deltas(&document_id).await?;
+                }
+            }
+            BackendMessage::SyncRequest { document_id } => {
+                info!(
+                    "Peer at {} requested full sync of document {}",
+                    address, document_id
+                );
+                if !self.documents.contains_key(&document_id) {
+                    bail!("Peer at {address} requested sync of unknown document {document_id}");
+                }
+                if self.config.read_only {
+                    // The peer would only reject it.
+                    return Ok(());
+                }
+
+                self.send_full_document(address, &document_id).await?;
+            }
+            BackendMessage::StateHash {
+                document_id,
+                algorithm,
+                hash,
+                frontiers,
+            } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    return Ok(());
+                };
+                if algorithm != CONTENT_HASH_ALGORITHM || doc.is_detached() {
+                    return Ok(());
+                }
+                let frontiers = Frontiers::decode(&frontiers).wrap_err_with(|| {
+                    format!("Peer at {address} sent invalid frontiers for {document_id}")
+                })?;
+                // Hashes only have to match once both sides have the same
+                // operations.
+                if doc.frontiers_to_vv(&frontiers).as_ref() != Some(&doc.oplog_vv()) {
+                    debug!(
+                        "Not comparing hash of {} with peer at {}, which has a different version",
+                        document_id, address
+                    );
+                    return Ok(());
+                }
+                if hash == content_hash(doc) {
+                    debug!("Document {} matches peer at {}", document_id, address);
+                    return Ok(());
+                }
+
+                warn!(
+                    "Document {} has diverged from peer at {}",
+                    document_id, address
+                );
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::DivergenceDetected {
+                        document_id: document_id.clone(),
+                        address: address.to_owned(),
+                        algorithm,
+                    })
+                    .await?;
+                if self.config.resync_on_divergence {
+                    self.request_full_sync(address, &document_id).await?;
+                }
+            }
+            BackendMessage::RenameDocument { old_id, new_id } => {
+                if self.peers[address].read_only {
+                    bail!("Rejected rename of {old_id} from read-only peer at {address}");
+                }
+                // Renames are forwarded between peers, so we may have renamed
+                // it already.
+                if !self.documents.contains_key(&old_id) && self.documents.contains_key(&new_id) {
+                    return Ok(());
+                }
+                self.rename_document(&old_id, &new_id)
+                    .await
+                    .wrap_err_with(|| {
+                        format!("Failed to rename {old_id} as asked by peer at {address}")
+                    })?;
+
+                let others: Vec<_> = self
+                    .peers
+                    .iter()
+                    .filter(|(other, peer)| *other != address && peer.peer_id.is_some())
+                    .map(|(other, _)| other.clone())
+                    .collect();
+                for other in others {
+                    self.send_outgoing(OutgoingMessage::DirectMessage {
+                        address: other,
+                        message: BackendMessage::RenameDocument {
+                            old_id: old_id.clone(),
+                            new_id: new_id.clone(),
+                        },
+                    })
+                    .await?;
+                }
+            }
+            BackendMessage::Ack {
+                document_id,
+                frontiers,
+            } => {
+                let Some(doc) = self.documents.get(&document_id) else {
+                    return Ok(());
+                };
+                let frontiers = Frontiers::decode(&frontiers).wrap_err_with(|| {
+                    format!("Peer at {address} sent invalid frontiers for {document_id}")
+                })?;
+                // Frontiers we can't resolve include operations we don't have
+                // yet, which we'll hear about when they're sent to us.
+                let Some(version) = doc.frontiers_to_vv(&frontiers) else {
+                    return Ok(());
+                };
+                debug!(
+                    "Peer at {} acknowledged version {:?} of document {}",
+                    address, version, document_id
+                );
+                if let Some(versions) = self.peers.get_mut(address).unwrap().versions.as_mut() {
+                    versions.entry(document_id).or_default().merge(&version);
+                }
+            }
+            BackendMessage::Presence(presence) => {
+                let peer = self.peers.get_mut(address).unwrap();
+                // Checked along with the handshake above.
+                let peer_id = peer.peer_id.unwrap();
+                info!("Peer {} is now {:?}", peer_id, presence);
+
+                peer.presence = Some(presence.clone());
+                self.channels
+                    .stdout_tx
+                    .send(ClientMessage::PeerPresence {
+                        peer_id,
+                        name: presence.name,
+                        color: presence.color,
+                    })
+                    .await?;
+            }
+            BackendMessage::Ping => {
+                self.send_outgoing(OutgoingMessage::DirectMessage {
+                    address: address.to_owned(),
+                    message: BackendMessage::Pong,
+                })
+                .await?;
+            }
+            BackendMessage::Pong => {
+                let Some(peer) = self.peers.get_mut(address) else {
+                    return Ok(());
+                };
+                peer.missed_pongs = 0;
+
+                // Pongs arrive in the order the pings were sent.
+                if let Some(sent) = peer.pings_sent.pop_front() {
+                    let rtt_ms = sent.elapsed().as_millis() as u64;
+                    self.channels
+                        .stdout_tx
+                        .send(ClientMessage::PeerLatency {
+                            address: address.to_owned(),
+                            rtt_ms,
+                        })
+                        .await?;
+                }
             }
         }
+
+        Ok(())
+    }
+}
+
+struct PeerInfo {
+    /// Peer ID of the client at the other end of the connection, once the
+    /// handshake has completed.
+    peer_id: Option<PeerID>,
+    /// Number of pings sent since the last pong was received.
+    missed_pongs: u32,
+    /// When each ping not yet answered was sent, oldest first.
+    pings_sent: VecDeque<Instant>,
+    /// When a data message, e.g. a sync, was last received from this peer.
+    /// Pings, pongs and other bookkeeping don't count.
+    last_seen: Instant,
+    /// Whether this connection was made with `AddPeer`, rather than accepted.
+    outgoing: bool,
+    /// Whether changes from this peer are rejected.
+    read_only: bool,
+    presence: Option<Presence>,
+    /// Documents this peer is sent updates for, or every document if it
+    /// hasn't subscribed to any.
+    subscriptions: Option<HashSet<String>>,
+    /// Version of each document this peer is known to have, from its
+    /// `Versions` onwards. `None` until they arrive.
+    versions: Option<HashMap<String, VersionVector>>,
+    /// Sequence number of the next sync of each document sent to this peer.
+    sent_syncs: HashMap<String, u64>,
+    /// Syncs of each document received from this peer, to apply them in
+    /// order.
+    received_syncs: HashMap<String, ReceivedSyncs>,
+    /// Challenge sent to this peer in our hello, until it proves it knows the
+    /// pre-shared key. Nothing but the proof is accepted until then.
+    psk_nonce: Option<[u8; 32]>,
+}
+
+/// Progress through the syncs of one document received from a peer.
+#[derive(Default)]
+struct ReceivedSyncs {
+    /// Sequence number of the next sync to apply.
+    next: u64,
+    /// Syncs that arrived before the next one, by sequence number.
+    held: BTreeMap<u64, Vec<u8>>,
+}
+
+impl PeerInfo {
+    /// Notes that the peer has the operations in `vv`, so they needn't be
+    /// sent back to it.
+    fn record_version(&mut self, document_id: &str, vv: &VersionVector) {
+        if let Some(versions) = self.versions.as_mut() {
+            versions
+                .entry(document_id.to_owned())
+                .or_default()
+                .merge(vv);
+        }
+    }
+
+    /// Whether the peer has sent its hello and proven it knows the pre-shared
+    /// key, if one is required.
+    fn handshake_complete(&self) -> bool {
+        self.peer_id.is_some() && self.psk_nonce.is_none()
+    }
+
+    /// Wraps operations in a document exported for this peer in a sync,
+    /// numbering it after the last one sent.
+    fn sync_message(&mut self, document_id: &str, data: Vec<u8>) -> BackendMessage {
+        let seq = self.sent_syncs.entry(document_id.to_owned()).or_default();
+        let message = BackendMessage::document_sync(document_id, data, *seq);
+        *seq += 1;
+
+        message
+    }
+
+    /// Exports the operations in `doc` this peer isn't known to have yet.
+    /// Returns `None` if the peer is up to date or isn't sent updates for the
+    /// document.
+    ///
+    /// What the peer has only advances once it acknowledges the operations,
+    /// so anything it hasn't yet is sent again with the next delta.
+    fn take_delta(
+        &mut self,
+        document_id: &str,
+        doc: &LoroDoc,
+        snapshot_threshold: Option<usize>,
+    ) -> Option<Vec<u8>> {
+        if self
+            .subscriptions
+            .as_ref()
+            .is_some_and(|subscriptions| !subscriptions.contains(document_id))
+        {
+            return None;
+        }
+        let versions = self.versions.as_mut()?;
+        // Exporting commits pending changes, so commit them first for them to
+        // count towards the version compared against.
+        doc.commit();
+        let version = doc.oplog_vv();
+        // Documents the peer doesn't have are still sent when empty, so it
+        // knows they exist.
+        let known = match versions.get_mut(document_id) {
+            Some(known) if known.includes_vv(&version) => return None,
+            Some(known) => known,
+            None => versions.entry(document_id.to_owned()).or_default(),
+        };
+        let data = if known.is_empty() {
+            export_all(doc, snapshot_threshold)
+        } else {
+            doc.export_from(known)
+        };
+
+        Some(data)
     }
 }
 
+/// State of a document whose local changes are kept from peers until sync is
+/// resumed.
+#[derive(Default)]
+struct PausedSync {
+    /// Whether updates from peers are held until sync is resumed as well,
+    /// rather than applied right away.
+    hold_incoming: bool,
+    /// Updates held from peers, along with the ID of the peer each came from.
+    held_updates: Vec<(PeerID, Vec<u8>)>,
+}
+
 struct DocumentInfo {
     sub_id: SubID,
+    /// Tracks local changes only, so remote edits are never undone.
+    undo_manager: UndoManager,
+    /// Whether local changes should be forwarded to the frontend, e.g. while
+    /// applying an undo.
+    emit_local: Arc<AtomicBool>,
+    /// Whether a debounced broadcast is waiting to be sent.
+    flush_pending: bool,
+    /// Set while local changes are kept from peers.
+    paused: Option<PausedSync>,
     // TODO Merge into HashMaps?
     cursor: Option<Cursor>,
     mark: Option<Cursor>,
     cursors: HashMap<PeerID, Cursor>,
     marks: HashMap<PeerID, Cursor>,
+    /// Range of each open comment, as last sent to the frontend.
+    comments: HashMap<String, (usize, usize)>,
 }
@@ -1,23 +1,53 @@
-use super::{BackendMessage, ClientMessage, ReadSocket, WriteSocket};
-use std::net::SocketAddr;
-use tokio::{net::TcpStream, sync::mpsc::Sender};
+use super::{transport::BoxedConnection, BackendMessage, ClientMessage, ReadSocket, WriteSocket};
+use tokio::sync::mpsc::Sender;
 
 pub enum MainTaskMessage {
-    NewConnection((TcpStream, SocketAddr)),
+    /// A connection accepted from the peer at the given address.
+    NewConnection((ReadSocket, WriteSocket), String),
     ClientMessage(ClientMessage),
-    BackendMessage(BackendMessage),
+    /// A message received from the peer at the given address.
+    BackendMessage(String, BackendMessage),
     DocumentChanged(String),
+    /// The connection to the peer at the given address was closed.
+    PeerDisconnected(String),
+    /// A new connection to the peer at the given address, added with
+    /// `AddPeer`, whether for the first time or after it dropped.
+    PeerConnected(String, (ReadSocket, WriteSocket)),
+    /// A byte stream to a peer made outside the client, such as an in-memory
+    /// pipe, under the given address. Whether it counts as made by this
+    /// client, rather than accepted, decides which side keeps the connection
+    /// if both connect to each other.
+    NewStream(String, BoxedConnection, bool),
+    /// The debounce window for the given document has passed.
+    FlushDocument(String),
+    /// Time to send peers the changes to every document waiting to be sent.
+    FlushAll,
+    /// Updates from peers have been applied to the given document since it
+    /// was last reported to the frontend.
+    ReportSync(String),
+    /// Time to ping peers and check for unresponsive ones.
+    Heartbeat,
+}
+
+pub enum IncomingMessage {
+    NewSocket(String, ReadSocket),
+    RemoveSocket(String),
 }
 
 pub enum OutgoingMessage {
-    BackendMessage(BackendMessage),
-    NewSocket(WriteSocket),
+    /// A message to be sent to the peer at the given address.
+    DirectMessage {
+        address: String,
+        message: BackendMessage,
+    },
+    NewSocket(String, WriteSocket),
+    RemoveSocket(String),
 }
 
 #[derive(Clone)]
 pub struct Channels {
     pub main_tx: Sender<MainTaskMessage>,
-    pub incoming_to_tx: Sender<ReadSocket>,
+    pub incoming_to_tx: Sender<IncomingMessage>,
     pub outgoing_tx: Sender<OutgoingMessage>,
     pub stdout_tx: Sender<ClientMessage>,
 }
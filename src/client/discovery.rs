@@ -0,0 +1,107 @@
+use super::ClientMessage;
+use color_eyre::{eyre::WrapErr, Result};
+use loro::PeerID;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{collections::HashMap, net::SocketAddr};
+use tokio::{sync::mpsc::Sender, task::JoinHandle};
+use tracing::{error, info};
+
+/// mDNS service type advertised by every client.
+const SERVICE_TYPE: &str = "_c3edit._tcp.local.";
+
+/// Advertises this client on the local network, and looks for other clients
+/// doing the same.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    task: JoinHandle<()>,
+}
+
+impl Discovery {
+    /// Advertises the listener on `port` under `name`, reporting other clients
+    /// to the frontend with `PeerDiscovered` as they're found.
+    pub fn start(
+        name: &str,
+        port: u16,
+        peer_id: PeerID,
+        stdout_tx: Sender<ClientMessage>,
+    ) -> Result<Self> {
+        let daemon = ServiceDaemon::new().wrap_err("Failed to start mDNS daemon")?;
+
+        // The peer ID keeps clients advertised under the same name apart.
+        let instance = format!("{name}-{peer_id:x}");
+        let properties = HashMap::from([
+            ("name".to_owned(), name.to_owned()),
+            ("peer_id".to_owned(), peer_id.to_string()),
+        ]);
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &format!("{instance}.local."),
+            "",
+            port,
+            properties,
+        )
+        .wrap_err("Invalid service name")?
+        .enable_addr_auto();
+        daemon
+            .register(service)
+            .wrap_err("Failed to advertise service")?;
+        let events = daemon
+            .browse(SERVICE_TYPE)
+            .wrap_err("Failed to browse for peers")?;
+
+        let own_id = peer_id.to_string();
+        let task = tokio::spawn(async move {
+            // Services are resolved again whenever their records are
+            // refreshed, so only report new or moved ones.
+            let mut reported = HashMap::new();
+
+            while let Ok(event) = events.recv_async().await {
+                let ServiceEvent::ServiceResolved(service) = event else {
+                    continue;
+                };
+                if service.get_property_val_str("peer_id") == Some(own_id.as_str()) {
+                    continue;
+                }
+                // Prefer addresses reachable from other machines, and IPv4,
+                // since IPv6 link-local addresses are unusable without a
+                // scope.
+                let Some(ip) = service
+                    .get_addresses()
+                    .iter()
+                    .map(|ip| ip.to_ip_addr())
+                    .min_by_key(|ip| (ip.is_loopback(), ip.is_ipv6()))
+                else {
+                    continue;
+                };
+
+                let address = SocketAddr::new(ip, service.get_port()).to_string();
+                if reported.get(service.get_fullname()) == Some(&address) {
+                    continue;
+                }
+                reported.insert(service.get_fullname().to_owned(), address.clone());
+
+                let name = service
+                    .get_property_val_str("name")
+                    .unwrap_or(service.get_fullname())
+                    .to_owned();
+                info!("Discovered peer {} at {}", name, address);
+
+                let message = ClientMessage::PeerDiscovered { name, address };
+                if stdout_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Discovery { daemon, task })
+    }
+
+    /// Stops advertising and looking for peers.
+    pub fn stop(self) {
+        self.task.abort();
+        if let Err(e) = self.daemon.shutdown() {
+            error!("Failed to stop mDNS daemon: {}", e);
+        }
+    }
+}
@@ -0,0 +1,503 @@
+use super::{channels::MainTaskMessage, default_container, Change, ClientMessage};
+use color_eyre::{eyre::eyre, Result};
+use futures::{stream, Stream, StreamExt};
+use std::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc::{Receiver, Sender},
+};
+
+/// Address prefix given to peers connected with [`connect_in_memory`].
+const MEMORY_PREFIX: &str = "memory:";
+
+/// Buffer size of each direction of an in-memory connection.
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Drives a running [`Client`](super::Client) from Rust, in place of messages
+/// from the frontend on stdin.
+///
+/// Requests are queued for the client's event loop, so these methods only fail
+/// once the client has exited. Their results, including any errors, arrive
+/// through the client's [`ClientEvents`].
+#[derive(Clone)]
+pub struct ClientHandle {
+    tx: Sender<MainTaskMessage>,
+}
+
+impl ClientHandle {
+    pub(super) fn new(tx: Sender<MainTaskMessage>) -> Self {
+        ClientHandle { tx }
+    }
+
+    /// Queues any message the frontend could send.
+    pub async fn send(&self, message: ClientMessage) -> Result<()> {
+        self.tx
+            .send(MainTaskMessage::ClientMessage(message))
+            .await
+            .map_err(|_| eyre!("Client has exited"))
+    }
+
+    /// Connects to the peer at `address`. Answered with `AddPeerResponse`.
+    pub async fn add_peer(&self, address: impl Into<String>) -> Result<()> {
+        self.send(ClientMessage::AddPeer {
+            address: address.into(),
+        })
+        .await
+    }
+
+    /// Creates a document, empty unless `initial_content` is given, and opens
+    /// it. Answered with `DocumentCreated`.
+    pub async fn create_document(
+        &self,
+        name: impl Into<String>,
+        initial_content: Option<String>,
+    ) -> Result<()> {
+        self.send(ClientMessage::CreateDocument {
+            name: name.into(),
+            initial_content,
+            container: default_container(),
+            document_id: None,
+        })
+        .await
+    }
+
+    /// Applies a local change to the main text container of an open document.
+    pub async fn apply_change(&self, document_id: impl Into<String>, change: Change) -> Result<()> {
+        self.send(ClientMessage::Change {
+            document_id: document_id.into(),
+            container: default_container(),
+            change,
+            origin: None,
+            timestamp: None,
+            message: None,
+            new_length: None,
+        })
+        .await
+    }
+
+    /// Adds a peer connected over `connection`, any byte stream the caller has
+    /// already set up, which the peer knows this client by `address`. Exactly
+    /// one side of each connection should pass `outgoing`.
+    pub async fn add_connection(
+        &self,
+        address: impl Into<String>,
+        connection: impl AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+        outgoing: bool,
+    ) -> Result<()> {
+        self.tx
+            .send(MainTaskMessage::NewStream(
+                address.into(),
+                Box::new(connection),
+                outgoing,
+            ))
+            .await
+            .map_err(|_| eyre!("Client has exited"))
+    }
+
+    /// Stops the client once pending changes have been sent to peers.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.send(ClientMessage::Shutdown).await
+    }
+}
+
+/// Connects two clients to each other through an in-memory pipe, without any
+/// sockets, e.g. for tests. Each sees the other at a `memory:` address, and
+/// both answer with `AddPeerResponse` once the handshake completes.
+pub async fn connect_in_memory(a: &ClientHandle, b: &ClientHandle) -> Result<()> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let address = format!("{MEMORY_PREFIX}{}", NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let (a_end, b_end) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+
+    a.add_connection(address.clone(), a_end, true).await?;
+    b.add_connection(address, b_end, false).await
+}
+
+/// Messages from a [`Client`](super::Client) that would otherwise be written
+/// to stdout for the frontend.
+///
+/// Ends once the client has shut down. The client stalls while this is full,
+/// so it should be polled continuously.
+pub struct ClientEvents {
+    rx: Receiver<ClientMessage>,
+}
+
+impl ClientEvents {
+    pub(super) fn new(rx: Receiver<ClientMessage>) -> Self {
+        ClientEvents { rx }
+    }
+
+    /// Keeps only changes to apply to open documents, as `(document_id,
+    /// container, change)`, splitting batches back into individual changes.
+    pub fn subscribe_changes(self) -> impl Stream<Item = (String, String, Change)> {
+        self.flat_map(|message| {
+            let changes = match message {
+                ClientMessage::Change {
+                    document_id,
+                    container,
+                    change,
+                    ..
+                } => vec![(document_id, container, change)],
+                ClientMessage::ChangeBatch {
+                    document_id,
+                    container,
+                    changes,
+                    ..
+                } => changes
+                    .into_iter()
+                    .map(|change| (document_id.clone(), container.clone(), change))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            stream::iter(changes)
+        })
+    }
+}
+
+impl Stream for ClientEvents {
+    type Item = ClientMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::BackendMessage;
+    use crate::client::{
+        harness::{Cluster, TestClient},
+        ClientConfig,
+    };
+    use futures::SinkExt;
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize},
+            Arc,
+        },
+        task::ready,
+        time::Duration,
+    };
+    use tokio::{
+        io::{DuplexStream, ReadBuf, ReadHalf, WriteHalf},
+        time::timeout,
+    };
+    use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+    /// Switches for making writes to a [`FaultyWrites`] misbehave.
+    #[derive(Clone, Default)]
+    struct Faults {
+        /// Writes fail, as if the connection were half-open.
+        broken: Arc<AtomicBool>,
+        /// Writes never finish, as if the peer had stopped reading.
+        stalled: Arc<AtomicBool>,
+    }
+
+    /// One end of an in-memory connection whose writes can be made to
+    /// misbehave.
+    struct FaultyWrites {
+        inner: DuplexStream,
+        faults: Faults,
+    }
+
+    impl FaultyWrites {
+        fn check(&self) -> Poll<io::Result<()>> {
+            if self.faults.broken.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if self.faults.stalled.load(Ordering::Relaxed) {
+                return Poll::Pending;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncRead for FaultyWrites {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for FaultyWrites {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            ready!(self.check())?;
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            ready!(self.check())?;
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_clients_converge() {
+        let mut a = TestClient::spawn(ClientConfig::default()).await;
+        let mut b = TestClient::spawn(ClientConfig::default()).await;
+        connect_in_memory(&a.handle, &b.handle).await.unwrap();
+        for client in [&mut a, &mut b] {
+            let address = client
+                .expect(|message| match message {
+                    ClientMessage::AddPeerResponse { address } => Some(address),
+                    _ => None,
+                })
+                .await;
+            assert!(address.starts_with(MEMORY_PREFIX), "{address}");
+        }
+
+        let mut cluster = Cluster {
+            clients: vec![a, b],
+        };
+        let document_id = cluster.create_document(0, "notes", "hello").await;
+        cluster
+            .edit(
+                1,
+                &document_id,
+                Change::Insert {
+                    index: 5,
+                    text: " world".to_owned(),
+                },
+            )
+            .await;
+        assert_eq!(cluster.converge(&document_id).await, "hello world");
+        cluster.shutdown().await;
+    }
+
+    /// Starts three clients, the first configured with `config`, connecting
+    /// the first to the others in memory. Client 0's
+    /// connection to client 1 is a [`FaultyWrites`], whose address and
+    /// switches are returned.
+    async fn cluster_with_faulty_peer(config: ClientConfig) -> (Cluster, String, Faults) {
+        let mut clients = vec![TestClient::spawn(config).await];
+        for _ in 0..2 {
+            clients.push(TestClient::spawn(ClientConfig::default()).await);
+        }
+        let address = format!("{MEMORY_PREFIX}faulty");
+        let (a_end, b_end) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+        let faults = Faults::default();
+        let a_end = FaultyWrites {
+            inner: a_end,
+            faults: faults.clone(),
+        };
+        clients[0]
+            .handle
+            .add_connection(address.clone(), a_end, true)
+            .await
+            .unwrap();
+        clients[1]
+            .handle
+            .add_connection(address.clone(), b_end, false)
+            .await
+            .unwrap();
+        connect_in_memory(&clients[0].handle, &clients[2].handle)
+            .await
+            .unwrap();
+        for client in &mut clients {
+            client
+                .expect(|message| match message {
+                    ClientMessage::AddPeerResponse { .. } => Some(()),
+                    _ => None,
+                })
+                .await;
+        }
+
+        (Cluster { clients }, address, faults)
+    }
+
+    #[tokio::test]
+    async fn failed_writes_drop_only_that_peer() {
+        let (mut cluster, address, faults) =
+            cluster_with_faulty_peer(ClientConfig::default()).await;
+        let document_id = cluster.create_document(0, "notes", "hello").await;
+
+        faults.broken.store(true, Ordering::Relaxed);
+        cluster
+            .edit(
+                0,
+                &document_id,
+                Change::Append {
+                    text: "!".to_owned(),
+                },
+            )
+            .await;
+        let client = &mut cluster.clients[0];
+        let context = client
+            .recv(|message| match message {
+                ClientMessage::Error { context, .. } => Some(context),
+                _ => None,
+            })
+            .await;
+        assert!(context.contains(&address), "{context}");
+        let dropped = client
+            .expect(|message| match message {
+                ClientMessage::PeerDisconnected { address } => Some(address),
+                _ => None,
+            })
+            .await;
+        assert_eq!(dropped, address);
+
+        // The other peer is still kept up to date.
+        let stale = cluster.clients.remove(1);
+        cluster
+            .edit(
+                0,
+                &document_id,
+                Change::Append {
+                    text: "?".to_owned(),
+                },
+            )
+            .await;
+        assert_eq!(cluster.converge(&document_id).await, "hello!?");
+        stale.handle.shutdown().await.unwrap();
+        cluster.shutdown().await;
+    }
+
+    /// Passes frames from `from` to `to`, swapping each pair of document
+    /// syncs if `swaps` is given, and counting how many pairs it swapped.
+    async fn forward(
+        from: ReadHalf<DuplexStream>,
+        to: WriteHalf<DuplexStream>,
+        swaps: Option<Arc<AtomicUsize>>,
+    ) {
+        let mut from = FramedRead::new(from, LengthDelimitedCodec::new());
+        let mut to = FramedWrite::new(to, LengthDelimitedCodec::new());
+        let mut held = None;
+        loop {
+            let frame = match held {
+                // Don't hold a sync forever if no other follows it.
+                Some(_) => match timeout(Duration::from_millis(50), from.next()).await {
+                    Ok(frame) => frame,
+                    Err(_) => {
+                        let _ = to.send(held.take().unwrap()).await;
+                        continue;
+                    }
+                },
+                None => from.next().await,
+            };
+            let Some(Ok(frame)) = frame else {
+                return;
+            };
+            let frame = frame.freeze();
+            let is_sync = matches!(
+                serde_json::from_slice(&frame),
+                Ok(BackendMessage::DocumentSync { .. })
+            );
+            let result = match (&swaps, is_sync, held.take()) {
+                (Some(swaps), true, Some(first)) => {
+                    swaps.fetch_add(1, Ordering::Relaxed);
+                    to.send(frame).await.and(to.send(first).await)
+                }
+                (Some(_), true, None) => {
+                    held = Some(frame);
+                    Ok(())
+                }
+                (_, _, first) => {
+                    held = first;
+                    to.send(frame).await
+                }
+            };
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reordered_syncs_apply_in_order() {
+        let mut a = TestClient::spawn(ClientConfig::default()).await;
+        let mut b = TestClient::spawn(ClientConfig::default()).await;
+        let (a_end, a_proxy) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+        let (b_end, b_proxy) = tokio::io::duplex(MEMORY_BUFFER_SIZE);
+        let (a_read, a_write) = tokio::io::split(a_proxy);
+        let (b_read, b_write) = tokio::io::split(b_proxy);
+        let swaps = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(forward(a_read, b_write, Some(swaps.clone())));
+        tokio::spawn(forward(b_read, a_write, None));
+
+        let address = format!("{MEMORY_PREFIX}reordered");
+        a.handle
+            .add_connection(address.clone(), a_end, true)
+            .await
+            .unwrap();
+        b.handle
+            .add_connection(address, b_end, false)
+            .await
+            .unwrap();
+        for client in [&mut a, &mut b] {
+            client
+                .expect(|message| match message {
+                    ClientMessage::AddPeerResponse { .. } => Some(()),
+                    _ => None,
+                })
+                .await;
+        }
+
+        let mut cluster = Cluster {
+            clients: vec![a, b],
+        };
+        let document_id = cluster.create_document(0, "notes", "").await;
+        for i in 0..20 {
+            let text = char::from(b'a' + i).to_string();
+            cluster.edit(0, &document_id, Change::Append { text }).await;
+        }
+        let text = cluster.converge(&document_id).await;
+        assert_eq!(text, "abcdefghijklmnopqrst");
+        assert!(swaps.load(Ordering::Relaxed) > 0);
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn slow_peers_are_dropped_without_stalling_others() {
+        let config = ClientConfig {
+            peer_queue_capacity: 8,
+            ..ClientConfig::default()
+        };
+        let (mut cluster, address, faults) = cluster_with_faulty_peer(config).await;
+        let document_id = cluster.create_document(0, "notes", "").await;
+
+        // Edits come slowly enough for the other peer to keep up.
+        faults.stalled.store(true, Ordering::Relaxed);
+        for i in 0..20 {
+            let text = char::from(b'a' + i).to_string();
+            cluster.edit(0, &document_id, Change::Append { text }).await;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let client = &mut cluster.clients[0];
+        let message = client
+            .recv(|message| match message {
+                ClientMessage::Error { message, .. } => Some(message),
+                _ => None,
+            })
+            .await;
+        assert!(message.contains("fell behind"), "{message}");
+        let dropped = client
+            .expect(|message| match message {
+                ClientMessage::PeerDisconnected { address } => Some(address),
+                _ => None,
+            })
+            .await;
+        assert_eq!(dropped, address);
+
+        let slow = cluster.clients.remove(1);
+        assert_eq!(cluster.converge(&document_id).await, "abcdefghijklmnopqrst");
+        slow.handle.shutdown().await.unwrap();
+        cluster.shutdown().await;
+    }
+}
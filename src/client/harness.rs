@@ -0,0 +1,1251 @@
+//! Runs several clients in one process, connected over loopback, to check
+//! that their documents converge.
+
+use super::{Change, ClientBuilder, ClientConfig, ClientHandle, ClientMessage};
+use futures::StreamExt;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::{sleep, timeout, Instant},
+};
+
+/// How long to wait for any one response, or for documents to converge.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How clients are connected to each other.
+#[derive(Debug, Clone, Copy)]
+pub enum Topology {
+    /// Each client connects to the next, and the last to the first.
+    Ring,
+    /// Every other client connects to the first.
+    Star,
+    /// Every client connects to every other.
+    Mesh,
+}
+
+impl Topology {
+    /// Pairs of clients to connect among `n`, as `(dialer, listener)`.
+    fn edges(self, n: usize) -> Vec<(usize, usize)> {
+        match self {
+            // Two clients in a ring would connect to each other twice.
+            Topology::Ring if n <= 2 => Topology::Mesh.edges(n),
+            Topology::Ring => (0..n).map(|i| (i, (i + 1) % n)).collect(),
+            Topology::Star => (1..n).map(|i| (i, 0)).collect(),
+            Topology::Mesh => (0..n)
+                .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+                .collect(),
+        }
+    }
+}
+
+/// A client running in the background, driven through its handle.
+pub struct TestClient {
+    pub handle: ClientHandle,
+    pub addr: SocketAddr,
+    /// Everything the client has sent to the frontend, which is drained as
+    /// it arrives so the client never stalls.
+    events: UnboundedReceiver<ClientMessage>,
+}
+
+impl TestClient {
+    /// Starts a client listening on a free loopback port.
+    pub async fn spawn(config: ClientConfig) -> TestClient {
+        let builder = ClientBuilder::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .config(config);
+        TestClient::from_builder(builder)
+    }
+
+    /// Starts a client built by `builder`, e.g. with extra listeners.
+    pub fn from_builder(builder: ClientBuilder) -> TestClient {
+        let addr = builder.local_addr().unwrap();
+        let (client, handle, mut client_events) = builder.build_embedded();
+        tokio::spawn(client.begin_event_loop());
+
+        let (tx, events) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(message) = client_events.next().await {
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        TestClient {
+            handle,
+            addr,
+            events,
+        }
+    }
+
+    /// Connects to the peer at `address`, waiting for the handshake to
+    /// complete.
+    pub async fn connect(&mut self, address: &str) {
+        self.handle.add_peer(address.to_owned()).await.unwrap();
+        self.expect(|message| match message {
+            ClientMessage::AddPeerResponse { address: added } if added == address => Some(()),
+            _ => None,
+        })
+        .await;
+    }
+
+    /// Waits for the first message `pick` returns something for, skipping
+    /// the rest.
+    pub async fn recv<T>(&mut self, mut pick: impl FnMut(ClientMessage) -> Option<T>) -> T {
+        let wait = async {
+            loop {
+                let message = self.events.recv().await.expect("Client exited");
+                if let Some(picked) = pick(message) {
+                    return picked;
+                }
+            }
+        };
+        timeout(TIMEOUT, wait)
+            .await
+            .expect("Timed out waiting for client")
+    }
+
+    /// Like [`Self::recv`], but panics if the client reports an error first.
+    pub async fn expect<T>(&mut self, mut pick: impl FnMut(ClientMessage) -> Option<T>) -> T {
+        self.recv(|message| match message {
+            ClientMessage::Error { message, context } => {
+                panic!("Client failed while {context}: {message}")
+            }
+            message => pick(message),
+        })
+        .await
+    }
+
+    /// Returns the main text of a document, or `None` if the client doesn't
+    /// have it yet.
+    pub async fn text(&mut self, document_id: &str) -> Option<String> {
+        self.handle
+            .send(ClientMessage::GetText {
+                document_id: document_id.to_owned(),
+                container: super::default_container(),
+            })
+            .await
+            .unwrap();
+        self.recv(|message| match message {
+            ClientMessage::Text { content, .. } => Some(Some(content)),
+            ClientMessage::Error { message, .. } if message.contains("No such document") => {
+                Some(None)
+            }
+            ClientMessage::Error { message, context } => {
+                panic!("Client failed while {context}: {message}")
+            }
+            _ => None,
+        })
+        .await
+    }
+}
+
+/// Several clients connected in some topology.
+pub struct Cluster {
+    pub clients: Vec<TestClient>,
+}
+
+impl Cluster {
+    /// Starts `n` clients with the default configuration and connects them.
+    pub async fn new(n: usize, topology: Topology) -> Cluster {
+        Cluster::with_configs(vec![ClientConfig::default(); n], topology).await
+    }
+
+    /// Starts a client with each of `configs` and connects them, returning
+    /// once every connection has completed its handshake.
+    pub async fn with_configs(configs: Vec<ClientConfig>, topology: Topology) -> Cluster {
+        let n = configs.len();
+        let mut clients = Vec::new();
+        for config in configs {
+            clients.push(TestClient::spawn(config).await);
+        }
+
+        for (dialer, listener) in topology.edges(n) {
+            let address = clients[listener].addr.to_string();
+            clients[dialer].connect(&address).await;
+        }
+
+        Cluster { clients }
+    }
+
+    /// Creates a document on client `creator`, then waits for every other
+    /// client to receive it and opens it there too. Returns its ID.
+    pub async fn create_document(&mut self, creator: usize, name: &str, content: &str) -> String {
+        let client = &mut self.clients[creator];
+        client
+            .handle
+            .create_document(name, Some(content.to_owned()))
+            .await
+            .unwrap();
+        let document_id = client
+            .expect(|message| match message {
+                ClientMessage::DocumentCreated { document_id } => Some(document_id),
+                _ => None,
+            })
+            .await;
+
+        for (i, client) in self.clients.iter_mut().enumerate() {
+            if i == creator {
+                continue;
+            }
+            let deadline = Instant::now() + TIMEOUT;
+            while client.text(&document_id).await.is_none() {
+                assert!(
+                    Instant::now() < deadline,
+                    "Client {i} never received {document_id}"
+                );
+                sleep(Duration::from_millis(10)).await;
+            }
+            client
+                .handle
+                .send(ClientMessage::JoinDocument {
+                    id: document_id.clone(),
+                })
+                .await
+                .unwrap();
+            client
+                .expect(|message| match message {
+                    ClientMessage::JoinDocumentResponse { .. } => Some(()),
+                    _ => None,
+                })
+                .await;
+        }
+
+        document_id
+    }
+
+    /// Applies `change` on client `editor`.
+    pub async fn edit(&self, editor: usize, document_id: &str, change: Change) {
+        self.clients[editor]
+            .handle
+            .apply_change(document_id, change)
+            .await
+            .unwrap();
+    }
+
+    /// Waits for every client to have the same text in the document,
+    /// returning it. Panics with each client's text if they never agree.
+    pub async fn converge(&mut self, document_id: &str) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let mut texts = Vec::new();
+            for client in &mut self.clients {
+                texts.push(client.text(document_id).await);
+            }
+            if let Some(Some(first)) = texts.first() {
+                if texts.iter().all(|text| text.as_ref() == Some(first)) {
+                    return first.clone();
+                }
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Clients never converged on {document_id}: {texts:#?}"
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Stops every client.
+    pub async fn shutdown(self) {
+        for client in &self.clients {
+            client.handle.shutdown().await.unwrap();
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::client::{
+        transport::WEBSOCKET_PREFIX, utils::metadata_entries, ChangeOrigin, IndexMode, TlsConfig,
+        WireFormat,
+    };
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use loro::LoroDoc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn insert(index: usize, text: &str) -> Change {
+        Change::Insert {
+            index,
+            text: text.to_owned(),
+        }
+    }
+
+    /// Has each of `editors` edit the document at once, then checks every
+    /// client ends up with every edit.
+    async fn check_concurrent_edits(cluster: &mut Cluster, editors: &[usize]) {
+        let document_id = cluster.create_document(editors[0], "notes", "base").await;
+
+        for &i in editors {
+            cluster
+                .edit(i, &document_id, insert(0, &format!("[{i}]")))
+                .await;
+        }
+        let text = cluster.converge(&document_id).await;
+
+        assert!(text.ends_with("base"), "{text}");
+        for &i in editors {
+            assert!(text.contains(&format!("[{i}]")), "{text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn mesh_converges() {
+        let mut cluster = Cluster::new(4, Topology::Mesh).await;
+        check_concurrent_edits(&mut cluster, &[0, 1, 2, 3]).await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn message_pack_peers_converge() {
+        // Only the first two agree on MessagePack, so the third is sent JSON.
+        let mut configs = vec![ClientConfig::default(); 3];
+        for config in &mut configs[..2] {
+            config.wire_format = WireFormat::MessagePack;
+        }
+        let mut cluster = Cluster::with_configs(configs, Topology::Mesh).await;
+        check_concurrent_edits(&mut cluster, &[0, 1, 2]).await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn ring_converges() {
+        let mut cluster = Cluster::new(3, Topology::Ring).await;
+        check_concurrent_edits(&mut cluster, &[0, 1, 2]).await;
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn star_converges_once_hub_edits() {
+        let mut cluster = Cluster::new(4, Topology::Star).await;
+        let document_id = cluster.create_document(0, "notes", "").await;
+
+        for i in 1..4 {
+            cluster
+                .edit(i, &document_id, insert(0, &format!("[{i}]")))
+                .await;
+        }
+        // Leaves only reach each other through the hub, which passes on what
+        // it has received along with its own edits.
+        let deadline = Instant::now() + TIMEOUT;
+        while cluster.clients[0]
+            .text(&document_id)
+            .await
+            .map(|text| text.len())
+            != Some(9)
+        {
+            assert!(Instant::now() < deadline, "Hub never received every edit");
+            sleep(Duration::from_millis(10)).await;
+        }
+        cluster.edit(0, &document_id, insert(0, "[0]")).await;
+        let text = cluster.converge(&document_id).await;
+
+        for i in 0..4 {
+            assert!(text.contains(&format!("[{i}]")), "{text}");
+        }
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn sequential_edits_converge() {
+        let mut cluster = Cluster::new(3, Topology::Mesh).await;
+        let document_id = cluster.create_document(1, "notes", "").await;
+
+        cluster.edit(0, &document_id, insert(0, "hello")).await;
+        assert_eq!(cluster.converge(&document_id).await, "hello");
+        cluster.edit(2, &document_id, insert(5, " world")).await;
+        assert_eq!(cluster.converge(&document_id).await, "hello world");
+        cluster
+            .edit(1, &document_id, Change::Delete { index: 0, len: 6 })
+            .await;
+        assert_eq!(cluster.converge(&document_id).await, "world");
+        cluster.shutdown().await;
+    }
+
+    /// Asks client `i` whether it has edits to the document no peer has
+    /// acknowledged.
+    async fn unsynced(cluster: &mut Cluster, i: usize, document_id: &str) -> bool {
+        let client = &mut cluster.clients[i];
+        client
+            .handle
+            .send(ClientMessage::HasUnsyncedChanges {
+                document_id: document_id.to_owned(),
+            })
+            .await
+            .unwrap();
+        client
+            .expect(|message| match message {
+                ClientMessage::UnsyncedChanges { unsynced, .. } => Some(unsynced),
+                _ => None,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn lagging_peer_converges() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "").await;
+        cluster.edit(0, &document_id, insert(0, "a")).await;
+        cluster.converge(&document_id).await;
+
+        // Client 1 holds everything it's sent without acknowledging it, so
+        // client 0 has to keep sending it.
+        cluster.clients[1]
+            .handle
+            .send(ClientMessage::PauseSync {
+                document_id: document_id.clone(),
+                hold_incoming: true,
+            })
+            .await
+            .unwrap();
+        for text in ["b", "c", "d"] {
+            cluster.edit(0, &document_id, insert(0, text)).await;
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert!(unsynced(&mut cluster, 0, &document_id).await);
+        let lagging = cluster.clients[1].text(&document_id).await;
+        assert_eq!(lagging.as_deref(), Some("a"));
+
+        cluster.clients[1]
+            .handle
+            .send(ClientMessage::ResumeSync {
+                document_id: document_id.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(cluster.converge(&document_id).await, "dcba");
+        cluster.edit(0, &document_id, insert(4, "e")).await;
+        assert_eq!(cluster.converge(&document_id).await, "dcbae");
+
+        let deadline = Instant::now() + TIMEOUT;
+        while unsynced(&mut cluster, 0, &document_id).await {
+            assert!(Instant::now() < deadline, "Edits were never acknowledged");
+            sleep(Duration::from_millis(10)).await;
+        }
+        cluster.shutdown().await;
+    }
+
+    /// Binds a listener on a free loopback port.
+    async fn loopback_listener() -> TcpListener {
+        TcpListener::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn tcp_and_websocket_listeners_accept_together() {
+        let extra = loopback_listener().await;
+        let websocket = loopback_listener().await;
+        let extra_addr = extra.local_addr().unwrap();
+        let websocket_addr = websocket.local_addr().unwrap();
+        // The hub passes updates between the others as soon as it has them.
+        let config = ClientConfig {
+            relay: true,
+            ..ClientConfig::default()
+        };
+        let builder = ClientBuilder::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .config(config)
+            .tcp_listener(extra)
+            .websocket_listener(websocket);
+        let hub = TestClient::from_builder(builder);
+
+        let mut tcp = TestClient::spawn(ClientConfig::default()).await;
+        let mut extra_tcp = TestClient::spawn(ClientConfig::default()).await;
+        let mut ws = TestClient::spawn(ClientConfig::default()).await;
+        tcp.connect(&hub.addr.to_string()).await;
+        extra_tcp.connect(&extra_addr.to_string()).await;
+        ws.connect(&format!("{WEBSOCKET_PREFIX}{websocket_addr}"))
+            .await;
+
+        let mut cluster = Cluster {
+            clients: vec![hub, tcp, extra_tcp, ws],
+        };
+        let document_id = cluster.create_document(1, "notes", "").await;
+        for i in 1..4 {
+            cluster
+                .edit(i, &document_id, insert(0, &format!("[{i}]")))
+                .await;
+        }
+        let text = cluster.converge(&document_id).await;
+        for i in 1..4 {
+            assert!(text.contains(&format!("[{i}]")), "{text}");
+        }
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn websocket_peers_converge() {
+        let websocket = loopback_listener().await;
+        let websocket_addr = websocket.local_addr().unwrap();
+        let builder = ClientBuilder::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .websocket_listener(websocket);
+        let listener = TestClient::from_builder(builder);
+        let mut dialer = TestClient::spawn(ClientConfig::default()).await;
+        dialer
+            .connect(&format!("{WEBSOCKET_PREFIX}{websocket_addr}"))
+            .await;
+
+        let mut cluster = Cluster {
+            clients: vec![listener, dialer],
+        };
+        check_concurrent_edits(&mut cluster, &[0, 1]).await;
+        cluster.shutdown().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_peers_converge() {
+        let path = std::env::temp_dir().join(format!("c3edit-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = tokio::net::UnixListener::bind(&path).unwrap();
+        let builder = ClientBuilder::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .unix_listener(socket);
+        let listener = TestClient::from_builder(builder);
+        let mut dialer = TestClient::spawn(ClientConfig::default()).await;
+        // Paths are told apart from TCP addresses by their leading `/`.
+        dialer.connect(path.to_str().unwrap()).await;
+
+        let mut cluster = Cluster {
+            clients: vec![listener, dialer],
+        };
+        check_concurrent_edits(&mut cluster, &[0, 1]).await;
+        cluster.shutdown().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Asks client `i` to close the document, returning the error if it
+    /// refuses.
+    async fn close(
+        cluster: &mut Cluster,
+        i: usize,
+        document_id: &str,
+        force: bool,
+    ) -> Result<(), String> {
+        let client = &mut cluster.clients[i];
+        client
+            .handle
+            .send(ClientMessage::CloseDocument {
+                document_id: document_id.to_owned(),
+                path: None,
+                force,
+            })
+            .await
+            .unwrap();
+        client
+            .recv(|message| match message {
+                ClientMessage::DocumentClosed { .. } => Some(Ok(())),
+                ClientMessage::Error { message, .. } => Some(Err(message)),
+                _ => None,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn closing_requires_acknowledged_changes() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "hello").await;
+        cluster.converge(&document_id).await;
+        let deadline = Instant::now() + TIMEOUT;
+        while unsynced(&mut cluster, 0, &document_id).await {
+            assert!(Instant::now() < deadline, "Edits were never acknowledged");
+            sleep(Duration::from_millis(10)).await;
+        }
+        close(&mut cluster, 0, &document_id, false).await.unwrap();
+
+        // Nothing has been acknowledged when no peer is around to do so.
+        let mut alone = Cluster::new(1, Topology::Mesh).await;
+        let document_id = alone.create_document(0, "notes", "hello").await;
+        let error = close(&mut alone, 0, &document_id, false).await.unwrap_err();
+        assert!(error.contains("acknowledged"), "{error}");
+        close(&mut alone, 0, &document_id, true).await.unwrap();
+        assert_eq!(alone.clients[0].text(&document_id).await, None);
+
+        cluster.shutdown().await;
+        alone.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn rejected_batch_changes_nothing() {
+        let mut cluster = Cluster::new(1, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "hello").await;
+        let batch = |changes| ClientMessage::ChangeBatch {
+            document_id: document_id.clone(),
+            container: crate::client::default_container(),
+            changes,
+            origin: None,
+            timestamp: None,
+            message: None,
+            new_length: None,
+        };
+        let mark = |len, key: &str| Change::Mark {
+            index: 0,
+            len,
+            key: key.to_owned(),
+            value: true.into(),
+        };
+
+        let client = &mut cluster.clients[0];
+        let changes = vec![insert(5, " world"), mark(5, "sparkly")];
+        client.handle.send(batch(changes)).await.unwrap();
+        client
+            .recv(|message| match message {
+                ClientMessage::Error { message, .. } => Some(message),
+                _ => None,
+            })
+            .await;
+        assert_eq!(client.text(&document_id).await.as_deref(), Some("hello"));
+
+        // Empty ranges are left alone rather than failing partway through.
+        let changes = vec![insert(5, " world"), mark(0, "bold")];
+        client.handle.send(batch(changes)).await.unwrap();
+        assert_eq!(cluster.converge(&document_id).await, "hello world");
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn clearing_reports_the_new_length() {
+        let mut cluster = Cluster::new(1, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "héllo").await;
+        let client = &mut cluster.clients[0];
+        client
+            .handle
+            .send(ClientMessage::ClearDocument {
+                document_id: document_id.clone(),
+                container: crate::client::default_container(),
+            })
+            .await
+            .unwrap();
+        let reported = client
+            .expect(|message| match message {
+                ClientMessage::Change {
+                    change: Change::Delete { index, len },
+                    new_length,
+                    ..
+                } => Some((index, len, new_length)),
+                _ => None,
+            })
+            .await;
+        assert_eq!(reported, (0, 5, Some(0)));
+        assert_eq!(cluster.converge(&document_id).await, "");
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn files_report_their_length() {
+        let path = std::env::temp_dir().join(format!("c3edit-{}.txt", std::process::id()));
+        std::fs::write(&path, "😀 hi").unwrap();
+        let mut client = TestClient::spawn(ClientConfig {
+            index_mode: IndexMode::Utf16,
+            ..ClientConfig::default()
+        })
+        .await;
+        client
+            .handle
+            .send(ClientMessage::CreateDocumentFromFile {
+                path: path.to_string_lossy().into_owned(),
+            })
+            .await
+            .unwrap();
+        let reported = client
+            .expect(|message| match message {
+                ClientMessage::Change {
+                    change: Change::Insert { text, .. },
+                    new_length,
+                    ..
+                } => Some((text, new_length)),
+                _ => None,
+            })
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reported, ("😀 hi".to_owned(), Some(5)));
+    }
+
+    #[tokio::test]
+    async fn comments_grow_with_insertions_inside_them() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "hello world").await;
+        let client = &mut cluster.clients[0];
+        client
+            .handle
+            .send(ClientMessage::AddComment {
+                document_id: document_id.clone(),
+                start: 0,
+                end: 5,
+                body: "greeting".to_owned(),
+            })
+            .await
+            .unwrap();
+        let moved = |message| match message {
+            ClientMessage::CommentMoved { start, end, .. } => Some((start, end)),
+            _ => None,
+        };
+        assert_eq!(client.expect(moved).await, (0, 5));
+
+        // Edits from peers move comments too.
+        cluster.edit(1, &document_id, insert(2, "XX")).await;
+        assert_eq!(cluster.clients[0].expect(moved).await, (0, 7));
+        cluster.edit(0, &document_id, insert(0, "> ")).await;
+        assert_eq!(cluster.clients[0].expect(moved).await, (2, 9));
+        cluster.edit(0, &document_id, insert(9, "!")).await;
+        cluster.edit(0, &document_id, insert(3, "Y")).await;
+        // Text inserted just after the comment stays out of it.
+        assert_eq!(cluster.clients[0].expect(moved).await, (2, 10));
+        assert_eq!(cluster.converge(&document_id).await, "> hYeXXllo! world");
+
+        // Deleting all of its text resolves the comment.
+        cluster
+            .edit(1, &document_id, Change::Delete { index: 2, len: 8 })
+            .await;
+        cluster.clients[0]
+            .expect(|message| match message {
+                ClientMessage::CommentResolved { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(cluster.converge(&document_id).await, "> ! world");
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn heartbeats_dont_keep_idle_peers_connected() {
+        let config = ClientConfig {
+            heartbeat_interval: Duration::from_millis(20),
+            ..ClientConfig::default()
+        };
+        let idle = ClientConfig {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..config.clone()
+        };
+        let mut cluster = Cluster::with_configs(vec![idle, config], Topology::Mesh).await;
+        let start = Instant::now();
+        cluster.clients[0]
+            .expect(|message| match message {
+                ClientMessage::PeerDisconnected { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_leaves_no_tasks_behind() {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let before = metrics.num_alive_tasks();
+
+        let mut cluster = Cluster::new(3, Topology::Mesh).await;
+        check_concurrent_edits(&mut cluster, &[0, 1, 2]).await;
+        assert!(metrics.num_alive_tasks() > before);
+        cluster.shutdown().await;
+
+        let deadline = Instant::now() + TIMEOUT;
+        while metrics.num_alive_tasks() > before {
+            assert!(
+                Instant::now() < deadline,
+                "{} tasks still running",
+                metrics.num_alive_tasks() - before
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn self_signed_tls_peers_converge() {
+        let fixture = |name| {
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(name)
+        };
+        // The certificate is its own CA.
+        let cert = fixture("localhost.crt");
+        let tls = TlsConfig::from_pem_files(&cert, &fixture("localhost.key"), &cert).unwrap();
+        let config = ClientConfig {
+            tls: Some(tls),
+            ..ClientConfig::default()
+        };
+
+        let mut cluster = Cluster::with_configs(vec![config; 2], Topology::Mesh).await;
+        check_concurrent_edits(&mut cluster, &[0, 1]).await;
+        cluster.shutdown().await;
+    }
+
+    /// Returns a copy of the document as client `i` has it.
+    async fn snapshot(cluster: &mut Cluster, i: usize, document_id: &str) -> LoroDoc {
+        let client = &mut cluster.clients[i];
+        client
+            .handle
+            .send(ClientMessage::ExportSnapshot {
+                document_id: document_id.to_owned(),
+            })
+            .await
+            .unwrap();
+        let data = client
+            .expect(|message| match message {
+                ClientMessage::Snapshot { data, .. } => Some(data),
+                _ => None,
+            })
+            .await;
+        let doc = LoroDoc::new();
+        doc.import(&BASE64_STANDARD.decode(data).unwrap()).unwrap();
+        doc
+    }
+
+    #[tokio::test]
+    async fn overlapping_bold_ranges_converge() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "hello world").await;
+        let bold = |index, len| Change::Mark {
+            index,
+            len,
+            key: "bold".to_owned(),
+            value: true.into(),
+        };
+        cluster.edit(0, &document_id, bold(0, 7)).await;
+        cluster.edit(1, &document_id, bold(4, 4)).await;
+        cluster.edit(1, &document_id, bold(9, 2)).await;
+
+        let expected = serde_json::json!([
+            { "insert": "hello wo", "attributes": { "bold": true } },
+            { "insert": "r" },
+            { "insert": "ld", "attributes": { "bold": true } },
+        ]);
+        let deadline = Instant::now() + TIMEOUT;
+        for i in 0..2 {
+            loop {
+                let doc = snapshot(&mut cluster, i, &document_id).await;
+                let text = doc.get_text(crate::client::default_container());
+                let delta = serde_json::to_value(text.to_delta()).unwrap();
+                if delta == expected {
+                    break;
+                }
+                assert!(Instant::now() < deadline, "Client {i} has {delta:#?}");
+                sleep(Duration::from_millis(10)).await;
+            }
+        }
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stopped_peers_are_reported_disconnected() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let stopped = cluster.clients.pop().unwrap();
+        stopped.handle.shutdown().await.unwrap();
+        let address = cluster.clients[0]
+            .expect(|message| match message {
+                ClientMessage::PeerDisconnected { address } => Some(address),
+                _ => None,
+            })
+            .await;
+        assert_eq!(address, stopped.addr.to_string());
+
+        let client = &mut cluster.clients[0];
+        client.handle.send(ClientMessage::ListPeers).await.unwrap();
+        let peers = client
+            .expect(|message| match message {
+                ClientMessage::PeerList { peers } => Some(peers),
+                _ => None,
+            })
+            .await;
+        assert!(peers.is_empty(), "{peers:?}");
+        cluster.shutdown().await;
+    }
+
+    /// Returns how many messages client `i` has sent to peers.
+    async fn messages_sent(cluster: &mut Cluster, i: usize) -> u64 {
+        let client = &mut cluster.clients[i];
+        client.handle.send(ClientMessage::GetStats).await.unwrap();
+        client
+            .expect(|message| match message {
+                ClientMessage::Stats { messages_sent, .. } => Some(messages_sent),
+                _ => None,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn ring_of_relays_settles() {
+        // Relays pass on every update at once, so any echo would go round
+        // the ring forever.
+        let config = ClientConfig {
+            relay: true,
+            ..ClientConfig::default()
+        };
+        let mut cluster = Cluster::with_configs(vec![config; 3], Topology::Ring).await;
+        check_concurrent_edits(&mut cluster, &[0, 1, 2]).await;
+
+        let mut sent = Vec::new();
+        for i in 0..3 {
+            sent.push(messages_sent(&mut cluster, i).await);
+        }
+        // Acknowledgements may still be in flight at first.
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            sleep(Duration::from_millis(200)).await;
+            let mut now = Vec::new();
+            for i in 0..3 {
+                now.push(messages_sent(&mut cluster, i).await);
+            }
+            if now == sent {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Still sending: {sent:?} -> {now:?}"
+            );
+            sent = now;
+        }
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn oversized_edits_are_refused() {
+        let small = ClientConfig {
+            max_document_size: 1024,
+            ..ClientConfig::default()
+        };
+        let mut cluster =
+            Cluster::with_configs(vec![ClientConfig::default(), small], Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "hello").await;
+        let error = |message| match message {
+            ClientMessage::Error { message, .. } => Some(message),
+            _ => None,
+        };
+
+        // Locally...
+        cluster
+            .edit(1, &document_id, insert(5, &"!".repeat(2000)))
+            .await;
+        let message = cluster.clients[1].recv(error).await;
+        assert!(message.contains("maximum"), "{message}");
+        // ...and from peers.
+        cluster
+            .edit(0, &document_id, insert(5, &"?".repeat(2000)))
+            .await;
+        let message = cluster.clients[1].recv(error).await;
+        assert!(message.contains("maximum"), "{message}");
+
+        let text = cluster.clients[1].text(&document_id).await;
+        assert_eq!(text.as_deref(), Some("hello"));
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn documents_start_empty_or_with_content() {
+        let mut client = TestClient::spawn(ClientConfig::default()).await;
+        // Leaving out the content is allowed.
+        let blank = r#"{"type": "create_document", "name": "blank"}"#;
+        let with_content =
+            r#"{"type": "create_document", "name": "notes", "initial_content": "hi"}"#;
+
+        for (message, expected) in [(blank, ""), (with_content, "hi")] {
+            let message = serde_json::from_str(message).unwrap();
+            client.handle.send(message).await.unwrap();
+            let document_id = client
+                .expect(|message| match message {
+                    ClientMessage::DocumentCreated { document_id } => Some(document_id),
+                    // No changes are reported for a blank document.
+                    ClientMessage::Change { .. } if expected.is_empty() => {
+                        panic!("Blank document reported a change")
+                    }
+                    _ => None,
+                })
+                .await;
+            assert_eq!(client.text(&document_id).await.as_deref(), Some(expected));
+        }
+        client.handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_creates_of_one_id_merge() {
+        let create = |content: &str| ClientMessage::CreateDocument {
+            name: "notes".to_owned(),
+            initial_content: Some(content.to_owned()),
+            container: crate::client::default_container(),
+            document_id: Some("shared".to_owned()),
+        };
+        let mut clients = Vec::new();
+        for content in ["A", "B"] {
+            // Neither has heard of the other's document yet.
+            let mut client = TestClient::spawn(ClientConfig::default()).await;
+            client.handle.send(create(content)).await.unwrap();
+            let document_id = client
+                .expect(|message| match message {
+                    ClientMessage::DocumentCreated { document_id } => Some(document_id),
+                    _ => None,
+                })
+                .await;
+            assert_eq!(document_id, "shared");
+            clients.push(client);
+        }
+        let address = clients[1].addr.to_string();
+        clients[0].connect(&address).await;
+
+        let mut cluster = Cluster { clients };
+        let text = cluster.converge("shared").await;
+        assert!(text == "AB" || text == "BA", "{text}");
+
+        // Now that it's known, the ID can't be taken again.
+        let client = &mut cluster.clients[1];
+        client.handle.send(create("C")).await.unwrap();
+        let message = client
+            .recv(|message| match message {
+                ClientMessage::Error { message, .. } => Some(message),
+                _ => None,
+            })
+            .await;
+        assert!(message.contains("shared"), "{message}");
+        assert_eq!(cluster.converge("shared").await, text);
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_drop_the_connection() {
+        let client = TestClient::spawn(ClientConfig::default()).await;
+        let mut stream = TcpStream::connect(client.addr).await.unwrap();
+        // Announce a frame far over the limit, without sending it.
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+
+        // The client hangs up rather than waiting for, or allocating, that
+        // much.
+        let mut received = Vec::new();
+        let read = timeout(TIMEOUT, stream.read_to_end(&mut received)).await;
+        assert!(read.is_ok(), "Connection was left open");
+
+        // Other peers are unaffected.
+        let mut peer = TestClient::spawn(ClientConfig::default()).await;
+        peer.connect(&client.addr.to_string()).await;
+        client.handle.shutdown().await.unwrap();
+        peer.handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn offline_edits_merge_on_reconnect() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "base").await;
+        cluster.converge(&document_id).await;
+
+        let address = cluster.clients[1].addr.to_string();
+        cluster.clients[0]
+            .handle
+            .send(ClientMessage::RemovePeer {
+                address: address.clone(),
+            })
+            .await
+            .unwrap();
+        cluster.clients[1]
+            .expect(|message| match message {
+                ClientMessage::PeerDisconnected { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+
+        cluster.edit(0, &document_id, insert(0, "[0]")).await;
+        cluster.edit(1, &document_id, insert(4, "[1]")).await;
+        let offline = cluster.clients[0].text(&document_id).await;
+        assert_eq!(offline.as_deref(), Some("[0]base"));
+        let offline = cluster.clients[1].text(&document_id).await;
+        assert_eq!(offline.as_deref(), Some("base[1]"));
+
+        cluster.clients[0].connect(&address).await;
+        assert_eq!(cluster.converge(&document_id).await, "[0]base[1]");
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_limit_are_refused() {
+        let mut hub = TestClient::spawn(ClientConfig {
+            max_peers: Some(2),
+            ..ClientConfig::default()
+        })
+        .await;
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            peers.push(TestClient::spawn(ClientConfig::default()).await);
+        }
+        let address = hub.addr.to_string();
+        for peer in &mut peers[..2] {
+            peer.connect(&address).await;
+        }
+
+        // The hub hangs up on the third before the handshake completes.
+        let extra = &mut peers[2];
+        extra.handle.add_peer(address).await.unwrap();
+        extra
+            .recv(|message| match message {
+                ClientMessage::AddPeerResponse { .. } => panic!("Connection was accepted"),
+                ClientMessage::PeerDisconnected { .. } | ClientMessage::Error { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+
+        // Nor will it connect out to another.
+        hub.handle.add_peer(extra.addr.to_string()).await.unwrap();
+        let message = hub
+            .recv(|message| match message {
+                ClientMessage::Error { message, .. } => Some(message),
+                _ => None,
+            })
+            .await;
+        assert!(message.contains("maximum number of peers"), "{message}");
+
+        hub.handle.send(ClientMessage::ListPeers).await.unwrap();
+        let connected = hub
+            .expect(|message| match message {
+                ClientMessage::PeerList { peers } => Some(peers.len()),
+                _ => None,
+            })
+            .await;
+        assert_eq!(connected, 2);
+        hub.handle.shutdown().await.unwrap();
+        for peer in peers {
+            peer.handle.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn resuming_sync_catches_peers_up_at_once() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "base").await;
+        cluster.converge(&document_id).await;
+        cluster.clients[0]
+            .handle
+            .send(ClientMessage::PauseSync {
+                document_id: document_id.clone(),
+                hold_incoming: false,
+            })
+            .await
+            .unwrap();
+
+        for (i, text) in ["a", "b", "c"].into_iter().enumerate() {
+            cluster.edit(0, &document_id, insert(i, text)).await;
+        }
+        // Updates from peers still apply while paused.
+        cluster.edit(1, &document_id, insert(4, "!")).await;
+        let deadline = Instant::now() + TIMEOUT;
+        while cluster.clients[0].text(&document_id).await.as_deref() != Some("abcbase!") {
+            assert!(Instant::now() < deadline, "Update was never applied");
+            sleep(Duration::from_millis(10)).await;
+        }
+        let paused = cluster.clients[1].text(&document_id).await;
+        assert_eq!(paused.as_deref(), Some("base!"));
+
+        cluster.clients[0]
+            .handle
+            .send(ClientMessage::ResumeSync {
+                document_id: document_id.clone(),
+            })
+            .await
+            .unwrap();
+        // Every paused edit arrives in the first update.
+        let client = &mut cluster.clients[1];
+        client
+            .expect(|message| match message {
+                ClientMessage::Change {
+                    origin: Some(ChangeOrigin::Remote { .. }),
+                    ..
+                }
+                | ClientMessage::ChangeBatch {
+                    origin: Some(ChangeOrigin::Remote { .. }),
+                    ..
+                } => Some(()),
+                _ => None,
+            })
+            .await;
+        let caught_up = client.text(&document_id).await;
+        assert_eq!(caught_up.as_deref(), Some("abcbase!"));
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn title_edits_converge() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "").await;
+        let set_title = |title: &str| ClientMessage::SetMetadata {
+            document_id: document_id.clone(),
+            key: "title".to_owned(),
+            value: Some(title.to_owned()),
+        };
+
+        cluster.clients[0]
+            .handle
+            .send(set_title("Draft"))
+            .await
+            .unwrap();
+        let changed = cluster.clients[1]
+            .expect(|message| match message {
+                ClientMessage::MetadataChanged { key, value, .. } => Some((key, value)),
+                _ => None,
+            })
+            .await;
+        assert_eq!(changed, ("title".to_owned(), Some("Draft".to_owned())));
+
+        // Both retitle it at once, and agree on one of them.
+        for (i, title) in ["Final", "Done"].into_iter().enumerate() {
+            cluster.clients[i]
+                .handle
+                .send(set_title(title))
+                .await
+                .unwrap();
+        }
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let mut titles = Vec::new();
+            for i in 0..2 {
+                let doc = snapshot(&mut cluster, i, &document_id).await;
+                titles.push(metadata_entries(&doc));
+            }
+            if titles[0] == titles[1] && titles[0][0].1 != "Draft" {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Titles never converged: {titles:?}"
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+        cluster.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn peer_joining_mid_burst_converges() {
+        let mut cluster = Cluster::new(2, Topology::Mesh).await;
+        let document_id = cluster.create_document(0, "notes", "").await;
+
+        let handle = cluster.clients[0].handle.clone();
+        let id = document_id.clone();
+        let burst = tokio::spawn(async move {
+            for i in 0..500 {
+                handle.apply_change(&id, insert(i, "x")).await.unwrap();
+                if i % 50 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let mut late = TestClient::spawn(ClientConfig::default()).await;
+        late.connect(&cluster.clients[0].addr.to_string()).await;
+        let deadline = Instant::now() + TIMEOUT;
+        while late.text(&document_id).await.is_none() {
+            assert!(Instant::now() < deadline, "Document never arrived");
+            sleep(Duration::from_millis(10)).await;
+        }
+        late.handle
+            .send(ClientMessage::JoinDocument {
+                id: document_id.clone(),
+            })
+            .await
+            .unwrap();
+        cluster.clients.push(late);
+
+        burst.await.unwrap();
+        assert_eq!(cluster.converge(&document_id).await, "x".repeat(500));
+        cluster.shutdown().await;
+    }
+}
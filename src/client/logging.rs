@@ -0,0 +1,55 @@
+use color_eyre::{eyre::WrapErr, Result};
+use std::{io, path::PathBuf};
+use tracing::level_filters::LevelFilter;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::EnvFilter;
+
+/// Where and how verbosely to log. Logs never go to stdout, which is reserved
+/// for messages to the frontend.
+#[derive(Debug, Clone, Default)]
+pub struct LogConfig {
+    /// Minimum level to log, in addition to any directives in `RUST_LOG`.
+    pub level: Option<LevelFilter>,
+    /// Log to rotating files instead of stderr.
+    pub file: Option<LogFile>,
+}
+
+/// A set of log files, rotated as time passes.
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub directory: PathBuf,
+    /// Start of each file's name, followed by the date and `.log`.
+    pub prefix: String,
+    pub rotation: Rotation,
+}
+
+/// Installs the global `tracing` subscriber described by `config`.
+///
+/// When logging to files, the returned guard must be held until exit, since
+/// dropping it stops buffered logs from being written.
+pub fn init_logging(config: &LogConfig) -> Result<Option<WorkerGuard>> {
+    let mut filter = EnvFilter::from_default_env();
+    if let Some(level) = config.level {
+        filter = filter.add_directive(level.into());
+    }
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let Some(ref file) = config.file else {
+        subscriber.with_writer(io::stderr).init();
+        return Ok(None);
+    };
+
+    let appender = RollingFileAppender::builder()
+        .rotation(file.rotation.clone())
+        .filename_prefix(&file.prefix)
+        .filename_suffix("log")
+        .build(&file.directory)
+        .wrap_err_with(|| format!("Failed to open log file in {}", file.directory.display()))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+    subscriber.with_writer(writer).with_ansi(false).init();
+
+    Ok(Some(guard))
+}
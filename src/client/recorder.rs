@@ -0,0 +1,117 @@
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use loro::LoroDoc;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use super::{utils::decompress_bounded, BackendMessage};
+
+/// Whether a recorded message was received from or sent to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// One line of a recording.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    /// Address of the peer the message was exchanged with.
+    peer: String,
+    direction: Direction,
+    message: BackendMessage,
+}
+
+/// Writes every message exchanged with peers to a file, one JSON object per
+/// line, for reproducing sync bugs with `replay_log`.
+pub struct Recorder {
+    file: Mutex<LineWriter<File>>,
+}
+
+impl Recorder {
+    /// Creates the recording at `path`, replacing any file already there.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .wrap_err_with(|| format!("Failed to create recording at {}", path.display()))?;
+
+        Ok(Recorder {
+            file: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    /// Records `message`, exchanged with the peer at `peer`. Failures are
+    /// only logged, since recording is a debugging aid.
+    pub fn record(&self, peer: &str, direction: Direction, message: &BackendMessage) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let record = Record {
+            timestamp,
+            peer: peer.to_owned(),
+            direction,
+            message: message.clone(),
+        };
+
+        let mut file = self.file.lock().unwrap();
+        let result = serde_json::to_writer(&mut *file, &record)
+            .map_err(io::Error::from)
+            .and_then(|()| file.write_all(b"\n"));
+        if let Err(e) = result {
+            warn!("Failed to record message to peer at {}: {}", peer, e);
+        }
+    }
+}
+
+/// Rebuilds the document with the given ID from a recording made with
+/// `ClientConfig::record_path`, importing every sync of it sent or received
+/// in the order they were recorded.
+pub fn replay_log(path: &Path, document_id: &str) -> Result<LoroDoc> {
+    let file = File::open(path)
+        .wrap_err_with(|| format!("Failed to open recording at {}", path.display()))?;
+    let doc = LoroDoc::new();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.wrap_err("Failed to read recording")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .wrap_err_with(|| format!("Invalid record on line {}", i + 1))?;
+        let BackendMessage::DocumentSync {
+            document_id: id,
+            data,
+            compressed,
+            ..
+        } = record.message
+        else {
+            continue;
+        };
+        if id != document_id {
+            continue;
+        }
+
+        let data = if compressed {
+            decompress_bounded(&data, usize::MAX)
+                .wrap_err_with(|| format!("Failed to decompress sync on line {}", i + 1))?
+        } else {
+            data
+        };
+        if let Err(e) = doc.import(&data) {
+            bail!("Failed to import sync on line {}: {e}", i + 1);
+        }
+    }
+
+    Ok(doc)
+}
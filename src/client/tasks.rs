@@ -1,90 +1,793 @@
 use super::{
-    channels::{MainTaskMessage, OutgoingMessage}, ClientMessage, ReadSocket,
+    channels::{IncomingMessage, MainTaskMessage, OutgoingMessage},
+    recorder::{Direction, Recorder},
+    transport::{self, ConnectionOptions, Listener, TrafficStats, Transport, WriteSocket},
+    BackendMessage, ClientEvents, ClientHandle, ClientMessage, Output, PeerState, RateLimit,
+    ReconnectPolicy,
+};
+use color_eyre::{eyre::eyre, Report};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use std::{
+    collections::HashMap,
+    future,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
 };
-use futures::{SinkExt, TryStreamExt};
-use std::io::Write as _;
 use tokio::{
-    io::{self, AsyncBufReadExt, BufReader},
-    net::TcpListener,
-    sync::mpsc::{Receiver, Sender},
+    fs::OpenOptions,
+    io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    signal,
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+    task::{JoinHandle, JoinSet},
+    time::{self, MissedTickBehavior},
 };
-use tracing::info;
+use tracing::{debug, error, info, warn};
+
+/// Longest to wait before accepting again after failing to accept a
+/// connection, e.g. while out of file descriptors.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Handles to the client's long-running tasks, used to stop them on shutdown.
+pub struct TaskHandles {
+    pub incoming: JoinHandle<()>,
+    pub outgoing: JoinHandle<()>,
+    /// Writes to stdout, or discards messages for a relay, unless the client
+    /// is embedded.
+    pub stdout: Option<JoinHandle<()>>,
+    /// Tasks with no work to finish, which can simply be aborted.
+    pub background: Vec<JoinHandle<()>>,
+    /// Pending `begin_timer` timers, which nothing needs once shutting down.
+    pub timers: JoinSet<()>,
+}
+
+impl TaskHandles {
+    /// Aborts every background task and timer, waiting for them to stop so
+    /// none are left polling closed connections. Returns how many there
+    /// were.
+    pub async fn abort_background(&mut self) -> usize {
+        let count = self.background.len() + self.timers.len();
+        for handle in self.background.drain(..) {
+            handle.abort();
+            let _ = handle.await;
+        }
+        self.timers.shutdown().await;
+
+        count
+    }
+}
 
-pub fn begin_incoming_task(tx: Sender<MainTaskMessage>, mut rx: Receiver<ReadSocket>) {
+/// Reports an error that occurred in a background task to the frontend.
+async fn report_error(stdout_tx: &Sender<ClientMessage>, message: String, context: String) {
+    error!("Error while {}: {}", context, message);
+
+    if stdout_tx
+        .send(ClientMessage::Error { message, context })
+        .await
+        .is_err()
+    {
+        error!("Stdout task exited; could not report error");
+    }
+}
+
+/// Limits the rate of messages read from a single peer.
+struct TokenBucket {
+    limit: RateLimit,
+    /// Goes negative when messages are waiting for tokens.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token, returning how long to wait before using it if none were
+    /// left.
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let rate = self.limit.per_second as f64;
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(self.limit.burst as f64);
+        self.last_refill = now;
+
+        self.tokens -= 1.0;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / rate))
+    }
+}
+
+pub fn begin_incoming_task(
+    tx: Sender<MainTaskMessage>,
+    stdout_tx: Sender<ClientMessage>,
+    stats: Arc<TrafficStats>,
+    recorder: Option<Arc<Recorder>>,
+    rate: Option<RateLimit>,
+    mut rx: Receiver<IncomingMessage>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Some(mut socket) = rx.recv().await {
-            let tx = tx.clone();
+        let mut tasks = HashMap::new();
 
-            // TODO store join handles so we can cancel tasks when disconnecting.
-            tokio::spawn(async move {
-                while let Some(message) = socket.try_next().await.unwrap() {
-                    info!("Received from network: {:?}", message);
-                    tx.send(MainTaskMessage::BackendMessage(message))
-                        .await
-                        .unwrap();
+        while let Some(message) = rx.recv().await {
+            match message {
+                IncomingMessage::NewSocket(address, mut socket) => {
+                    let tx = tx.clone();
+                    let stdout_tx = stdout_tx.clone();
+                    let stats = stats.clone();
+                    let recorder = recorder.clone();
+                    let task_address = address.clone();
+
+                    let handle = tokio::spawn(async move {
+                        let address = task_address;
+                        let mut bucket = rate.map(TokenBucket::new);
+                        let mut throttled = false;
+
+                        loop {
+                            match socket.try_next().await {
+                                Ok(Some(message)) => {
+                                    info!("Received from network: {:?}", message);
+                                    stats.messages_received.fetch_add(1, Ordering::Relaxed);
+                                    if let Some(ref recorder) = recorder {
+                                        recorder.record(&address, Direction::Incoming, &message);
+                                    }
+
+                                    // Waiting here also stops reading from the
+                                    // socket, pushing back on the peer.
+                                    match bucket.as_mut().and_then(TokenBucket::take) {
+                                        Some(wait) => {
+                                            if !throttled {
+                                                warn!(
+                                                    "Peer at {} is sending too fast, throttling",
+                                                    address
+                                                );
+                                                throttled = true;
+                                            }
+                                            time::sleep(wait).await;
+                                        }
+                                        None => throttled = false,
+                                    }
+                                    if tx
+                                        .send(MainTaskMessage::BackendMessage(
+                                            address.clone(),
+                                            message,
+                                        ))
+                                        .await
+                                        .is_err()
+                                    {
+                                        break;
+                                    }
+                                }
+                                Ok(None) => {
+                                    info!("Peer at {} closed the connection", address);
+                                    let _ =
+                                        tx.send(MainTaskMessage::PeerDisconnected(address)).await;
+                                    break;
+                                }
+                                Err(e) => {
+                                    report_error(
+                                        &stdout_tx,
+                                        format!("{e:#}"),
+                                        format!("reading from peer at {address}"),
+                                    )
+                                    .await;
+                                    let _ =
+                                        tx.send(MainTaskMessage::PeerDisconnected(address)).await;
+                                    break;
+                                }
+                            }
+                        }
+                    });
+                    tasks.insert(address, handle);
                 }
-            });
+                IncomingMessage::RemoveSocket(address) => {
+                    if let Some(handle) = tasks.remove(&address) {
+                        handle.abort();
+                    }
+                }
+            }
         }
-    });
+
+        for handle in tasks.into_values() {
+            handle.abort();
+            let _ = handle.await;
+        }
+    })
 }
 
-pub fn begin_outgoing_task(mut rx: Receiver<OutgoingMessage>) {
+/// Sends messages to peers. A peer whose connection fails to write is dropped
+/// and reported as disconnected, without affecting the others.
+pub fn begin_outgoing_task(
+    stdout_tx: Sender<ClientMessage>,
+    main_tx: Sender<MainTaskMessage>,
+    stats: Arc<TrafficStats>,
+    recorder: Option<Arc<Recorder>>,
+    queue_capacity: usize,
+    mut rx: Receiver<OutgoingMessage>,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut sockets = Vec::new();
+        // Each peer is written to by its own task, so that a slow peer only
+        // holds up its own queue.
+        let mut queues = HashMap::new();
+        let mut writers = JoinSet::new();
+        // Notices of dropped peers still waiting for the main task.
+        let mut notices = JoinSet::new();
 
         loop {
-            if let Some(message) = rx.recv().await {
-                match message {
-                    OutgoingMessage::NewSocket(socket) => {
-                        sockets.push(socket);
+            let message = tokio::select! {
+                message = rx.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                Some(result) = writers.join_next() => {
+                    if let Ok(Some((address, error))) = result {
+                        report_write_failure(&stdout_tx, &main_tx, &mut notices, address, error)
+                            .await;
                     }
-                    OutgoingMessage::BackendMessage(message) => {
-                        info!("Sending to network: {:?}", message);
-
-                        for socket in sockets.iter_mut() {
-                            socket.send(message.clone()).await.unwrap();
-                        }
+                    continue;
+                }
+            };
+            let (address, message) = match message {
+                OutgoingMessage::NewSocket(address, socket) => {
+                    let (queue_tx, queue_rx) = mpsc::channel(queue_capacity);
+                    let writer = writers.spawn(write_to_peer(
+                        address.clone(),
+                        socket,
+                        queue_rx,
+                        stats.clone(),
+                        recorder.clone(),
+                    ));
+                    // Replacing a queue lets the old writer finish what it
+                    // has left before closing its socket.
+                    queues.insert(address, (queue_tx, writer));
+                    continue;
+                }
+                OutgoingMessage::RemoveSocket(address) => {
+                    queues.remove(&address);
+                    continue;
+                }
+                OutgoingMessage::DirectMessage { address, message } => {
+                    info!("Sending to peer at {}: {:?}", address, message);
+                    if !queues.contains_key(&address) {
+                        error!("Attempted to send to unknown peer at {}", address);
+                        continue;
                     }
+                    (address, message)
                 }
+            };
+
+            let (queue, writer) = &queues[&address];
+            match queue.try_send(message) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    // Whatever it's stuck writing would be cut off, so don't
+                    // wait for it.
+                    writer.abort();
+                    queues.remove(&address);
+                    let error =
+                        eyre!("Peer fell behind with {queue_capacity} messages waiting to be sent");
+                    report_write_failure(&stdout_tx, &main_tx, &mut notices, address, error).await;
+                }
+                // The writer already reported why it stopped.
+                Err(TrySendError::Closed(_)) => {
+                    queues.remove(&address);
+                }
+            }
+        }
+
+        // Closing the queues has each writer send what's left, then close
+        // its connection.
+        queues.clear();
+        while let Some(result) = writers.join_next().await {
+            if let Ok(Some((address, error))) = result {
+                report_write_failure(&stdout_tx, &main_tx, &mut notices, address, error).await;
             }
         }
+        // The main task is shutting down too, so it may never take them.
+        notices.shutdown().await;
+    })
+}
+
+/// Writes the messages in `queue` to the peer at `address` until the queue is
+/// closed, then closes the connection. Stops at the first failed write,
+/// returning the peer's address and the error.
+async fn write_to_peer(
+    address: String,
+    mut socket: WriteSocket,
+    mut queue: Receiver<BackendMessage>,
+    stats: Arc<TrafficStats>,
+    recorder: Option<Arc<Recorder>>,
+) -> Option<(String, Report)> {
+    while let Some(message) = queue.recv().await {
+        if let Some(ref recorder) = recorder {
+            recorder.record(&address, Direction::Outgoing, &message);
+        }
+        if let Err(e) = socket.send(message).await {
+            return Some((address, e));
+        }
+        stats.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if let Err(e) = socket.close().await {
+        info!("Failed to close connection to peer at {}: {:#}", address, e);
+    }
+    None
+}
+
+/// Reports that writing to the peer at `address` failed, and has the main
+/// task treat the peer as disconnected, since its connection is likely
+/// half-open.
+async fn report_write_failure(
+    stdout_tx: &Sender<ClientMessage>,
+    main_tx: &Sender<MainTaskMessage>,
+    notices: &mut JoinSet<()>,
+    address: String,
+    error: Report,
+) {
+    warn!(
+        "Dropping connection to peer at {} after failed write",
+        address
+    );
+    report_error(
+        stdout_tx,
+        format!("{error:#}"),
+        format!("sending to peer at {address}"),
+    )
+    .await;
+
+    // The main task may itself be waiting to send to this task, so don't
+    // wait for it here.
+    while notices.try_join_next().is_some() {}
+    let main_tx = main_tx.clone();
+    notices.spawn(async move {
+        let _ = main_tx
+            .send(MainTaskMessage::PeerDisconnected(address))
+            .await;
     });
 }
 
-pub fn begin_stdin_task(tx: Sender<MainTaskMessage>) {
+/// Sends each line of `reader` to `handle` as a message from the frontend,
+/// reporting and skipping any that aren't valid messages, until `reader` is
+/// exhausted. Returns whether the client is still running.
+async fn forward_lines(
+    reader: impl AsyncBufRead + Unpin,
+    handle: &ClientHandle,
+    stdout_tx: &Sender<ClientMessage>,
+) -> bool {
+    let mut lines = reader.lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                info!("Stdin closed");
+                return true;
+            }
+            Err(e) => {
+                error!("Failed to read from stdin: {}", e);
+                return true;
+            }
+        };
+        info!("Received message from stdin: {}", line);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message = match serde_json::from_str::<ClientMessage>(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                report_error(
+                    stdout_tx,
+                    format!("Invalid message {line:?}: {e}"),
+                    "parsing message from frontend".to_owned(),
+                )
+                .await;
+                continue;
+            }
+        };
+        if handle.send(message).await.is_err() {
+            return false;
+        }
+    }
+}
+
+/// Forwards messages from the frontend to `handle`. Once stdin closes, shuts
+/// the client down if `shutdown_on_close` is set, or leaves it running
+/// without a frontend otherwise.
+pub fn begin_stdin_task(
+    handle: ClientHandle,
+    stdout_tx: Sender<ClientMessage>,
+    shutdown_on_close: bool,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let stdin = BufReader::new(io::stdin());
-        let mut lines = stdin.lines();
+        if !forward_lines(stdin, &handle, &stdout_tx).await {
+            // The client has already shut down.
+            return;
+        }
 
-        while let Ok(Some(line)) = lines.next_line().await {
-            info!("Received message from stdin: {}", line);
-            let message = serde_json::from_str::<ClientMessage>(&line).unwrap();
-            tx.send(MainTaskMessage::ClientMessage(message))
-                .await
-                .unwrap();
+        if shutdown_on_close {
+            info!("Frontend is gone; shutting down");
+            let _ = handle.send(ClientMessage::Shutdown).await;
+        } else {
+            info!("Frontend is gone; continuing without one");
         }
-    });
+    })
 }
 
-pub fn begin_stdout_task(mut rx: Receiver<ClientMessage>) {
+/// Writer for messages to the frontend supplied to `ClientBuilder::output`.
+pub type FrontendWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where `begin_stdout_task` writes messages to.
+pub enum OutputWriter {
+    Target(Output),
+    Writer(FrontendWriter),
+}
+
+/// Writes `events` to `output` for the frontend.
+pub fn begin_stdout_task(mut events: ClientEvents, output: OutputWriter) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            let serialized = serde_json::to_string(&message).unwrap();
-            info!("Sending message to stdout: {:?}", serialized);
-            // TODO should this be using Tokio's stdout?
-            let mut stdout = std::io::stdout();
-            stdout.write_all(serialized.as_bytes()).unwrap();
-            stdout.write_all(b"\n").unwrap();
+        let mut writer: FrontendWriter = match output {
+            OutputWriter::Writer(writer) => writer,
+            OutputWriter::Target(Output::Stdout) => Box::new(io::stdout()),
+            OutputWriter::Target(Output::Path(path)) => {
+                // Opening a named pipe waits for a reader, so this happens
+                // here rather than while building the client.
+                match OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await
+                {
+                    Ok(file) => Box::new(file),
+                    Err(e) => {
+                        error!("Failed to open output {}: {}", path.display(), e);
+                        // Keep draining, so the rest of the client isn't
+                        // held up.
+                        while events.next().await.is_some() {}
+                        return;
+                    }
+                }
+            }
+        };
+
+        while let Some(message) = events.next().await {
+            // Serializing this enum can't fail, since every variant only holds
+            // plain data.
+            let mut serialized = serde_json::to_string(&message).unwrap();
+            info!("Sending message to frontend: {:?}", serialized);
+            // Written in one go, so a reader never sees part of a line
+            // without the rest.
+            serialized.push('\n');
+            if let Err(e) = write_flushed(&mut writer, serialized.as_bytes()).await {
+                error!("Failed to write to frontend: {}", e);
+            }
         }
-    });
+    })
+}
+
+async fn write_flushed(writer: &mut FrontendWriter, data: &[u8]) -> io::Result<()> {
+    writer.write_all(data).await?;
+    writer.flush().await
 }
 
-pub fn begin_listening_task(listener: TcpListener, tx: Sender<MainTaskMessage>) {
+/// Drains `events` when there's no frontend to send them to, logging them
+/// instead.
+pub fn begin_discard_task(mut events: ClientEvents) -> JoinHandle<()> {
     tokio::spawn(async move {
-        while let Ok(message) = listener.accept().await {
-            tx.send(MainTaskMessage::NewConnection(message))
-                .await
-                .unwrap();
+        while let Some(message) = events.next().await {
+            debug!("No frontend to send message to: {:?}", message);
         }
+    })
+}
+
+pub fn begin_listening_task(
+    mut listener: Listener,
+    transport: Transport,
+    options: ConnectionOptions,
+    tx: Sender<MainTaskMessage>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // Aborted along with this task when it's dropped.
+        let mut handshakes = JoinSet::new();
+        let mut backoff = Duration::ZERO;
+
+        loop {
+            let accepted = listener.accept(&options.tcp).await;
+            if tx.is_closed() {
+                break;
+            }
+            let (connection, address) = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    // Errors like running out of file descriptors go away
+                    // with time, so wait rather than retrying in a busy loop.
+                    backoff = (backoff * 2).clamp(Duration::from_millis(10), MAX_ACCEPT_BACKOFF);
+                    error!(
+                        "Failed to accept connection, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    time::sleep(backoff).await;
+                    continue;
+                }
+            };
+            backoff = Duration::ZERO;
+
+            // Perform any handshakes separately so a slow peer can't hold up
+            // other incoming connections.
+            let options = options.clone();
+            let tx = tx.clone();
+            while handshakes.try_join_next().is_some() {}
+            handshakes.spawn(async move {
+                match transport::accept_connection(connection, transport, &options).await {
+                    Ok(sockets) => {
+                        let _ = tx
+                            .send(MainTaskMessage::NewConnection(sockets, address))
+                            .await;
+                    }
+                    Err(e) => {
+                        error!("Failed to accept connection from {}: {:?}", address, e);
+                    }
+                }
+            });
+        }
+    })
+}
+
+pub fn begin_heartbeat_task(interval: Duration, tx: Sender<MainTaskMessage>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately.
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            if tx.send(MainTaskMessage::Heartbeat).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Shuts the client down once SIGINT or SIGTERM is received, rather than
+/// letting the process be killed with messages still queued.
+pub fn begin_signal_task(tx: Sender<MainTaskMessage>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let interrupt = async {
+            if let Err(e) = signal::ctrl_c().await {
+                error!("Failed to listen for SIGINT: {}", e);
+                future::pending::<()>().await;
+            }
+        };
+        #[cfg(unix)]
+        let terminate = async {
+            match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => {
+                    signal.recv().await;
+                }
+                Err(e) => {
+                    error!("Failed to listen for SIGTERM: {}", e);
+                    future::pending::<()>().await;
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = future::pending::<()>();
+
+        tokio::select! {
+            () = interrupt => info!("Received SIGINT"),
+            () = terminate => info!("Received SIGTERM"),
+        }
+        let _ = tx
+            .send(MainTaskMessage::ClientMessage(ClientMessage::Shutdown))
+            .await;
+    })
+}
+
+/// Repeatedly tries to connect to the peer at `address`, backing off
+/// exponentially between attempts.
+pub fn begin_reconnect_task(
+    address: String,
+    options: ConnectionOptions,
+    policy: ReconnectPolicy,
+    tx: Sender<MainTaskMessage>,
+    stdout_tx: Sender<ClientMessage>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut backoff = policy.initial_backoff;
+        let _ = stdout_tx
+            .send(ClientMessage::PeerState {
+                address: address.clone(),
+                state: PeerState::Reconnecting,
+            })
+            .await;
+
+        for attempt in 1..=policy.max_attempts {
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(policy.max_backoff);
+
+            info!("Reconnecting to peer at {} (attempt {})", address, attempt);
+            let _ = stdout_tx
+                .send(ClientMessage::PeerReconnecting {
+                    address: address.clone(),
+                    attempt,
+                })
+                .await;
+
+            match transport::open_connection(&address, &options).await {
+                Ok(sockets) => {
+                    let _ = tx
+                        .send(MainTaskMessage::PeerConnected(address, sockets))
+                        .await;
+                    return;
+                }
+                Err(e) => {
+                    info!("Failed to reconnect to peer at {}: {:#}", address, e);
+                }
+            }
+        }
+
+        report_error(
+            &stdout_tx,
+            format!("Gave up after {} attempts", policy.max_attempts),
+            format!("reconnecting to peer at {address}"),
+        )
+        .await;
+        let _ = stdout_tx
+            .send(ClientMessage::PeerState {
+                address,
+                state: PeerState::Disconnected,
+            })
+            .await;
+    })
+}
+
+/// Connects to the peer at `address`, making up to `attempts` attempts with
+/// `delay` between them, doubled after each one.
+pub fn begin_connect_task(
+    address: String,
+    options: ConnectionOptions,
+    attempts: u32,
+    mut delay: Duration,
+    tx: Sender<MainTaskMessage>,
+    stdout_tx: Sender<ClientMessage>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        for attempt in 1..=attempts {
+            match transport::open_connection(&address, &options).await {
+                Ok(sockets) => {
+                    let _ = tx
+                        .send(MainTaskMessage::PeerConnected(address, sockets))
+                        .await;
+                    return;
+                }
+                Err(e) if attempt == attempts => {
+                    report_error(
+                        &stdout_tx,
+                        format!("{e:#}"),
+                        format!("connecting to peer at {address}"),
+                    )
+                    .await;
+                }
+                Err(e) => {
+                    info!(
+                        "Failed to connect to peer at {} (attempt {}), retrying: {:#}",
+                        address, attempt, e
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+
+        let _ = stdout_tx
+            .send(ClientMessage::PeerState {
+                address,
+                state: PeerState::Disconnected,
+            })
+            .await;
+    })
+}
+
+/// Has the main task send peers pending changes every `interval`.
+pub fn begin_flush_task(interval: Duration, tx: Sender<MainTaskMessage>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(interval);
+        // Catching up on missed ticks would only send empty flushes.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            if tx.send(MainTaskMessage::FlushAll).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Sends `message` to the main task after `delay`, e.g. to broadcast a
+/// document once its debounce window has passed. The timer is added to
+/// `timers` so it can be cancelled.
+pub fn begin_timer(
+    timers: &mut JoinSet<()>,
+    message: MainTaskMessage,
+    delay: Duration,
+    tx: Sender<MainTaskMessage>,
+) {
+    // Clear out timers that have already fired.
+    while timers.try_join_next().is_some() {}
+    timers.spawn(async move {
+        time::sleep(delay).await;
+        let _ = tx.send(message).await;
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_are_throttled_once_tokens_run_out() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_second: 10,
+            burst: 3,
+        });
+        for _ in 0..3 {
+            assert_eq!(bucket.take(), None);
+        }
+
+        // Each message past the burst waits for one more token.
+        let first = bucket.take().expect("burst should be used up");
+        let second = bucket.take().expect("burst should be used up");
+        assert!(first <= Duration::from_millis(100), "{first:?}");
+        assert!(first > Duration::from_millis(90), "{first:?}");
+        assert!(second > first + Duration::from_millis(90), "{second:?}");
+    }
+
+    #[test]
+    fn tokens_refill_while_idle() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            per_second: 100,
+            burst: 1,
+        });
+        assert_eq!(bucket.take(), None);
+        assert!(bucket.take().is_some());
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(bucket.take(), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_frontend_lines_are_skipped() {
+        let input = b"garbage\n\n{\"type\":\"add_peer\",\"address\":\"a\"}\n\
+            {\"type\":\"no_such_message\"}\n{\"type\":\"shutdown\"}\n";
+        let (main_tx, mut main_rx) = mpsc::channel(10);
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(10);
+        let handle = ClientHandle::new(main_tx);
+        assert!(forward_lines(&input[..], &handle, &stdout_tx).await);
+
+        let mut forwarded = Vec::new();
+        while let Ok(MainTaskMessage::ClientMessage(message)) = main_rx.try_recv() {
+            forwarded.push(message);
+        }
+        assert!(matches!(
+            forwarded[..],
+            [ClientMessage::AddPeer { .. }, ClientMessage::Shutdown]
+        ));
+        for bad in ["garbage", "no_such_message"] {
+            match stdout_rx.try_recv() {
+                Ok(ClientMessage::Error { message, .. }) => {
+                    assert!(message.contains(bad), "{message}")
+                }
+                _ => panic!("Expected an error about {bad:?}"),
+            }
+        }
+        assert!(stdout_rx.try_recv().is_err());
+
+        // Nothing more is read once the client is gone.
+        drop(main_rx);
+        assert!(!forward_lines(&input[..], &handle, &stdout_tx).await);
+    }
+}
@@ -0,0 +1,657 @@
+use super::{utils::gzip, BackendMessage};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use futures::{future, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use rustls_pemfile::{certs, private_key};
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName, RootCertStore},
+    TlsAcceptor, TlsConnector,
+};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_util::{
+    bytes::Bytes,
+    codec::{FramedRead, FramedWrite, LengthDelimitedCodec},
+};
+use tracing::warn;
+
+/// A bidirectional byte stream to a peer, regardless of transport.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Connection for T {}
+
+pub type BoxedConnection = Box<dyn Connection>;
+
+/// Messages received from a peer.
+pub type ReadSocket = Pin<Box<dyn Stream<Item = Result<BackendMessage>> + Send + Sync>>;
+/// Messages to be sent to a peer.
+pub type WriteSocket = Pin<Box<dyn Sink<BackendMessage, Error = Report> + Send + Sync>>;
+
+/// Address prefix selecting the WebSocket transport for outgoing connections.
+pub const WEBSOCKET_PREFIX: &str = "ws://";
+/// Address prefix selecting a Unix domain socket for outgoing connections.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Encoding used for messages sent to peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WireFormat {
+    /// Human-readable, and understood by every peer.
+    #[default]
+    Json,
+    /// Compact binary encoding, used only when both peers prefer it.
+    MessagePack,
+}
+
+/// Settings shared by every peer connection.
+#[derive(Clone)]
+pub struct ConnectionOptions {
+    pub tls: Option<TlsConfig>,
+    pub format: WireFormat,
+    pub tcp: TcpOptions,
+    /// Proxy for outgoing TCP connections, if any.
+    pub proxy: Option<Socks5Proxy>,
+    /// Largest message in bytes accepted over a byte stream. Peers announcing
+    /// a larger one are disconnected before it's read.
+    pub max_frame_length: usize,
+    /// Whether document updates are compressed for peers that agree to it.
+    pub compression: bool,
+    /// Keys to sign messages with and verify peers' messages against, if
+    /// signing is enabled.
+    pub signing: Option<SigningConfig>,
+    pub stats: Arc<TrafficStats>,
+}
+
+/// Ed25519 keys used to sign every message sent to peers, and to require
+/// signed messages from them.
+///
+/// Each peer sends its public key in its `Hello`, and every later message
+/// from it is checked against that key. Messages with invalid signatures are
+/// logged and dropped.
+#[derive(Clone)]
+pub struct SigningConfig {
+    pub key: SigningKey,
+    /// Public keys of the peers allowed to connect. Peers presenting any other
+    /// key are disconnected. If empty, any key is accepted.
+    pub trusted_keys: Vec<VerifyingKey>,
+}
+
+// Written out so the private key never ends up in logs.
+impl fmt::Debug for SigningConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningConfig")
+            .field("key", &self.key.verifying_key())
+            .field("trusted_keys", &self.trusted_keys)
+            .finish()
+    }
+}
+
+/// A SOCKS5 proxy to make outgoing TCP connections through.
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    pub host: String,
+    pub port: u16,
+    pub auth: Option<ProxyAuth>,
+}
+
+/// Username and password for a SOCKS5 proxy.
+#[derive(Debug, Clone)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl Socks5Proxy {
+    /// Connects to `target` through the proxy, which resolves its hostname.
+    async fn connect(&self, target: &str) -> Result<TcpStream> {
+        let proxy = (self.host.as_str(), self.port);
+        let stream = match &self.auth {
+            Some(auth) => {
+                Socks5Stream::connect_with_password(proxy, target, &auth.username, &auth.password)
+                    .await
+            }
+            None => Socks5Stream::connect(proxy, target).await,
+        }
+        .wrap_err_with(|| {
+            format!(
+                "Failed to connect to {target} through proxy at {}:{}",
+                self.host, self.port
+            )
+        })?;
+
+        Ok(stream.into_inner())
+    }
+}
+
+/// Socket options set on TCP connections, both incoming and outgoing.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpOptions {
+    /// Whether to disable Nagle's algorithm, so small messages like
+    /// keystrokes are sent immediately.
+    pub nodelay: bool,
+    /// If set, enables TCP keepalive, probing idle connections at this
+    /// interval.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    fn apply(&self, socket: &TcpStream) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(interval) = self.keepalive {
+            let keepalive = TcpKeepalive::new()
+                .with_time(interval)
+                .with_interval(interval);
+            SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Traffic counters for all peer connections, shared between tasks.
+///
+/// Bytes are counted as encoded messages, excluding any framing or TLS
+/// overhead.
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub messages_sent: AtomicU64,
+    pub messages_received: AtomicU64,
+}
+
+/// Encodes and decodes the messages of a single connection.
+///
+/// Each side's `Hello` is sent as JSON, and nothing else is sent until the
+/// peer's `Hello` arrives. Every later message in either direction uses
+/// MessagePack if both `Hello`s asked for it, and JSON otherwise. Document
+/// updates are compressed in the same way, once the peer's `Hello` shows that
+/// both sides want compression.
+///
+/// Signed messages are always verified against the key in the peer's `Hello`
+/// if it sent one, and are required if signing is enabled.
+#[derive(Clone)]
+struct WireCodec {
+    preferred: WireFormat,
+    compression: bool,
+    signing: Option<SigningConfig>,
+    /// Public key the peer presented in its `Hello`.
+    peer_key: Arc<OnceLock<VerifyingKey>>,
+    /// Whether both sides have agreed to use MessagePack.
+    negotiated: Arc<AtomicBool>,
+    /// Whether both sides have agreed to compress document updates.
+    compression_negotiated: Arc<AtomicBool>,
+    /// Set once the peer's `Hello` has been received, and with it the format
+    /// of every later message.
+    peer_hello: Arc<watch::Sender<bool>>,
+    stats: Arc<TrafficStats>,
+}
+
+impl WireCodec {
+    fn new(options: &ConnectionOptions) -> Self {
+        WireCodec {
+            preferred: options.format,
+            compression: options.compression,
+            signing: options.signing.clone(),
+            peer_key: Arc::new(OnceLock::new()),
+            negotiated: Arc::new(AtomicBool::new(false)),
+            compression_negotiated: Arc::new(AtomicBool::new(false)),
+            peer_hello: Arc::new(watch::Sender::new(false)),
+            stats: options.stats.clone(),
+        }
+    }
+
+    /// Encodes a message once it can be sent. Only our `Hello` is sent before
+    /// the peer's arrives, since until then the format isn't known.
+    async fn encode_after_hello(&self, message: BackendMessage) -> Result<Vec<u8>> {
+        if !matches!(message, BackendMessage::Hello { .. }) {
+            self.peer_hello
+                .subscribe()
+                .wait_for(|&received| received)
+                .await?;
+        }
+
+        self.encode(&message)
+    }
+
+    fn encode(&self, message: &BackendMessage) -> Result<Vec<u8>> {
+        let compressed;
+        let message = match message {
+            BackendMessage::DocumentSync {
+                document_id,
+                data,
+                compressed: false,
+                seq,
+            } if self.compression_negotiated.load(Ordering::Relaxed) => {
+                compressed = BackendMessage::DocumentSync {
+                    document_id: document_id.clone(),
+                    data: gzip(data),
+                    compressed: true,
+                    seq: *seq,
+                };
+                &compressed
+            }
+            _ => message,
+        };
+
+        // The peer can't know the format before it has our `Hello`.
+        let format = match message {
+            BackendMessage::Hello { .. } => WireFormat::Json,
+            _ => self.format(),
+        };
+        let mut data = Self::serialize(format, message)?;
+        if let Some(ref signing) = self.signing {
+            let signature = signing.key.sign(&data);
+            data = Self::serialize(
+                format,
+                &BackendMessage::Signed {
+                    payload: data,
+                    signature: signature.to_bytes().to_vec(),
+                },
+            )?;
+        }
+        self.stats
+            .bytes_sent
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        Ok(data)
+    }
+
+    /// Format of every message after the `Hello`s.
+    fn format(&self) -> WireFormat {
+        if self.negotiated.load(Ordering::Relaxed) {
+            WireFormat::MessagePack
+        } else {
+            WireFormat::Json
+        }
+    }
+
+    fn serialize(format: WireFormat, message: &BackendMessage) -> Result<Vec<u8>> {
+        Ok(match format {
+            WireFormat::Json => serde_json::to_vec(message)?,
+            WireFormat::MessagePack => rmp_serde::to_vec_named(message)?,
+        })
+    }
+
+    fn deserialize(format: WireFormat, data: &[u8]) -> Result<BackendMessage> {
+        Ok(match format {
+            WireFormat::Json => serde_json::from_slice(data)?,
+            WireFormat::MessagePack => rmp_serde::from_slice(data)?,
+        })
+    }
+
+    /// Decodes a message in the negotiated format.
+    fn decode(&self, data: &[u8]) -> Result<Option<BackendMessage>> {
+        self.decode_as(self.format(), data)
+    }
+
+    /// Decodes a message in `format`, returning `None` if it should be
+    /// dropped because its signature is invalid.
+    fn decode_as(&self, format: WireFormat, data: &[u8]) -> Result<Option<BackendMessage>> {
+        self.stats
+            .bytes_received
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        let message = match Self::deserialize(format, data)? {
+            BackendMessage::Signed { payload, signature } => {
+                let message = Self::deserialize(format, &payload)?;
+                let key = match message {
+                    BackendMessage::Hello {
+                        public_key: Some(ref key),
+                        ..
+                    } => Some(parse_public_key(key)?),
+                    _ => self.peer_key.get().copied(),
+                };
+                let valid = match (key, Signature::from_slice(&signature)) {
+                    (Some(key), Ok(signature)) => key.verify(&payload, &signature).is_ok(),
+                    _ => false,
+                };
+                if !valid {
+                    warn!("Dropping message with invalid signature: {:?}", message);
+                    return Ok(None);
+                }
+                message
+            }
+            _ if self.signing.is_some() => {
+                return Err(eyre!(
+                    "Peer sent an unsigned message, but signing is required"
+                ));
+            }
+            message => message,
+        };
+
+        if let BackendMessage::Hello {
+            wire_format,
+            compression,
+            ref public_key,
+            ..
+        } = message
+        {
+            if let Some(key) = public_key {
+                let key = parse_public_key(key)?;
+                if let Some(ref signing) = self.signing {
+                    if !signing.trusted_keys.is_empty() && !signing.trusted_keys.contains(&key) {
+                        return Err(eyre!("Peer's public key is not trusted"));
+                    }
+                }
+                let _ = self.peer_key.set(key);
+            }
+            if wire_format == WireFormat::MessagePack && self.preferred == WireFormat::MessagePack {
+                self.negotiated.store(true, Ordering::Relaxed);
+            }
+            if compression && self.compression {
+                self.compression_negotiated.store(true, Ordering::Relaxed);
+            }
+            self.peer_hello.send_replace(true);
+        }
+
+        Ok(Some(message))
+    }
+}
+
+fn parse_public_key(key: &[u8]) -> Result<VerifyingKey> {
+    let key: &[u8; 32] = key.try_into().wrap_err("Public key has the wrong length")?;
+
+    VerifyingKey::from_bytes(key).wrap_err("Invalid public key")
+}
+
+/// Frames a raw byte stream as length-delimited messages.
+pub fn frame_stream(
+    connection: BoxedConnection,
+    options: &ConnectionOptions,
+) -> (ReadSocket, WriteSocket) {
+    let (read, write) = tokio::io::split(connection);
+    let codec = WireCodec::new(options);
+    let length_codec = LengthDelimitedCodec::builder()
+        .max_frame_length(options.max_frame_length)
+        .new_codec();
+
+    let read_codec = codec.clone();
+    let read = FramedRead::new(read, length_codec.clone())
+        .map_err(Report::from)
+        .try_filter_map(move |frame| future::ready(read_codec.decode(&frame)));
+    // `SinkMapErr` panics if used again after an error, so convert errors
+    // with `with` instead.
+    let write = FramedWrite::new(write, length_codec).with(move |message: BackendMessage| {
+        let codec = codec.clone();
+        async move { codec.encode_after_hello(message).await.map(Bytes::from) }
+    });
+
+    (Box::pin(read), Box::pin(write))
+}
+
+/// Frames a WebSocket as messages, one per WebSocket message.
+///
+/// Messages are sent as binary frames holding the same encoding used over TCP.
+/// Text frames holding JSON are accepted as well, for the benefit of browser
+/// clients.
+pub fn frame_websocket<S>(
+    websocket: WebSocketStream<S>,
+    options: &ConnectionOptions,
+) -> (ReadSocket, WriteSocket)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (write, read) = websocket.split();
+    let codec = WireCodec::new(options);
+
+    let read_codec = codec.clone();
+    let read = read.map_err(Report::from).try_filter_map(move |message| {
+        future::ready(match message {
+            Message::Binary(data) => read_codec.decode(&data),
+            Message::Text(text) => read_codec.decode_as(WireFormat::Json, text.as_bytes()),
+            // Pings are answered by tungstenite itself.
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => Ok(None),
+        })
+    });
+    let write = write.with(move |message: BackendMessage| {
+        let codec = codec.clone();
+        async move {
+            codec
+                .encode_after_hello(message)
+                .await
+                .map(|data| Message::Binary(data.into()))
+        }
+    });
+
+    (Box::pin(read), Box::pin(write))
+}
+
+/// TLS configuration used for both incoming and outgoing connections.
+#[derive(Clone)]
+pub struct TlsConfig {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl TlsConfig {
+    pub fn new(
+        server_config: Arc<rustls::ServerConfig>,
+        client_config: Arc<rustls::ClientConfig>,
+    ) -> Self {
+        TlsConfig {
+            acceptor: TlsAcceptor::from(server_config),
+            connector: TlsConnector::from(client_config),
+        }
+    }
+
+    /// Loads a configuration from PEM files.
+    ///
+    /// `cert` and `key` identify this client to peers, while `ca` contains the
+    /// certificates used to verify peers.
+    pub fn from_pem_files(cert: &Path, key: &Path, ca: &Path) -> Result<Self> {
+        let cert_chain = certs(&mut open_pem(cert)?)
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err("Failed to parse certificate")?;
+        let key = private_key(&mut open_pem(key)?)
+            .wrap_err("Failed to parse private key")?
+            .ok_or_else(|| eyre!("No private key found in {}", key.display()))?;
+
+        let mut roots = RootCertStore::empty();
+        for ca_cert in certs(&mut open_pem(ca)?) {
+            roots
+                .add(ca_cert.wrap_err("Failed to parse CA certificate")?)
+                .wrap_err("Invalid CA certificate")?;
+        }
+
+        let server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)
+            .wrap_err("Invalid certificate or key")?;
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(TlsConfig::new(
+            Arc::new(server_config),
+            Arc::new(client_config),
+        ))
+    }
+
+    pub async fn accept(&self, stream: BoxedConnection) -> Result<BoxedConnection> {
+        let stream = self.acceptor.accept(stream).await?;
+
+        Ok(Box::new(stream))
+    }
+
+    /// Performs a TLS handshake over `stream`, verifying the peer's
+    /// certificate against the host in `address`.
+    pub async fn connect(&self, address: &str, stream: TcpStream) -> Result<BoxedConnection> {
+        let host = address
+            .rsplit_once(':')
+            .map_or(address, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_owned())
+            .wrap_err_with(|| format!("Invalid server name: {host}"))?;
+        let stream = self.connector.connect(server_name, stream).await?;
+
+        Ok(Box::new(stream))
+    }
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+    Ok(BufReader::new(file))
+}
+
+/// A socket accepting connections from peers.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// A Unix domain socket, along with the number of connections accepted
+    /// so far, used to give each peer a unique address.
+    #[cfg(unix)]
+    Unix(UnixListener, u64),
+}
+
+impl Listener {
+    /// Accepts a new connection, returning it along with the peer's address.
+    pub async fn accept(&mut self, tcp: &TcpOptions) -> io::Result<(BoxedConnection, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                tcp.apply(&socket)?;
+                Ok((Box::new(socket), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, count) => {
+                let (socket, _) = listener.accept().await?;
+                // Clients connecting to a Unix socket are almost always
+                // unnamed, so number them instead.
+                *count += 1;
+                let path = listener.local_addr()?;
+                let path = path.as_pathname().unwrap_or(Path::new(""));
+                Ok((
+                    Box::new(socket),
+                    format!("{UNIX_PREFIX}{}#{count}", path.display()),
+                ))
+            }
+        }
+    }
+}
+
+/// Protocol spoken over a listener's connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Length-delimited messages directly over the byte stream.
+    Stream,
+    WebSocket,
+}
+
+/// Performs any handshakes needed on a newly-accepted connection.
+pub async fn accept_connection(
+    connection: BoxedConnection,
+    transport: Transport,
+    options: &ConnectionOptions,
+) -> Result<(ReadSocket, WriteSocket)> {
+    let connection = match &options.tls {
+        Some(tls) => tls
+            .accept(connection)
+            .await
+            .wrap_err("TLS handshake failed")?,
+        None => connection,
+    };
+
+    match transport {
+        Transport::Stream => Ok(frame_stream(connection, options)),
+        Transport::WebSocket => {
+            let websocket = tokio_tungstenite::accept_async(connection)
+                .await
+                .wrap_err("WebSocket handshake failed")?;
+            Ok(frame_websocket(websocket, options))
+        }
+    }
+}
+
+/// Connects to the peer at `address`.
+///
+/// Addresses starting with [`WEBSOCKET_PREFIX`] are connected to over
+/// WebSocket, filesystem paths over a Unix domain socket, and all others over
+/// TCP. TLS and the proxy are never used for Unix domain sockets, which are
+/// local-only.
+pub async fn open_connection(
+    address: &str,
+    options: &ConnectionOptions,
+) -> Result<(ReadSocket, WriteSocket)> {
+    #[cfg(unix)]
+    if let Some(path) = unix_socket_path(address) {
+        let socket = UnixStream::connect(path)
+            .await
+            .wrap_err_with(|| format!("Failed to connect to peer at {address}"))?;
+        return Ok(frame_stream(Box::new(socket), options));
+    }
+
+    let (host, transport) = match address.strip_prefix(WEBSOCKET_PREFIX) {
+        Some(host) => (host.trim_end_matches('/'), Transport::WebSocket),
+        None => (address, Transport::Stream),
+    };
+
+    let socket = match &options.proxy {
+        Some(proxy) => proxy.connect(host).await?,
+        None => TcpStream::connect(host)
+            .await
+            .wrap_err_with(|| format!("Failed to connect to peer at {address}"))?,
+    };
+    options.tcp.apply(&socket)?;
+    let connection: BoxedConnection = match &options.tls {
+        Some(tls) => tls
+            .connect(host, socket)
+            .await
+            .wrap_err_with(|| format!("TLS handshake with {address} failed"))?,
+        None => Box::new(socket),
+    };
+
+    match transport {
+        Transport::Stream => Ok(frame_stream(connection, options)),
+        Transport::WebSocket => {
+            let (websocket, _) = tokio_tungstenite::client_async(address, connection)
+                .await
+                .wrap_err_with(|| format!("WebSocket handshake with {address} failed"))?;
+            Ok(frame_websocket(websocket, options))
+        }
+    }
+}
+
+/// Returns the socket path if `address` refers to a Unix domain socket.
+#[cfg(unix)]
+fn unix_socket_path(address: &str) -> Option<&str> {
+    match address.strip_prefix(UNIX_PREFIX) {
+        Some(path) => Some(path),
+        None if address.starts_with('/') => Some(address),
+        None => None,
+    }
+}
@@ -1,12 +1,27 @@
-use loro::{event::ContainerDiff, LoroDoc, TextDelta};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report, Result,
+};
+use hmac::{Hmac, Mac};
+use loro::{
+    cursor::Cursor, event::ContainerDiff, ContainerID, ContainerType, Frontiers, LoroDoc,
+    LoroError, LoroText, LoroValue, PeerID, TextDelta, ValueOrContainer, ID,
+};
+use miniz_oxide::inflate::TINFLStatus;
+use sha2::Sha256;
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+use std::{collections::HashMap, io::Read};
 
-use super::Change;
+use super::{
+    Change, ChangeOrigin, ClientMessage, HistoryEntry, IndexMode, COMMENTS_CONTAINER,
+    COMMITS_CONTAINER, METADATA_CONTAINER, TEXT_CONTAINER,
+};
 
-pub fn generate_unique_id(name: &str, doc: &mut LoroDoc) -> String {
+pub fn generate_unique_id(name: &str, documents: &HashMap<String, LoroDoc>) -> String {
     let mut i = 0;
     let mut unique_name = name.to_string();
 
-    while !doc.get_text(unique_name.as_str()).is_empty() {
+    while documents.contains_key(&unique_name) {
         i += 1;
         unique_name = format!("{}-{}", name, i);
     }
@@ -14,33 +29,1022 @@ pub fn generate_unique_id(name: &str, doc: &mut LoroDoc) -> String {
     unique_name
 }
 
-pub fn diffs_to_changes(c_diffs: &[ContainerDiff]) -> Vec<Change> {
+/// Derives a peer ID from `identity`, the same on every run and platform.
+pub fn peer_id_from_identity(identity: &str) -> PeerID {
+    fnv1a(0xcbf29ce484222325, identity.as_bytes())
+}
+
+/// Name of the algorithm `content_hash` uses, sent along with hashes.
+pub const CONTENT_HASH_ALGORITHM: &str = "fnv1a-64";
+
+/// Hashes the text and metadata of `doc`, so peers with the same version can
+/// check they have the same contents.
+pub fn content_hash(doc: &LoroDoc) -> u64 {
+    let mut text = text_contents(doc);
+    text.sort();
+
+    // Each string is prefixed with its length so that moving bytes between
+    // adjacent strings changes the hash.
+    text.iter()
+        .chain(metadata_entries(doc).iter())
+        .flat_map(|(key, value)| [key, value])
+        .fold(0xcbf29ce484222325, |hash, string| {
+            let hash = fnv1a(hash, &(string.len() as u64).to_le_bytes());
+            fnv1a(hash, string.as_bytes())
+        })
+}
+
+/// Proves knowledge of the pre-shared key `psk` to the peer that sent
+/// `nonce`, on behalf of the peer with ID `prover`.
+pub fn psk_proof(psk: &str, nonce: &[u8], prover: PeerID) -> Vec<u8> {
+    psk_mac(psk, nonce, prover).finalize().into_bytes().to_vec()
+}
+
+/// Checks `mac` is the proof `psk_proof` would give, in constant time.
+pub fn verify_psk_proof(psk: &str, nonce: &[u8], prover: PeerID, mac: &[u8]) -> bool {
+    psk_mac(psk, nonce, prover).verify_slice(mac).is_ok()
+}
+
+fn psk_mac(psk: &str, nonce: &[u8], prover: PeerID) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(psk.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(b"c3edit-psk");
+    mac.update(nonce);
+    mac.update(&prover.to_le_bytes());
+    mac
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is stable across
+/// runs and platforms. Continues from `hash`.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Fails if `doc` is checked out to a past version, since it can't be edited
+/// until it's returned to the latest one.
+pub fn ensure_attached(doc: &LoroDoc, document_id: &str) -> Result<()> {
+    if doc.is_detached() {
+        bail!("Document {document_id} is checked out to a past version; check out the latest version to edit it");
+    }
+
+    Ok(())
+}
+
+/// Returns the current frontiers of `doc` in the `counter@peer` form used in
+/// `Version` messages, sorted so they can be compared directly.
+pub fn format_frontiers(doc: &LoroDoc) -> Vec<String> {
+    let mut ids: Vec<_> = doc.state_frontiers().iter().copied().collect();
+    ids.sort_by_key(|id| (id.peer, id.counter));
+
+    ids.iter().map(ToString::to_string).collect()
+}
+
+/// Summarizes every change in `doc`'s history, most recent first, keeping at
+/// most `limit`. Also returns whether any were left out.
+pub fn document_history(doc: &LoroDoc, limit: usize) -> (Vec<HistoryEntry>, bool) {
+    let vv = doc.oplog_vv();
+    let mut changes = doc.with_oplog(|oplog| {
+        let mut changes = Vec::new();
+        for (&peer, peer_changes) in oplog.changes() {
+            // Each change runs up to where the next one starts, and the last
+            // up to the end of the peer's operations.
+            let ends = peer_changes
+                .iter()
+                .skip(1)
+                .map(|change| change.id().counter)
+                .chain(vv.get(&peer).copied());
+            for (change, end) in peer_changes.iter().zip(ends) {
+                let start = change.id().counter;
+                changes.push((change.lamport(), peer, start, end, change.timestamp()));
+            }
+        }
+        changes
+    });
+    // Lamport timestamps order changes consistently with their dependencies,
+    // unlike authors' clocks.
+    changes.sort_by_key(|&(lamport, peer, ..)| std::cmp::Reverse((lamport, peer)));
+    let truncated = changes.len() > limit;
+    let messages = commit_messages(doc);
+
+    let entries = changes
+        .into_iter()
+        .take(limit)
+        .map(|(_, peer, start, end, timestamp)| {
+            // Loro may merge several commits into one change.
+            let change_messages: Vec<_> = messages
+                .iter()
+                .filter(|(id, _)| id.peer == peer && (start..end).contains(&id.counter))
+                .map(|(_, message)| message.as_str())
+                .collect();
+
+            HistoryEntry {
+                peer_id: peer,
+                timestamp,
+                op_count: (end - start) as usize,
+                version: ID::new(peer, end - 1).to_string(),
+                message: (!change_messages.is_empty()).then(|| change_messages.join("\n")),
+            }
+        })
+        .collect();
+
+    (entries, truncated)
+}
+
+/// Returns the messages given to changes in `doc`, with the ID of each one's
+/// first operation, in order.
+fn commit_messages(doc: &LoroDoc) -> Vec<(ID, String)> {
+    let LoroValue::Map(entries) = doc.get_map(COMMITS_CONTAINER).get_value() else {
+        return Vec::new();
+    };
+
+    let mut messages: Vec<_> = entries
+        .iter()
+        .filter_map(|(id, message)| {
+            let id = ID::try_from(id.as_str()).ok()?;
+            match message {
+                LoroValue::String(message) => Some((id, message.to_string())),
+                _ => None,
+            }
+        })
+        .collect();
+    messages.sort_by_key(|(id, _)| (id.peer, id.counter));
+
+    messages
+}
+
+/// Parses frontiers in the `counter@peer` form used in `Version` messages.
+pub fn parse_frontiers(frontiers: &[String]) -> Result<Frontiers> {
+    let ids = frontiers
+        .iter()
+        .map(|id| ID::try_from(id.as_str()).wrap_err_with(|| format!("Invalid operation ID: {id}")))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Frontiers::from(&ids))
+}
+
+/// Checks that every change in `changes` stays within a text of `len` code
+/// points, accounting for the changes before it, and only uses styles Loro
+/// knows, so that an invalid batch can be rejected before any of it is
+/// applied.
+pub fn validate_changes(mut len: usize, changes: &[Change]) -> Result<()> {
+    for (i, change) in changes.iter().enumerate() {
+        if let Change::Mark { key, .. } | Change::Unmark { key, .. } = change {
+            check_style(key).wrap_err_with(|| format!("Invalid style (change {i})"))?;
+        }
+        let (index, end, action) = match change {
+            Change::Insert { index, .. } => (*index, Some(*index), "Insertion"),
+            Change::Append { .. } => (len, Some(len), "Insertion"),
+            Change::Delete { index, len } => (*index, index.checked_add(*len), "Deletion"),
+            Change::Mark { index, len, .. } => (*index, index.checked_add(*len), "Style"),
+            Change::Unmark { index, len, .. } => (*index, index.checked_add(*len), "Style removal"),
+        };
+        match end {
+            Some(end) if end <= len => {}
+            Some(end) if end != index => {
+                bail!("{action} at {index}..{end} (change {i}) is out of bounds for text of length {len}")
+            }
+            _ => {
+                bail!("{action} at {index} (change {i}) is out of bounds for text of length {len}")
+            }
+        }
+
+        match change {
+            Change::Insert { text, .. } | Change::Append { text } => len += text.chars().count(),
+            Change::Delete { len: deleted, .. } => len -= deleted,
+            Change::Mark { .. } | Change::Unmark { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails if Loro has no configuration for the style `key`, so would refuse to
+/// apply it.
+fn check_style(key: &str) -> Result<()> {
+    // Styles are configured per document, but every document uses Loro's
+    // defaults, so try the style on an empty one.
+    let doc = LoroDoc::new();
+    let text = doc.get_text(TEXT_CONTAINER);
+    text.insert(0, " ")?;
+    match text.mark(0..1, key, true) {
+        Err(LoroError::StyleConfigMissing(_)) => bail!("Unknown style: {key}"),
+        result => Ok(result?),
+    }
+}
+
+/// Returns the length of a text `len` long once `change` is applied to it,
+/// both counted in the units of `index_mode`.
+pub fn length_after(len: usize, change: &Change, index_mode: IndexMode) -> usize {
+    match change {
+        Change::Insert { text, .. } | Change::Append { text } => {
+            len + match index_mode {
+                IndexMode::CodePoint => text.chars().count(),
+                IndexMode::Utf16 => text.encode_utf16().count(),
+            }
+        }
+        Change::Delete { len: deleted, .. } => len.saturating_sub(*deleted),
+        Change::Mark { .. } | Change::Unmark { .. } => len,
+    }
+}
+
+/// Returns the insertions and deletions that turn `old` into `new`, to be
+/// applied in order.
+pub fn text_diff(old: &str, new: &str) -> Vec<Change> {
+    let old: Vec<char> = old.chars().collect();
+    let new: Vec<char> = new.chars().collect();
+    let mut changes = Vec::new();
+
+    for op in capture_diff_slices(Algorithm::Myers, &old, &new) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        // Everything before this op already matches `new`.
+        let index = new_range.start;
+        if matches!(tag, DiffTag::Delete | DiffTag::Replace) {
+            changes.push(Change::Delete {
+                index,
+                len: old_range.len(),
+            });
+        }
+        if matches!(tag, DiffTag::Insert | DiffTag::Replace) {
+            changes.push(Change::Insert {
+                index,
+                text: new[new_range].iter().collect(),
+            });
+        }
+    }
+
+    changes
+}
+
+/// Converts `change`, with indices in UTF-16 code units into `text`, to one
+/// with indices in Unicode code points, then applies it to `text` so the next
+/// change can be converted.
+pub fn change_from_utf16(text: &mut String, change: Change) -> Result<Change> {
+    let Some((index, len)) = change_range(&change) else {
+        apply_to_string(text, &change);
+        return Ok(change);
+    };
+    let start = index_from_utf16(text, index)?;
+    let end = match index.checked_add(len) {
+        Some(end) => index_from_utf16(text, end)?,
+        None => bail!("Range at {index} of length {len} is out of bounds"),
+    };
+    let change = with_range(change, start, end - start);
+    apply_to_string(text, &change);
+
+    Ok(change)
+}
+
+/// Converts `change`, with indices in Unicode code points into `text`, to one
+/// with indices in UTF-16 code units, then applies it to `text` so the next
+/// change can be converted.
+pub fn change_to_utf16(text: &mut String, change: Change) -> Change {
+    let Some((index, len)) = change_range(&change) else {
+        apply_to_string(text, &change);
+        return change;
+    };
+    let start = utf16_index(text, index);
+    let len = text
+        .chars()
+        .skip(index)
+        .take(len)
+        .map(char::len_utf16)
+        .sum();
+    apply_to_string(text, &change);
+
+    with_range(change, start, len)
+}
+
+/// Returns the number of UTF-16 code units in the first `index` code points
+/// of `text`.
+pub fn utf16_index(text: &str, index: usize) -> usize {
+    text.chars().take(index).map(char::len_utf16).sum()
+}
+
+/// Returns the number of code points in the first `index` UTF-16 code units
+/// of `text`, failing if that's past its end or inside a surrogate pair.
+pub fn index_from_utf16(text: &str, index: usize) -> Result<usize> {
+    let mut units = 0;
+    for (i, c) in text.chars().enumerate() {
+        if units == index {
+            return Ok(i);
+        }
+        units += c.len_utf16();
+        if units > index {
+            bail!("UTF-16 index {index} is inside a surrogate pair");
+        }
+    }
+    if units != index {
+        bail!("UTF-16 index {index} is out of bounds for text of length {units}");
+    }
+
+    Ok(text.chars().count())
+}
+
+/// Returns the index of `change`, and the length of text it covers, which is
+/// 0 for insertions. Appends have neither.
+fn change_range(change: &Change) -> Option<(usize, usize)> {
+    match *change {
+        Change::Insert { index, .. } => Some((index, 0)),
+        Change::Append { .. } => None,
+        Change::Delete { index, len }
+        | Change::Mark { index, len, .. }
+        | Change::Unmark { index, len, .. } => Some((index, len)),
+    }
+}
+
+/// Returns `change` moved to `index`, covering `len`, which is ignored for
+/// insertions.
+fn with_range(change: Change, index: usize, len: usize) -> Change {
+    match change {
+        Change::Insert { text, .. } => Change::Insert { index, text },
+        Change::Append { text } => Change::Append { text },
+        Change::Delete { .. } => Change::Delete { index, len },
+        Change::Mark { key, value, .. } => Change::Mark {
+            index,
+            len,
+            key,
+            value,
+        },
+        Change::Unmark { key, .. } => Change::Unmark { index, len, key },
+    }
+}
+
+/// Applies `change`, with indices in Unicode code points, to `text`. Styles
+/// don't change the text, so they're ignored.
+fn apply_to_string(text: &mut String, change: &Change) {
+    let byte_index = |text: &str, index: usize| {
+        text.char_indices()
+            .nth(index)
+            .map_or(text.len(), |(byte, _)| byte)
+    };
+    match change {
+        Change::Insert { index, text: added } => {
+            text.insert_str(byte_index(text, *index), added);
+        }
+        Change::Append { text: added } => text.push_str(added),
+        Change::Delete { index, len } => {
+            let start = byte_index(text, *index);
+            let end = byte_index(text, index + len);
+            text.replace_range(start..end, "");
+        }
+        Change::Mark { .. } | Change::Unmark { .. } => {}
+    }
+}
+
+/// Fails if `name` can't be used as the name of a text container.
+pub fn check_container_name(name: &str) -> Result<&str> {
+    if name.is_empty() {
+        bail!("Container name must not be empty");
+    }
+    // Root containers are keyed by name alone in the document's value.
+    if [METADATA_CONTAINER, COMMITS_CONTAINER, COMMENTS_CONTAINER].contains(&name) {
+        bail!("Container name {name:?} is reserved");
+    }
+
+    Ok(name)
+}
+
+/// Converts diffs to changes, along with the name of the container each
+/// applies to.
+pub fn diffs_to_changes(c_diffs: &[ContainerDiff]) -> Vec<(String, Change)> {
     let mut changes = Vec::new();
 
     for c_diff in c_diffs {
-        let deltas = c_diff.diff.as_text().unwrap();
-        let mut index = 0;
+        // Documents only ever hold root text containers, besides metadata.
+        let (ContainerID::Root { name, .. }, Some(deltas)) = (c_diff.target, c_diff.diff.as_text())
+        else {
+            continue;
+        };
+        changes.extend(
+            delta_to_changes(deltas)
+                .into_iter()
+                .map(|change| (name.to_string(), change)),
+        );
+    }
+
+    changes
+}
+
+/// Converts diffs to the metadata entries they change, with `None` for those
+/// removed. Entries with values other than strings are left out.
+pub fn diffs_to_metadata(c_diffs: &[ContainerDiff]) -> Vec<(String, Option<String>)> {
+    let mut entries = Vec::new();
+
+    for c_diff in c_diffs {
+        let (
+            ContainerID::Root {
+                name,
+                container_type: ContainerType::Map,
+            },
+            Some(delta),
+        ) = (c_diff.target, c_diff.diff.as_map())
+        else {
+            continue;
+        };
+        if &**name != METADATA_CONTAINER {
+            continue;
+        }
+        for (key, value) in delta.updated.iter() {
+            let value = match value {
+                Some(ValueOrContainer::Value(LoroValue::String(value))) => Some(value.to_string()),
+                Some(_) => continue,
+                None => None,
+            };
+            entries.push((key.to_string(), value));
+        }
+    }
+    entries.sort();
+
+    entries
+}
+
+/// Returns every metadata entry in `doc` with a string value, sorted by key.
+pub fn metadata_entries(doc: &LoroDoc) -> Vec<(String, String)> {
+    let LoroValue::Map(entries) = doc.get_map(METADATA_CONTAINER).get_value() else {
+        return Vec::new();
+    };
+
+    let mut entries: Vec<_> = entries
+        .iter()
+        .filter_map(|(key, value)| match value {
+            LoroValue::String(value) => Some((key.clone(), value.to_string())),
+            _ => None,
+        })
+        .collect();
+    entries.sort();
+
+    entries
+}
+
+/// Returns the ID, start and end cursors, and body of every comment in `doc`
+/// that isn't resolved, sorted by ID. Malformed comments are left out.
+pub fn open_comments(doc: &LoroDoc) -> Vec<(String, Cursor, Cursor, String)> {
+    let LoroValue::Map(comments) = doc.get_map(COMMENTS_CONTAINER).get_deep_value() else {
+        return Vec::new();
+    };
+
+    let mut open: Vec<_> = comments
+        .iter()
+        .filter_map(|(id, comment)| {
+            let LoroValue::Map(comment) = comment else {
+                return None;
+            };
+            if let Some(LoroValue::Bool(true)) = comment.get("resolved") {
+                return None;
+            }
+            let cursor = |key| match comment.get(key) {
+                Some(LoroValue::Binary(data)) => Cursor::decode(data).ok(),
+                _ => None,
+            };
+            let Some(LoroValue::String(body)) = comment.get("body") else {
+                return None;
+            };
+            Some((
+                id.clone(),
+                cursor("start")?,
+                cursor("end")?,
+                body.to_string(),
+            ))
+        })
+        .collect();
+    open.sort_by(|a, b| a.0.cmp(&b.0));
+
+    open
+}
 
-        for diff in deltas {
-            match diff {
-                TextDelta::Retain { retain, .. } => {
-                    index += retain;
+/// Returns the contents of every text container in `doc`, keyed by name.
+pub fn text_contents(doc: &LoroDoc) -> Vec<(String, String)> {
+    let LoroValue::Map(containers) = doc.get_deep_value() else {
+        return Vec::new();
+    };
+
+    // Text containers are the only ones whose value is a string.
+    containers
+        .iter()
+        .filter_map(|(name, value)| match value {
+            LoroValue::String(content) => Some((name.clone(), content.to_string())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Describes an error decoding or importing `len` bytes of `document_id` from
+/// `source`, including what kind of problem it was, since bad data and peers
+/// running incompatible versions of Loro look much the same.
+pub fn import_error(error: LoroError, document_id: &str, source: &str, len: usize) -> Report {
+    let kind = match error {
+        LoroError::DecodeChecksumMismatchError => "checksum mismatch, so the data was corrupted",
+        LoroError::IncompatibleFutureEncodingError(_) => {
+            "encoding from a newer version, so the peer needs to be downgraded or this client upgraded"
+        }
+        LoroError::DecodeVersionVectorError
+        | LoroError::DecodeDataCorruptionError
+        | LoroError::DecodeError(_) => "malformed data, possibly from an incompatible version",
+        _ => "data that couldn't be applied",
+    };
+
+    Report::new(error).wrap_err(format!(
+        "Failed to import {len} bytes of {document_id} from {source}: {kind}"
+    ))
+}
+
+/// Exports every operation in `doc`, as a snapshot once its history has at
+/// least `snapshot_threshold` operations.
+pub fn export_all(doc: &LoroDoc, snapshot_threshold: Option<usize>) -> Vec<u8> {
+    match snapshot_threshold {
+        Some(threshold) if doc.len_ops() >= threshold => doc.export_snapshot(),
+        _ => doc.export_from(&Default::default()),
+    }
+}
+
+/// Returns the total size in bytes of the text and metadata in `doc`.
+pub fn document_size(doc: &LoroDoc) -> usize {
+    let text: usize = text_contents(doc)
+        .iter()
+        .map(|(_, content)| content.len())
+        .sum();
+    let metadata: usize = metadata_entries(doc)
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum();
+
+    text + metadata
+}
+
+/// Fails if adding `added` bytes of text to a document of `size` bytes would
+/// make it larger than `max` bytes.
+pub fn check_document_size(size: usize, added: usize, max: usize) -> Result<()> {
+    let size = size.saturating_add(added);
+    if size > max {
+        bail!("Document would grow to {size} bytes, over the maximum of {max}");
+    }
+
+    Ok(())
+}
+
+/// Magic number at the start of gzip data.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Magic number at the start of zstd data, which older peers and recordings
+/// compressed syncs with.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compresses `data` with gzip.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // No modification time, default compression, unknown OS.
+    let mut compressed = vec![GZIP_MAGIC[0], GZIP_MAGIC[1], 8, 0, 0, 0, 0, 0, 0, 255];
+    compressed.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+    compressed.extend(crc32(data).to_le_bytes());
+    compressed.extend((data.len() as u32).to_le_bytes());
+    compressed
+}
+
+/// Decompresses gzip- or zstd-compressed `data`, failing if the result would
+/// be larger than `max` bytes.
+pub fn decompress_bounded(data: &[u8], max: usize) -> Result<Vec<u8>> {
+    if data.starts_with(&ZSTD_MAGIC) {
+        let decoder = zstd::stream::read::Decoder::new(data)?;
+        let mut decompressed = Vec::new();
+        decoder
+            .take((max as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > max {
+            bail!("Decompressed data is over the maximum of {max} bytes");
+        }
+        return Ok(decompressed);
+    }
+
+    let (body, trailer) = gzip_parts(data)?;
+    let decompressed =
+        miniz_oxide::inflate::decompress_to_vec_with_limit(body, max).map_err(|e| {
+            match e.status {
+                TINFLStatus::HasMoreOutput => {
+                    eyre!("Decompressed data is over the maximum of {max} bytes")
                 }
-                TextDelta::Insert { insert, .. } => {
-                    changes.push(Change::Insert {
-                        index,
-                        text: insert.to_string(),
-                    });
+                _ => eyre!("Invalid gzip data: {e}"),
+            }
+        })?;
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if crc != crc32(&decompressed) || len != decompressed.len() as u32 {
+        bail!("Gzip data is corrupt");
+    }
+
+    Ok(decompressed)
+}
+
+/// Splits gzip `data` into its deflate-compressed body and the 8-byte trailer
+/// holding its checksum and length.
+fn gzip_parts(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let truncated = || eyre!("Gzip data is truncated");
+    if data.len() < 18 || data[..2] != GZIP_MAGIC || data[2] != 8 {
+        bail!("Data isn't gzip-compressed");
+    }
+    let flags = data[3];
+    let mut rest = &data[10..];
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+        rest = rest.get(2 + len..).ok_or_else(truncated)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = rest.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+            rest = &rest[end + 1..];
+        }
+    }
+    if flags & FHCRC != 0 {
+        rest = rest.get(2..).ok_or_else(truncated)?;
+    }
+
+    let split = rest.len().checked_sub(8).ok_or_else(truncated)?;
+    Ok(rest.split_at(split))
+}
+
+/// Computes the CRC-32 checksum of `data`, as used by gzip.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Groups `changes` to the given document into as few messages as possible,
+/// keeping them in order. Runs of changes to the same container become a
+/// single `ChangeBatch`. Every message is tagged with `origin`.
+pub fn changes_to_messages(
+    document_id: &str,
+    origin: Option<ChangeOrigin>,
+    changes: Vec<(String, Change)>,
+) -> Vec<ClientMessage> {
+    let mut runs: Vec<(String, Vec<Change>)> = Vec::new();
+    for (container, change) in changes {
+        match runs.last_mut() {
+            Some((last, run)) if *last == container => run.push(change),
+            _ => runs.push((container, vec![change])),
+        }
+    }
+
+    runs.into_iter()
+        .map(|(container, mut changes)| {
+            let document_id = document_id.to_owned();
+            if changes.len() == 1 {
+                ClientMessage::Change {
+                    document_id,
+                    container,
+                    change: changes.pop().unwrap(),
+                    origin,
+                    timestamp: None,
+                    message: None,
+                    new_length: None,
                 }
-                TextDelta::Delete { delete, .. } => {
-                    changes.push(Change::Delete {
-                        index,
-                        len: *delete,
-                    });
+            } else {
+                ClientMessage::ChangeBatch {
+                    document_id,
+                    container,
+                    changes,
+                    origin,
+                    timestamp: None,
+                    message: None,
+                    new_length: None,
                 }
             }
+        })
+        .collect()
+}
+
+/// Returns the changes needed to build every text container in `doc` from an
+/// empty document, starting with the main one.
+pub fn doc_to_changes(doc: &LoroDoc) -> Result<Vec<(String, Change)>> {
+    let mut names: Vec<_> = text_contents(doc)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort_by(|a, b| (a != TEXT_CONTAINER, a).cmp(&(b != TEXT_CONTAINER, b)));
+
+    let mut changes = Vec::new();
+    for name in names {
+        let text = doc.get_text(name.as_str());
+        changes.extend(
+            text_to_changes(&text)?
+                .into_iter()
+                .map(|change| (name.clone(), change)),
+        );
+    }
+
+    Ok(changes)
+}
+
+/// Returns the changes needed to build `text`, including its formatting, from
+/// an empty document.
+pub fn text_to_changes(text: &LoroText) -> Result<Vec<Change>> {
+    // There's no typed accessor for the current delta, so go through its JSON
+    // representation.
+    let deltas: Vec<TextDelta> = serde_json::from_value(serde_json::to_value(text.to_delta())?)?;
+
+    Ok(delta_to_changes(&deltas))
+}
+
+fn delta_to_changes(deltas: &[TextDelta]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let mut index = 0;
+
+    for diff in deltas {
+        match diff {
+            TextDelta::Retain { retain, attributes } => {
+                if let Some(attributes) = attributes {
+                    for (key, value) in attributes {
+                        changes.push(style_change(index, *retain, key, value));
+                    }
+                }
+                index += retain;
+            }
+            TextDelta::Insert { insert, attributes } => {
+                // Later deltas are relative to the text after this insertion,
+                // which Loro measures in Unicode code points.
+                let len = insert.chars().count();
+                changes.push(Change::Insert {
+                    index,
+                    text: insert.to_string(),
+                });
+                if let Some(attributes) = attributes {
+                    for (key, value) in attributes {
+                        changes.push(style_change(index, len, key, value));
+                    }
+                }
+                index += len;
+            }
+            TextDelta::Delete { delete } => {
+                changes.push(Change::Delete {
+                    index,
+                    len: *delete,
+                });
+            }
         }
     }
 
     changes
 }
+
+/// Converts a delta attribute into a change, where a null value means the
+/// style was removed.
+fn style_change(index: usize, len: usize, key: &str, value: &LoroValue) -> Change {
+    if value.is_null() {
+        Change::Unmark {
+            index,
+            len,
+            key: key.to_owned(),
+        }
+    } else {
+        Change::Mark {
+            index,
+            len,
+            key: key.to_owned(),
+            value: value.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Applies each change in turn to `text`.
+    fn apply_all(text: &str, changes: &[Change]) -> String {
+        let mut text = text.to_owned();
+        for change in changes {
+            apply_to_string(&mut text, change);
+        }
+        text
+    }
+
+    #[test]
+    fn diffs_use_code_points() {
+        let doc = LoroDoc::new();
+        let mirror = Arc::new(Mutex::new(String::new()));
+        let events_mirror = mirror.clone();
+        let _sub = doc.subscribe_root(Arc::new(move |event| {
+            let mut mirror = events_mirror.lock().unwrap();
+            for (_, change) in diffs_to_changes(&event.events) {
+                apply_to_string(&mut mirror, &change);
+            }
+        }));
+
+        let text = doc.get_text(TEXT_CONTAINER);
+        let check = |name: &str| {
+            doc.commit();
+            assert_eq!(*mirror.lock().unwrap(), text.to_string(), "after {name}");
+        };
+        text.insert(0, "日本語").unwrap();
+        check("inserting CJK");
+        text.insert(1, "😀👨‍👩‍👧").unwrap();
+        check("inserting emoji");
+        text.insert(7, "x").unwrap();
+        check("inserting after emoji");
+        text.delete(1, 3).unwrap();
+        check("deleting across emoji");
+        text.insert(text.len_unicode(), "🎉").unwrap();
+        check("appending");
+    }
+
+    #[test]
+    fn text_diff_round_trips_multibyte_text() {
+        let cases = [
+            ("", "日本語"),
+            ("日本語", ""),
+            ("a😀b", "a👍b"),
+            ("👨‍👩‍👧 family", "family 👨‍👩‍👧"),
+            ("漢字かな", "漢かな字"),
+        ];
+        for (old, new) in cases {
+            assert_eq!(
+                apply_all(old, &text_diff(old, new)),
+                new,
+                "{old:?} -> {new:?}"
+            );
+        }
+    }
+
+    fn insert(index: usize, text: &str) -> Change {
+        Change::Insert {
+            index,
+            text: text.to_owned(),
+        }
+    }
+
+    fn delete(index: usize, len: usize) -> Change {
+        Change::Delete { index, len }
+    }
+
+    #[test]
+    fn validate_accepts_changes_in_bounds() {
+        assert!(validate_changes(3, &[insert(0, "a"), insert(4, "b")]).is_ok());
+        assert!(validate_changes(3, &[delete(0, 3)]).is_ok());
+        // Each change sees the text as left by the ones before it.
+        assert!(validate_changes(0, &[insert(0, "abc"), delete(1, 2), insert(1, "d")]).is_ok());
+        assert!(validate_changes(
+            0,
+            &[
+                Change::Append {
+                    text: "ab".to_owned()
+                },
+                delete(0, 2)
+            ]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn validate_counts_code_points() {
+        // Four code points, though more bytes and UTF-16 code units.
+        assert!(validate_changes(0, &[insert(0, "日😀👍é"), delete(0, 4)]).is_ok());
+        assert!(validate_changes(0, &[insert(0, "日😀👍é"), delete(0, 5)]).is_err());
+    }
+
+    #[test]
+    fn validate_accepts_empty_ranges() {
+        assert!(validate_changes(3, &[delete(3, 0)]).is_ok());
+        assert!(validate_changes(0, &[delete(0, 0)]).is_ok());
+        let mark = Change::Unmark {
+            index: 2,
+            len: 0,
+            key: "bold".to_owned(),
+        };
+        assert!(validate_changes(2, &[mark]).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_changes() {
+        assert!(validate_changes(3, &[insert(4, "a")]).is_err());
+        assert!(validate_changes(3, &[delete(2, 2)]).is_err());
+        assert!(validate_changes(3, &[delete(4, 0)]).is_err());
+        assert!(validate_changes(3, &[delete(1, usize::MAX)]).is_err());
+        // Earlier deletions shorten the text for later changes.
+        assert!(validate_changes(3, &[delete(0, 2), insert(2, "a")]).is_err());
+        // The whole batch is rejected, so the error names the change at fault.
+        let error = validate_changes(3, &[insert(0, "a"), delete(3, 2)]).unwrap_err();
+        assert!(error.to_string().contains("change 1"), "{error}");
+    }
+
+    #[test]
+    fn validate_rejects_unknown_styles() {
+        let mark = |key: &str| Change::Mark {
+            index: 0,
+            len: 1,
+            key: key.to_owned(),
+            value: true.into(),
+        };
+        assert!(validate_changes(1, &[mark("bold")]).is_ok());
+        let error = validate_changes(1, &[insert(0, "a"), mark("sparkly")]).unwrap_err();
+        assert!(format!("{error:#}").contains("Unknown style"), "{error:#}");
+    }
+
+    #[test]
+    fn utf16_indices_count_surrogate_pairs() {
+        let text = "a😀b日";
+        let units = [0, 1, 3, 4, 5];
+        for (index, &unit) in units.iter().enumerate() {
+            assert_eq!(utf16_index(text, index), unit);
+            assert_eq!(index_from_utf16(text, unit).unwrap(), index);
+        }
+        // Indices past the end stop at it.
+        assert_eq!(utf16_index(text, 10), 5);
+    }
+
+    #[test]
+    fn utf16_index_inside_surrogate_pair_is_rejected() {
+        assert!(index_from_utf16("a😀b", 2).is_err());
+        assert!(index_from_utf16("😀", 1).is_err());
+    }
+
+    #[test]
+    fn utf16_index_out_of_range_is_rejected() {
+        assert!(index_from_utf16("a😀b", 5).is_err());
+        assert!(index_from_utf16("", 1).is_err());
+        assert_eq!(index_from_utf16("", 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn changes_convert_from_utf16() {
+        let mut text = "a😀b".to_owned();
+        let change = change_from_utf16(&mut text, delete(1, 2)).unwrap();
+        assert!(matches!(change, Change::Delete { index: 1, len: 1 }));
+        assert_eq!(text, "ab");
+
+        let change = change_from_utf16(&mut text, insert(2, "👍")).unwrap();
+        assert!(matches!(change, Change::Insert { index: 2, .. }));
+        assert_eq!(text, "ab👍");
+
+        // An empty range can sit at the very end.
+        let change = change_from_utf16(&mut text, delete(4, 0)).unwrap();
+        assert!(matches!(change, Change::Delete { index: 3, len: 0 }));
+        assert_eq!(text, "ab👍");
+    }
+
+    #[test]
+    fn invalid_utf16_changes_are_rejected() {
+        let mut text = "a😀b".to_owned();
+        assert!(change_from_utf16(&mut text, delete(2, 1)).is_err());
+        assert!(change_from_utf16(&mut text, delete(1, 1)).is_err());
+        assert!(change_from_utf16(&mut text, delete(3, 2)).is_err());
+        assert!(change_from_utf16(&mut text, insert(5, "c")).is_err());
+        assert!(change_from_utf16(&mut text, delete(1, usize::MAX)).is_err());
+        // Rejected changes leave the text alone.
+        assert_eq!(text, "a😀b");
+    }
+
+    #[test]
+    fn changes_round_trip_through_utf16() {
+        let changes = [
+            insert(0, "日😀"),
+            insert(1, "👨‍👩‍👧"),
+            delete(2, 2),
+            Change::Append {
+                text: "🎉".to_owned(),
+            },
+            delete(0, 0),
+        ];
+        let mut code_points = String::new();
+        let mut utf16 = String::new();
+        for change in changes {
+            let expected = change_range(&change);
+            let converted = change_to_utf16(&mut code_points, change);
+            let back = change_from_utf16(&mut utf16, converted).unwrap();
+            assert_eq!(change_range(&back), expected);
+            assert_eq!(utf16, code_points);
+        }
+        assert_eq!(code_points, "日👨‍👧😀🎉");
+    }
+
+    #[test]
+    fn gzip_round_trips_within_bounds() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let data = "hello world ".repeat(100).into_bytes();
+        let compressed = gzip(&data);
+        assert!(compressed.starts_with(&GZIP_MAGIC));
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress_bounded(&compressed, data.len()).unwrap(), data);
+        assert!(decompress_bounded(&compressed, data.len() - 1).is_err());
+
+        let mut corrupt = compressed.clone();
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        assert!(decompress_bounded(&corrupt, data.len()).is_err());
+        assert!(decompress_bounded(&compressed[..12], data.len()).is_err());
+
+        // Syncs from peers that still use zstd are understood too.
+        let zstd = zstd::encode_all(data.as_slice(), 0).unwrap();
+        assert_eq!(decompress_bounded(&zstd, data.len()).unwrap(), data);
+    }
+}
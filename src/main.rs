@@ -1,11 +1,19 @@
-mod client;
-
-use clap::Parser;
-use client::ClientBuilder;
-use color_eyre::Result;
-use std::io;
+use c3edit::client::{
+    init_logging, ClientBuilder, ClientConfig, IndexMode, LogConfig, LogFile, Output, ProxyAuth,
+    RateLimit, ReconnectPolicy, SigningConfig, SigningKey, Socks5Proxy, TcpOptions, TlsConfig,
+    VerifyingKey, WireFormat,
+};
+use clap::{Parser, ValueEnum};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use std::{path::PathBuf, time::Duration};
 use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tracing::{info, level_filters::LevelFilter};
+use tracing_appender::rolling::Rotation;
 
 /// Real-time cross-editor collaborative editing backend.
 #[derive(Debug, Parser)]
@@ -19,35 +27,426 @@ struct Args {
     #[arg(short, long, default_value = "6969")]
     port: u16,
 
-    /// Print debug information to stderr.
+    /// Port to additionally listen to incoming connections on. May be given
+    /// more than once.
+    #[arg(long)]
+    extra_port: Vec<u16>,
+
+    /// Port to additionally listen to incoming WebSocket connections on. May be
+    /// given more than once.
+    #[arg(long)]
+    websocket_port: Vec<u16>,
+
+    /// Unix domain socket to additionally listen to local connections on. May
+    /// be given more than once.
+    #[cfg(unix)]
+    #[arg(long)]
+    unix_socket: Vec<PathBuf>,
+
+    /// Certificate chain (PEM) to present to peers; enables TLS.
+    #[arg(long, requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) for the TLS certificate.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Certificates (PEM) used to verify peers when using TLS.
+    #[arg(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    /// Document saved with `save_document` to open at startup, named after the
+    /// file. May be given several times.
+    #[arg(long = "document")]
+    documents: Vec<PathBuf>,
+
+    /// Seconds between pings sent to each peer.
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_interval: u64,
+
+    /// Number of consecutive unanswered pings after which a peer is
+    /// disconnected.
+    #[arg(long, default_value = "3")]
+    max_missed_pongs: u32,
+
+    /// Most peers to be connected to at once. Further connections are
+    /// refused.
+    #[arg(long)]
+    max_peers: Option<usize>,
+
+    /// Number of operations in a document's history at which peers without it
+    /// are sent a snapshot rather than every update, or 0 to never do so.
+    #[arg(long, default_value = "10000")]
+    snapshot_threshold: usize,
+
+    /// Number of attempts to connect to a newly-added peer before giving up.
+    #[arg(long, default_value = "3", value_parser = clap::value_parser!(u32).range(1..))]
+    connect_attempts: u32,
+
+    /// Reconnect to dropped peers, giving up after this many attempts.
+    #[arg(long)]
+    reconnect_attempts: Option<u32>,
+
+    /// Stable identity to derive this client's peer ID from, so peers
+    /// recognize it after a restart. Must differ between clients.
+    #[arg(long)]
+    identity: Option<String>,
+
+    /// Run without an editor, only holding documents and passing updates
+    /// between peers.
+    #[arg(long, default_value = "false", conflicts_with = "read_only")]
+    relay: bool,
+
+    /// Keep running to serve peers after the editor exits, rather than
+    /// shutting down.
+    #[arg(long, default_value = "false")]
+    keep_running: bool,
+
+    /// Only receive updates from peers, refusing local edits.
+    #[arg(long, default_value = "false")]
+    read_only: bool,
+
+    /// Capacity of the channels carrying messages between the main event
+    /// loop, peers, and the frontend. Larger values smooth out bursts of
+    /// editing at the cost of memory.
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    channel_capacity: u64,
+
+    /// Messages that may wait to be written to each peer before it's
+    /// disconnected for falling behind.
+    #[arg(long, default_value = "256", value_parser = clap::value_parser!(u64).range(1..))]
+    peer_queue_capacity: u64,
+
+    /// Let TCP delay small messages to combine them into fewer packets
+    /// (Nagle's algorithm), at the cost of latency.
+    #[arg(long, default_value = "false")]
+    tcp_delay: bool,
+
+    /// Seconds of idleness after which TCP keepalive probes are sent to peers,
+    /// detecting dead connections even without heartbeats.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    tcp_keepalive: Option<u64>,
+
+    /// Seconds without document updates, cursors or presence from a peer
+    /// after which its connection is closed, even if it answers heartbeats.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    idle_timeout: Option<u64>,
+
+    /// Largest size in bytes of any document's text. Larger edits and updates
+    /// from peers are refused.
+    #[arg(long, default_value = "67108864")]
+    max_document_size: usize,
+
+    /// Send and receive document updates uncompressed, saving CPU time on fast
+    /// networks.
+    #[arg(long, default_value = "false")]
+    no_compression: bool,
+
+    /// Largest message in bytes accepted from a peer. Peers sending larger
+    /// ones are disconnected.
+    #[arg(long, default_value = "8388608")]
+    max_frame_length: usize,
+
+    /// Messages per second accepted from each peer before further ones are
+    /// delayed, with bursts of up to twice as many allowed. 0 disables the
+    /// limit.
+    #[arg(long, default_value = "100")]
+    incoming_rate: u32,
+
+    /// Milliseconds to wait for further local changes before broadcasting, so
+    /// that bursts of typing are sent together.
+    #[arg(long)]
+    debounce_ms: Option<u64>,
+
+    /// Send local changes to peers once every this many milliseconds,
+    /// however fast they're made, instead of as soon as they're made.
+    #[arg(long, conflicts_with = "debounce_ms", value_parser = clap::value_parser!(u64).range(1..))]
+    flush_interval_ms: Option<u64>,
+
+    /// Encoding to exchange messages with peers in. MessagePack is only used
+    /// with peers that choose it as well.
+    #[arg(long, value_enum, default_value_t = Format::Json)]
+    wire_format: Format,
+
+    /// How the editor counts positions in text.
+    #[arg(long, value_enum, default_value_t = Indices::CodePoint)]
+    index_mode: Indices,
+
+    /// File holding a hex-encoded Ed25519 private key to sign messages to
+    /// peers with. Peers must then sign their messages as well.
+    #[arg(long)]
+    signing_key: Option<PathBuf>,
+
+    /// Hex-encoded Ed25519 public key of a peer allowed to connect when
+    /// signing. May be given several times; if never given, any peer is
+    /// allowed.
+    #[arg(long = "trusted-key", value_parser = parse_hex_key, requires = "signing_key")]
+    trusted_keys: Vec<[u8; 32]>,
+
+    /// Password peers must share with this client to sync with it. Both
+    /// sides must be given the same one.
+    #[arg(long)]
+    psk: Option<String>,
+
+    /// SOCKS5 proxy to connect to peers through, as HOST:PORT.
+    #[arg(long, value_parser = parse_host_port)]
+    socks5_proxy: Option<(String, u16)>,
+
+    /// Credentials for the SOCKS5 proxy, as USERNAME:PASSWORD.
+    #[arg(long, value_parser = parse_proxy_auth, requires = "socks5_proxy")]
+    socks5_auth: Option<ProxyAuth>,
+
+    /// Log debug information; shorthand for `--log-level debug`.
     #[arg(long, default_value = "false")]
     debug: bool,
+
+    /// Minimum level of messages to log, in addition to `RUST_LOG`.
+    #[arg(long, conflicts_with = "debug")]
+    log_level: Option<LevelFilter>,
+
+    /// Directory to write logs to instead of stderr.
+    #[arg(long)]
+    log_dir: Option<PathBuf>,
+
+    /// File to record every message exchanged with peers to, for debugging.
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// File or named pipe to write messages to the frontend to, instead of
+    /// stdout.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// How often to start a new log file.
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily, requires = "log_dir")]
+    log_rotation: LogRotation,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Indices {
+    CodePoint,
+    Utf16,
+}
+
+impl From<Indices> for IndexMode {
+    fn from(indices: Indices) -> Self {
+        match indices {
+            Indices::CodePoint => IndexMode::CodePoint,
+            Indices::Utf16 => IndexMode::Utf16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Json,
+    Msgpack,
+}
+
+impl From<Format> for WireFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Json => WireFormat::Json,
+            Format::Msgpack => WireFormat::MessagePack,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+fn parse_host_port(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| "expected HOST:PORT".to_owned())?;
+    let port = port.parse().map_err(|e| format!("invalid port: {e}"))?;
+
+    Ok((host.to_owned(), port))
+}
+
+fn parse_hex_key(s: &str) -> Result<[u8; 32], String> {
+    let s = s.trim();
+    if s.len() != 64 || !s.is_ascii() {
+        return Err("expected 64 hexadecimal digits".to_owned());
+    }
+
+    let mut key = [0; 32];
+    for (byte, digits) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16).map_err(|e| format!("invalid key: {e}"))?;
+    }
+
+    Ok(key)
+}
+
+fn parse_proxy_auth(s: &str) -> Result<ProxyAuth, String> {
+    let (username, password) = s
+        .split_once(':')
+        .ok_or_else(|| "expected USERNAME:PASSWORD".to_owned())?;
+
+    Ok(ProxyAuth {
+        username: username.to_owned(),
+        password: password.to_owned(),
+    })
+}
+
+/// Binds a Unix domain socket at `path`, replacing a stale socket left behind by
+/// a previous run.
+#[cfg(unix)]
+fn bind_unix_socket(path: &std::path::Path) -> Result<UnixListener> {
+    use std::{fs, os::unix::fs::FileTypeExt};
+
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(UnixListener::bind(path)?)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    let mut filter = tracing_subscriber::filter::EnvFilter::from_default_env();
-    if args.debug {
-        filter = filter.add_directive(LevelFilter::DEBUG.into());
-    }
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .with_writer(io::stderr)
-        .init();
+    let signing = match args.signing_key {
+        Some(ref path) => {
+            let key = std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+            let key = parse_hex_key(&key).map_err(|e| eyre!("{}: {e}", path.display()))?;
+            let trusted_keys = args
+                .trusted_keys
+                .iter()
+                .map(VerifyingKey::from_bytes)
+                .collect::<Result<_, _>>()
+                .wrap_err("Invalid trusted key")?;
+            Some(SigningConfig {
+                key: SigningKey::from_bytes(&key),
+                trusted_keys,
+            })
+        }
+        None => None,
+    };
+
+    let tls = match (&args.tls_cert, &args.tls_key, &args.tls_ca) {
+        (Some(cert), Some(key), Some(ca)) => Some(TlsConfig::from_pem_files(cert, key, ca)?),
+        _ => None,
+    };
+
+    let config = ClientConfig {
+        main_capacity: args.channel_capacity as usize,
+        stdout_capacity: args.channel_capacity as usize,
+        outgoing_capacity: args.channel_capacity as usize,
+        peer_queue_capacity: args.peer_queue_capacity as usize,
+        tcp: TcpOptions {
+            nodelay: !args.tcp_delay,
+            keepalive: args.tcp_keepalive.map(Duration::from_secs),
+        },
+        tls,
+        heartbeat_interval: Duration::from_secs(args.heartbeat_interval),
+        max_missed_pongs: args.max_missed_pongs,
+        reconnect: args.reconnect_attempts.map(|max_attempts| ReconnectPolicy {
+            max_attempts,
+            ..Default::default()
+        }),
+        read_only: args.read_only,
+        debounce: args.debounce_ms.map(Duration::from_millis),
+        flush_interval: args.flush_interval_ms.map(Duration::from_millis),
+        log: LogConfig {
+            level: args.log_level.or(args.debug.then_some(LevelFilter::DEBUG)),
+            file: args.log_dir.map(|directory| LogFile {
+                directory,
+                prefix: "c3edit".to_owned(),
+                rotation: args.log_rotation.into(),
+            }),
+        },
+        idle_timeout: args.idle_timeout.map(Duration::from_secs),
+        connect_attempts: args.connect_attempts,
+        max_peers: args.max_peers,
+        index_mode: args.index_mode.into(),
+        record_path: args.record,
+        output: args.output.map_or(Output::Stdout, Output::Path),
+        snapshot_threshold: (args.snapshot_threshold > 0).then_some(args.snapshot_threshold),
+        relay: args.relay,
+        shutdown_on_stdin_close: !args.keep_running,
+        identity: args.identity,
+        max_document_size: args.max_document_size,
+        max_frame_length: args.max_frame_length,
+        compression: !args.no_compression,
+        wire_format: args.wire_format.into(),
+        signing,
+        psk: args.psk,
+        proxy: args.socks5_proxy.map(|(host, port)| Socks5Proxy {
+            host,
+            port,
+            auth: args.socks5_auth,
+        }),
+        incoming_rate: (args.incoming_rate > 0).then(|| RateLimit {
+            per_second: args.incoming_rate,
+            burst: args.incoming_rate.saturating_mul(2),
+        }),
+        ..Default::default()
+    };
+    // Held until exit so that buffered logs are written out.
+    let log_guard = init_logging(&config.log)?;
 
     color_eyre::install()?;
 
     let addr = format!("{}:{}", args.address, args.port);
-    let listener = TcpListener::bind(&addr).await.unwrap();
-    info!("Listening on {addr}");
+    let mut builder = ClientBuilder::bind(&addr).await?;
+    info!("Listening on {}", builder.local_addr()?);
 
-    let client = ClientBuilder::new(listener).build();
+    if config.tls.is_some() {
+        info!("TLS enabled");
+    }
+    builder = builder.config(config);
+    for path in &args.documents {
+        let snapshot = std::fs::read(path)
+            .wrap_err_with(|| format!("Failed to read snapshot from {}", path.display()))?;
+        let id = path
+            .file_stem()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy();
+        builder = builder.document(id.clone(), &snapshot)?;
+        info!("Loaded document {} from {}", id, path.display());
+    }
+    for port in args.extra_port {
+        let addr = format!("{}:{}", args.address, port);
+        builder = builder.tcp_listener(TcpListener::bind(&addr).await?);
+        info!("Also listening for connections on {addr}");
+    }
+    for port in args.websocket_port {
+        let addr = format!("{}:{}", args.address, port);
+        builder = builder.websocket_listener(TcpListener::bind(&addr).await?);
+        info!("Listening for WebSocket connections on {addr}");
+    }
+    #[cfg(unix)]
+    for path in &args.unix_socket {
+        builder = builder.unix_listener(bind_unix_socket(path)?);
+        info!("Listening for local connections on {}", path.display());
+    }
+    let client = builder.build();
 
     info!("Entering client event loop");
     client.begin_event_loop().await;
 
     info!("Client exited, returning");
-    Ok(())
+    // The thread blocked reading stdin would keep the runtime from shutting
+    // down, so exit directly, once any buffered logs are written.
+    drop(log_guard);
+    std::process::exit(0)
 }